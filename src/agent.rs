@@ -0,0 +1,310 @@
+/// This file is the agent, which keeps a store unlocked for a while, like ssh-agent
+/// `envn unlock` hands it the key the keyring is wrapped with, and the following
+/// commands ask it for that key instead of asking for the password
+/// It listens on the `agent.sock` socket of the store, which only the user can connect to,
+/// and forgets the key and exits once nobody asked for it for the idle timeout
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    encryption::WrappingKey,
+    error::{EnvnError, Result},
+};
+
+/// The name of the socket in the store
+pub const SOCKET_FILE: &str = "agent.sock";
+
+/// How long the agent waits for a connection to send its request or take the answer,
+/// so that a client that hangs does not keep everyone else waiting
+const SERVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long a command waits for the agent, longer than the agent waits for a client ahead of it
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a command asks the agent, one JSON line per connection
+#[derive(Serialize, Deserialize)]
+enum Request {
+    /// The key, if the agent holds one
+    Get,
+    /// Hold on to this key, with a new idle timeout
+    Unlock(WrappingKey, Duration),
+    /// Forget the key and exit
+    Lock,
+}
+
+/// What the agent answers
+#[derive(Serialize, Deserialize)]
+enum Response {
+    Key(Option<WrappingKey>),
+    Done,
+}
+
+/// The path of the socket of the store in `dir`
+pub fn socket_path(dir: &Path) -> PathBuf {
+    dir.join(SOCKET_FILE)
+}
+
+/// Asks the agent of the store for the key.
+///
+/// # Returns
+///
+/// The key, or `None` if no agent is running or it holds no key.
+pub fn get_key(dir: &Path) -> Result<Option<WrappingKey>> {
+    match request(dir, &Request::Get)? {
+        Some(Response::Key(key)) => Ok(key),
+        _ => Ok(None),
+    }
+}
+
+/// Hands the key to the agent of the store, which has to be running.
+/// The agent exits once nobody asked it for the key for `timeout`.
+pub fn unlock(dir: &Path, key: WrappingKey, timeout: Duration) -> Result<()> {
+    match request(dir, &Request::Unlock(key, timeout))? {
+        Some(Response::Done) => Ok(()),
+        _ => Err(EnvnError::NotFound("The agent".to_string())),
+    }
+}
+
+/// Tells the agent of the store to forget the key and exit.
+///
+/// # Returns
+///
+/// `true` if an agent was running.
+pub fn lock(dir: &Path) -> Result<bool> {
+    Ok(request(dir, &Request::Lock)?.is_some())
+}
+
+/// Checks if an agent is listening on the socket of the store.
+pub fn is_running(dir: &Path) -> bool {
+    matches!(request(dir, &Request::Get), Ok(Some(_)))
+}
+
+/// Sends a request to the agent.
+///
+/// # Returns
+///
+/// The response, or `None` if no agent is listening.
+#[cfg(unix)]
+fn request(dir: &Path, request: &Request) -> Result<Option<Response>> {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        os::unix::net::UnixStream,
+    };
+
+    let mut stream = match UnixStream::connect(socket_path(dir)) {
+        Ok(stream) => stream,
+        Err(_) => return Ok(None),
+    };
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut line = serde_json::to_string(request).map_err(|e| EnvnError::Parse(e.to_string()))?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    let mut answer = String::new();
+    BufReader::new(stream).read_line(&mut answer)?;
+    let response = serde_json::from_str(&answer)
+        .map_err(|e| EnvnError::Parse(format!("The agent answered with {}", e)))?;
+    Ok(Some(response))
+}
+
+#[cfg(not(unix))]
+fn request(_dir: &Path, _request: &Request) -> Result<Option<Response>> {
+    Ok(None)
+}
+
+/// Runs the agent of the store in `dir` until it is locked or was idle for `timeout`.
+///
+/// # Returns
+///
+/// An error if another agent is already running, or the socket could not be created.
+#[cfg(unix)]
+pub fn serve(dir: &Path, timeout: Duration) -> Result<()> {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        os::unix::{fs::PermissionsExt, net::UnixListener},
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    if is_running(dir) {
+        return Err(EnvnError::Auth(
+            "An agent is already running for this store".to_string(),
+        ));
+    }
+    let path = socket_path(dir);
+    // a socket left behind by an agent that was killed
+    let _ = std::fs::remove_file(&path);
+
+    // nobody but the user may connect, not even between binding and the chmod
+    // SAFETY: umask only swaps the file mode mask of the process
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(&path);
+    unsafe { libc::umask(umask) };
+    let listener = listener?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+
+    let key: Arc<Mutex<Option<WrappingKey>>> = Arc::new(Mutex::new(None));
+    // when the agent was last asked for anything, and how long it may be idle
+    let idle = Arc::new(Mutex::new((Instant::now(), timeout)));
+
+    let watched = Arc::clone(&idle);
+    let socket = path.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(1));
+        let expired = watched
+            .lock()
+            .map(|idle| idle.0.elapsed() >= idle.1)
+            .unwrap_or(true);
+        if expired {
+            let _ = std::fs::remove_file(&socket);
+            std::process::exit(0);
+        }
+    });
+
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let timeouts = stream
+            .set_read_timeout(Some(SERVE_TIMEOUT))
+            .and_then(|_| stream.set_write_timeout(Some(SERVE_TIMEOUT)));
+        if timeouts.is_err() {
+            continue;
+        }
+        let mut line = String::new();
+        if BufReader::new(&stream).read_line(&mut line).is_err() {
+            continue;
+        }
+        let request: Request = match serde_json::from_str(&line) {
+            Ok(request) => request,
+            Err(_) => continue,
+        };
+        if let Ok(mut idle) = idle.lock() {
+            idle.0 = Instant::now();
+            if let Request::Unlock(_, timeout) = &request {
+                idle.1 = *timeout;
+            }
+        }
+
+        let mut held = key
+            .lock()
+            .map_err(|_| EnvnError::Auth("The agent is broken".to_string()))?;
+        let (response, exit) = match request {
+            Request::Get => (Response::Key(held.clone()), false),
+            Request::Unlock(new, _) => {
+                *held = Some(new);
+                (Response::Done, false)
+            }
+            Request::Lock => {
+                *held = None;
+                (Response::Done, true)
+            }
+        };
+        if let Ok(mut answer) = serde_json::to_string(&response) {
+            answer.push('\n');
+            let _ = stream.write_all(answer.as_bytes());
+        }
+        if exit {
+            break;
+        }
+    }
+
+    let _ = std::fs::remove_file(&path);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn serve(_dir: &Path, _timeout: Duration) -> Result<()> {
+    Err(unsupported())
+}
+
+/// The error of the agent on systems without unix sockets
+#[cfg(not(unix))]
+fn unsupported() -> EnvnError {
+    EnvnError::Io(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "The agent needs unix sockets",
+    ))
+}
+
+/// Starts an agent for the store in the background, and waits until it listens.
+///
+/// # Arguments
+///
+/// * `dir` - The directory of the store.
+/// * `timeout` - The idle timeout, passed on to `envn agent --timeout`.
+#[cfg(unix)]
+pub fn spawn(dir: &Path, timeout: &str) -> Result<()> {
+    use std::{os::unix::process::CommandExt, process::Stdio};
+
+    // its own process group, so that Ctrl-C in the terminal leaves it running
+    std::process::Command::new(std::env::current_exe()?)
+        .args(["agent", "--timeout", timeout])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn()?;
+
+    for _ in 0..50 {
+        if is_running(dir) {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Err(EnvnError::NotFound(
+        "The agent that was started".to_string(),
+    ))
+}
+
+#[cfg(not(unix))]
+pub fn spawn(_dir: &Path, _timeout: &str) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{get_key, is_running, lock, serve, socket_path, unlock};
+    use crate::{encryption::KdfParams, Vault};
+    use std::{os::unix::net::UnixStream, time::Duration};
+
+    #[test]
+    fn test_agent_holds_the_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let kdf = KdfParams {
+            m_cost: 1024,
+            t_cost: 1,
+            p_cost: 1,
+        };
+        let vault = Vault::create_with_kdf(dir.path(), "hunter2", kdf).unwrap();
+        vault
+            .set("db", "DATABASE_URL", "postgres://localhost")
+            .unwrap();
+        assert!(get_key(dir.path()).unwrap().is_none());
+
+        let store = dir.path().to_path_buf();
+        let agent = std::thread::spawn(move || serve(&store, Duration::from_secs(60)));
+        while !is_running(dir.path()) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(get_key(dir.path()).unwrap().is_none());
+        unlock(dir.path(), vault.wrapping_key(), Duration::from_secs(60)).unwrap();
+        // a client that never sends its request is given up on
+        let silent = UnixStream::connect(socket_path(dir.path())).unwrap();
+        let key = get_key(dir.path()).unwrap().unwrap();
+        drop(silent);
+        let reopened = Vault::open_with_key(dir.path(), &key, kdf).unwrap();
+        assert_eq!(reopened.get("db").unwrap().value, "postgres://localhost");
+
+        assert!(lock(dir.path()).unwrap());
+        agent.join().unwrap().unwrap();
+        assert!(!lock(dir.path()).unwrap());
+    }
+}
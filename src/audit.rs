@@ -0,0 +1,501 @@
+/// This file keeps the audit log, a record of who read or changed which secret
+/// The log lives in the `audit` table of `env.db`, and rows are only ever appended
+/// Every row holds an HMAC of itself and the row before it, keyed with a key only the
+/// unlocked keyring can reach, so that changing or removing a row breaks the chain
+/// The head of the log, in `audit_head`, counts the rows and holds the newest hash,
+/// so that removing the newest rows is noticed as well
+use hmac::{Hmac, Mac};
+use rusqlite::{params, Connection, OptionalExtension, Transaction, TransactionBehavior};
+use sha2::Sha256;
+
+use crate::{
+    encryption::{decrypt, encrypt, get_key, get_nonce, Keyring},
+    error::{EnvnError, Result},
+    utils::{now, parse_duration, parse_time, TIME_FORMAT},
+};
+
+/// The `prev_hash` of the first row
+const GENESIS: [u8; 32] = [0; 32];
+
+/// The associated data the key of the chain is encrypted with
+const KEY_AAD: &[u8] = b"envn audit log";
+
+/// A row of the audit log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub id: i64,
+    /// When it happened, in UTC
+    pub created_at: String,
+    /// The command that was run, like `get` or `delete`
+    pub command: String,
+    /// The secret it was run on, `None` if it was run on all of them
+    pub name: Option<String>,
+    pub project: String,
+    pub environment: String,
+    /// The user of the operating system
+    pub user: String,
+    pub host: String,
+    /// The directory the command was run in
+    pub cwd: String,
+}
+
+/// Which rows of the audit log to show
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// Only rows from this time on, in the format `parse_since` returns
+    pub since: Option<String>,
+    /// Only rows about this secret
+    pub name: Option<String>,
+}
+
+/// The head of the audit log, as stored in `audit_head`
+struct Head {
+    /// How many rows were appended
+    rows: i64,
+    /// The hash of the newest row
+    hash: Vec<u8>,
+    /// The HMAC of `rows` and `hash`
+    mac: Vec<u8>,
+    /// The key of the chain, encrypted with a key of the keyring
+    key: Vec<u8>,
+    nonce: Vec<u8>,
+    key_version: u32,
+}
+
+impl Record {
+    /// The hash of the row, chained to the hash of the row before it.
+    fn hash(&self, key: &[u8], prev_hash: &[u8]) -> Result<Vec<u8>> {
+        let fields = bincode::serialize(&(
+            prev_hash,
+            &self.created_at,
+            &self.command,
+            &self.name,
+            &self.project,
+            &self.environment,
+            &self.user,
+            &self.host,
+            &self.cwd,
+        ))?;
+        hmac(key, &fields)
+    }
+}
+
+impl Head {
+    /// The HMAC of the row count and the newest hash.
+    fn mac(&self, key: &[u8]) -> Result<Vec<u8>> {
+        hmac(key, &bincode::serialize(&(self.rows, &self.hash))?)
+    }
+}
+
+/// Computes the HMAC-SHA256 of `data`.
+fn hmac(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)
+        .map_err(|_| EnvnError::Crypto("The key of the audit log is malformed".to_string()))?;
+    mac.update(data);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// Reads the head of the audit log, `None` if it has none.
+fn read_head(conn: &Connection) -> Result<Option<Head>> {
+    Ok(conn
+        .query_row(
+            "SELECT rows, hash, mac, key, nonce, key_version FROM audit_head WHERE id = 1",
+            [],
+            |row| {
+                Ok(Head {
+                    rows: row.get(0)?,
+                    hash: row.get(1)?,
+                    mac: row.get(2)?,
+                    key: row.get(3)?,
+                    nonce: row.get(4)?,
+                    key_version: row.get(5)?,
+                })
+            },
+        )
+        .optional()?)
+}
+
+/// Stores the head of the audit log with a fresh HMAC.
+fn write_head(conn: &Connection, head: &mut Head, key: &[u8]) -> Result<()> {
+    head.mac = head.mac(key)?;
+    conn.execute(
+        "INSERT OR REPLACE INTO audit_head (id, rows, hash, mac, key, nonce, key_version)
+        VALUES (1, ?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            head.rows,
+            head.hash,
+            head.mac,
+            head.key,
+            head.nonce,
+            head.key_version
+        ],
+    )?;
+    Ok(())
+}
+
+/// Creates the head of an empty audit log, with a new key for its chain.
+///
+/// # Returns
+///
+/// The head and the unencrypted key of the chain.
+fn new_head(keyring: &Keyring) -> Result<(Head, Vec<u8>)> {
+    let key = get_key().to_vec();
+    let (key_version, data_key) = keyring.current();
+    let nonce = get_nonce();
+    let head = Head {
+        rows: 0,
+        hash: GENESIS.to_vec(),
+        mac: Vec::new(),
+        key: encrypt(data_key, nonce, &key, KEY_AAD)?,
+        nonce: nonce.to_vec(),
+        key_version,
+    };
+    Ok((head, key))
+}
+
+/// Decrypts the key of the chain.
+fn open_key(head: &Head, keyring: &Keyring) -> Result<Vec<u8>> {
+    let data_key = keyring
+        .get(head.key_version)
+        .ok_or(EnvnError::Crypto(format!(
+            "The key version {} of the audit log is not in the keyring",
+            head.key_version
+        )))?;
+    decrypt(data_key, &head.nonce, &head.key, KEY_AAD)
+        .map_err(|_| EnvnError::Crypto("The key of the audit log was tampered with".to_string()))
+}
+
+/// Reads the head of the audit log and checks its HMAC.
+///
+/// # Returns
+///
+/// The head and the key of the chain, `None` if nothing was ever appended,
+/// or a `Crypto` error if the head was removed or tampered with.
+fn unlock(conn: &Connection, keyring: &Keyring) -> Result<Option<(Head, Vec<u8>)>> {
+    let head = match read_head(conn)? {
+        Some(head) => head,
+        None => {
+            let rows: i64 = conn.query_row("SELECT COUNT(*) FROM audit", [], |row| row.get(0))?;
+            return match rows {
+                0 => Ok(None),
+                _ => Err(EnvnError::Crypto(
+                    "The head of the audit log was removed".to_string(),
+                )),
+            };
+        }
+    };
+    let key = open_key(&head, keyring)?;
+    if head.mac(&key)? != head.mac {
+        return Err(EnvnError::Crypto(
+            "The head of the audit log was tampered with".to_string(),
+        ));
+    }
+    Ok(Some((head, key)))
+}
+
+/// Appends a row to the audit log.
+///
+/// # Arguments
+///
+/// * `conn` - The connection to the database.
+/// * `keyring` - The unlocked keyring, which holds the key of the chain.
+/// * `command` - The command that was run.
+/// * `name` - The secret it was run on, `None` if it was run on all of them.
+/// * `project` - The project the command worked on.
+/// * `environment` - The environment the command worked on.
+pub fn append(
+    conn: &Connection,
+    keyring: &Keyring,
+    command: &str,
+    name: Option<&str>,
+    project: &str,
+    environment: &str,
+) -> Result<()> {
+    let record = Record {
+        id: 0,
+        created_at: now().format(TIME_FORMAT).to_string(),
+        command: command.to_string(),
+        name: name.map(str::to_string),
+        project: project.to_string(),
+        environment: environment.to_string(),
+        user: current_user(),
+        host: hostname(),
+        cwd: std::env::current_dir()?.display().to_string(),
+    };
+
+    // the head is read in the same transaction the row is written in,
+    // which takes the write lock right away so that no two commands chain onto the same row
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    let (mut head, key) = match unlock(&tx, keyring)? {
+        Some(unlocked) => unlocked,
+        None => new_head(keyring)?,
+    };
+    let prev_hash = head.hash.clone();
+    let hash = record.hash(&key, &prev_hash)?;
+
+    tx.execute(
+        "INSERT INTO audit
+            (created_at, command, name, project, environment, user, host, cwd, prev_hash, hash)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        params![
+            record.created_at,
+            record.command,
+            record.name,
+            record.project,
+            record.environment,
+            record.user,
+            record.host,
+            record.cwd,
+            prev_hash,
+            hash
+        ],
+    )?;
+    head.rows += 1;
+    head.hash = hash;
+    write_head(&tx, &mut head, &key)?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Reads the rows of the audit log, oldest first.
+pub fn read(conn: &Connection, filter: &Filter) -> Result<Vec<Record>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, created_at, command, name, project, environment, user, host, cwd
+        FROM audit
+        WHERE (?1 IS NULL OR created_at >= ?1) AND (?2 IS NULL OR name = ?2)
+        ORDER BY id",
+    )?;
+    let records = stmt.query_map(params![filter.since, filter.name], |row| {
+        Ok(Record {
+            id: row.get(0)?,
+            created_at: row.get(1)?,
+            command: row.get(2)?,
+            name: row.get(3)?,
+            project: row.get(4)?,
+            environment: row.get(5)?,
+            user: row.get(6)?,
+            host: row.get(7)?,
+            cwd: row.get(8)?,
+        })
+    })?;
+    Ok(records.collect::<rusqlite::Result<_>>()?)
+}
+
+/// Reads the hashes every row was chained with, oldest first.
+fn read_hashes(conn: &Connection) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut stmt = conn.prepare("SELECT prev_hash, hash FROM audit ORDER BY id")?;
+    let hashes = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    Ok(hashes.collect::<rusqlite::Result<_>>()?)
+}
+
+/// Checks that no row of the audit log was changed, added or removed.
+///
+/// # Arguments
+///
+/// * `conn` - The connection to the database.
+/// * `keyring` - The unlocked keyring, which holds the key of the chain.
+///
+/// # Returns
+///
+/// The number of rows, or a `Crypto` error naming the first row that breaks the chain.
+pub fn verify(conn: &Connection, keyring: &Keyring) -> Result<usize> {
+    let (head, key) = match unlock(conn, keyring)? {
+        Some(unlocked) => unlocked,
+        None => return Ok(0),
+    };
+    let records = read(conn, &Filter::default())?;
+    let hashes = read_hashes(conn)?;
+
+    let mut expected = GENESIS.to_vec();
+    for (record, (prev_hash, hash)) in records.iter().zip(&hashes) {
+        if *prev_hash != expected || *hash != record.hash(&key, prev_hash)? {
+            return Err(EnvnError::Crypto(format!(
+                "The audit log was tampered with at row {}",
+                record.id
+            )));
+        }
+        expected = hash.clone();
+    }
+
+    if records.len() as i64 != head.rows || expected != head.hash {
+        return Err(EnvnError::Crypto(format!(
+            "The audit log holds {} rows, but {} were appended to it",
+            records.len(),
+            head.rows
+        )));
+    }
+    Ok(records.len())
+}
+
+/// Chains the rows of the audit log with a new key.
+/// Older versions chained them with plain SHA-256, which anyone could recompute,
+/// so the old hashes are not worth checking before they are replaced.
+pub(crate) fn rechain(tx: &Transaction, keyring: &Keyring) -> Result<()> {
+    let records = read(tx, &Filter::default())?;
+    if records.is_empty() {
+        return Ok(());
+    }
+
+    let (mut head, key) = new_head(keyring)?;
+    for record in &records {
+        let hash = record.hash(&key, &head.hash)?;
+        tx.execute(
+            "UPDATE audit SET prev_hash = ?1, hash = ?2 WHERE id = ?3",
+            params![head.hash, hash, record.id],
+        )?;
+        head.rows += 1;
+        head.hash = hash;
+    }
+    write_head(tx, &mut head, &key)
+}
+
+/// Encrypts the key of the chain with the current key of the keyring, for a key rotation.
+/// The chain and the head stay as they are, so a tampered log stays detectable.
+pub(crate) fn reencrypt_key(tx: &Transaction, keyring: &Keyring) -> Result<()> {
+    let head = match read_head(tx)? {
+        Some(head) => head,
+        None => return Ok(()),
+    };
+    let key = open_key(&head, keyring)?;
+    let (key_version, data_key) = keyring.current();
+    let nonce = get_nonce();
+    tx.execute(
+        "UPDATE audit_head SET key = ?1, nonce = ?2, key_version = ?3 WHERE id = 1",
+        params![
+            encrypt(data_key, nonce, &key, KEY_AAD)?,
+            nonce.to_vec(),
+            key_version
+        ],
+    )?;
+    Ok(())
+}
+
+/// Parses the `--since` of `envn audit`.
+///
+/// # Arguments
+///
+/// * `since` - A date like `2024-01-31`, a time like `2024-01-31 12:00:00`,
+///   or a time ago like `30m`, `12h` or `7d`.
+///
+/// # Returns
+///
+/// The time in the format of the audit log, or a `Parse` error.
+pub fn parse_since(since: &str) -> Result<String> {
+    let time = match parse_duration(since) {
+        Ok(ago) => now() - ago,
+        Err(_) => parse_time(since).map_err(|_| {
+            EnvnError::Parse(format!(
+                "{} is not a time, use a date like 2024-01-31 or a time ago like 12h or 7d",
+                since
+            ))
+        })?,
+    };
+    Ok(time.format(TIME_FORMAT).to_string())
+}
+
+/// The user of the operating system
+fn current_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// The name of the machine
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    match result {
+        0 => String::from_utf8_lossy(&buf[..len]).to_string(),
+        _ => "unknown".to_string(),
+    }
+}
+
+/// The name of the machine
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "unknown".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{append, parse_since, read, reencrypt_key, verify, Filter};
+    use crate::{
+        db::prepare_db,
+        encryption::{get_key, KdfParams, Keyring},
+        error::EnvnError,
+    };
+    use rusqlite::Connection;
+
+    const KDF: KdfParams = KdfParams {
+        m_cost: 1024,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    fn is_tampered(conn: &Connection, keyring: &Keyring) -> bool {
+        matches!(verify(conn, keyring), Err(EnvnError::Crypto(_)))
+    }
+
+    #[test]
+    fn test_chain_detects_tampering() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut keyring = Keyring::new("hunter2", KDF, get_key()).unwrap();
+        let mut conn = Connection::open_in_memory().unwrap();
+        prepare_db(&mut conn, dir.path(), &keyring).unwrap();
+
+        assert_eq!(verify(&conn, &keyring).unwrap(), 0);
+        for name in ["db", "token", "db"] {
+            append(&conn, &keyring, "get", Some(name), "default", "base").unwrap();
+        }
+        append(&conn, &keyring, "all", None, "default", "base").unwrap();
+
+        assert_eq!(verify(&conn, &keyring).unwrap(), 4);
+        let filter = Filter {
+            since: Some(parse_since("1h").unwrap()),
+            name: Some("db".to_string()),
+        };
+        assert_eq!(read(&conn, &filter).unwrap().len(), 2);
+
+        // another keyring can not verify, nor extend, the chain
+        let other = Keyring::new("hunter2", KDF, get_key()).unwrap();
+        assert!(is_tampered(&conn, &other));
+        assert!(append(&conn, &other, "get", Some("db"), "default", "base").is_err());
+
+        conn.execute("UPDATE audit SET name = 'other' WHERE id = 3", [])
+            .unwrap();
+        assert!(is_tampered(&conn, &keyring));
+        conn.execute("UPDATE audit SET name = 'db' WHERE id = 3", [])
+            .unwrap();
+        assert_eq!(verify(&conn, &keyring).unwrap(), 4);
+
+        // a key rotation keeps the chain, the head follows the newest key
+        keyring.add(get_key());
+        let tx = conn.transaction().unwrap();
+        reencrypt_key(&tx, &keyring).unwrap();
+        tx.commit().unwrap();
+        keyring.retain_current();
+        assert_eq!(verify(&conn, &keyring).unwrap(), 4);
+
+        // removing the newest row is noticed by the head, removing the head as well
+        let backup = dir.path().join("backup.db");
+        conn.execute("VACUUM INTO ?1", [backup.to_str().unwrap()])
+            .unwrap();
+        conn.execute("DELETE FROM audit WHERE id = 4", []).unwrap();
+        assert!(is_tampered(&conn, &keyring));
+        conn.execute("UPDATE audit_head SET rows = 3", []).unwrap();
+        assert!(is_tampered(&conn, &keyring));
+        conn.execute("DELETE FROM audit_head", []).unwrap();
+        assert!(is_tampered(&conn, &keyring));
+
+        let conn = Connection::open(&backup).unwrap();
+        assert_eq!(verify(&conn, &keyring).unwrap(), 4);
+        conn.execute("DELETE FROM audit WHERE id = 2", []).unwrap();
+        assert!(is_tampered(&conn, &keyring));
+
+        assert_eq!(parse_since("2024-01-31").unwrap(), "2024-01-31 00:00:00");
+        assert!(parse_since("yesterday").is_err());
+    }
+}
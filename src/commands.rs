@@ -3,39 +3,159 @@
 /// nor exported to the main file
 /// Any new command should be added to this file as well
 /// as the handler function
-use std::{io::Write, path::Path};
+use std::{
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
 
 use crate::{
-    db::{delete_entry_by_name, does_exist, get_all_names, get_by_name, insert_env},
-    file::{self, compress, decompress, file_exists, join_app_path, load_file_to_insert_in_db},
-    utils::{construct_struct, decrypt_struct, display_env, get_date_time, DisplayEnv},
+    output::{self, Format, SortBy},
+    prompt, AuditCommand, Command, EnvironmentsCommand, FilterArgs, GitCommand, IdentityCommand,
+    ListArgs, MetadataArgs, OnConflict, ProjectsCommand, VaultCommand,
 };
 use bunt::println as print;
+use envn::{
+    agent, audit, dotenv,
+    error::{EnvnError, Result},
+    file::{self, file_exists, read_env_file},
+    identity::{Identity, Recipient},
+    manifest::{Manifest, Resolved, Source},
+    search::Query,
+    share::SharedSecret,
+    utils::{display_env, get_date_time, now, parse_duration},
+    vault::Difference,
+    vaultfile::{self, VaultFile, VAULT_FILE},
+    Vault,
+};
+
+/// The number of secrets on a page of `envn all --page`, unless a range is given
+const DEFAULT_PAGE_SIZE: usize = 20;
 
 /// Handles the command passed in by the user
-pub fn handle_command(cmd: &str, name: Option<String>) {
-    match cmd {
-        "get" => get_command(name),
-        "show" => get_command(name),
-        "add" => add_command(name),
-        "save" => save_command(name),
-        "append" => append_env(name),
-        "all" => all_command(name),
-        "edit" => edit_entry(name),
-        "backup" => backup_command(name),
-        "restore" => restore_command(name),
-        "delete" => delete_entry(name),
-        "load" => load_file(name),
-        "reset" => reset_command(name),
-        _ => print!(
-            "{$red}Command Not Found{/$}\nUse {$yellow}envn help{/$} to see available commands"
-        ),
+/// `vault` is the unlocked store the secrets are read from and written to
+/// Any error ends the process with the exit code of the error
+pub fn handle_command(cmd: Command, mut vault: Vault) {
+    let result = match cmd {
+        Command::Get {
+            name,
+            format,
+            value_only,
+            reveal,
+        } => get_command(name, format, value_only, reveal, &vault),
+        Command::Add {
+            name,
+            key,
+            value,
+            value_stdin,
+            metadata,
+        } => add_command(name, key, value, value_stdin, metadata, &vault),
+        Command::Save { names, output } => save_command(names, output, &vault),
+        Command::Append { name } => append_env(name, &vault),
+        Command::All {
+            range,
+            format,
+            filter,
+            list,
+            reveal,
+        } => all_command(range, format, filter, list, reveal, &vault),
+        Command::Search {
+            pattern,
+            regex,
+            in_values,
+            format,
+            reveal,
+        } => search_command(pattern, regex, in_values, format, reveal, &vault),
+        Command::Edit {
+            name,
+            key,
+            value,
+            metadata,
+        } => edit_entry(name, key, value, metadata, &vault),
+        Command::Backup { name } => backup_command(name, &vault),
+        Command::Restore { name } => restore_command(name, vault),
+        Command::Delete { name, yes } => delete_entry(name, yes, &vault),
+        Command::Load { file, on_conflict } => load_file(file, on_conflict, &vault),
+        Command::Identity { cmd } => identity_command(cmd, &vault),
+        Command::Share { to, names, output } => share_command(to, names, output, &vault),
+        Command::Receive {
+            file,
+            from,
+            on_conflict,
+        } => receive_command(file, from, on_conflict, &vault),
+        Command::Reset { what, yes } => reset_command(what, yes, vault),
+        Command::Verify => verify_command(&vault),
+        Command::Passwd {
+            new_password_fd,
+            new_password_file,
+        } => passwd_command(new_password_fd, new_password_file, &mut vault),
+        Command::Unlock { timeout } => unlock_command(&timeout, &vault),
+        // handled before the store is unlocked
+        Command::Lock | Command::Agent { .. } | Command::Git { .. } => Ok(()),
+        Command::RotateKey => rotate_key_command(&mut vault),
+        Command::Run {
+            names,
+            tag,
+            command,
+        } => run_command(names, tag, command, vault),
+        Command::Vault { file, cmd } => vault_command(file, cmd, &vault),
+        Command::MergeDriver { base, ours, theirs } => {
+            merge_driver_command(&base, &ours, &theirs, &vault)
+        }
+        Command::Projects { cmd } => projects_command(cmd, &mut vault),
+        Command::Environments { cmd } => environments_command(cmd, &mut vault),
+        Command::Diff { environments } => diff_command(&environments, &vault),
+        Command::Sync { output } => sync_command(output, &mut vault),
+        Command::Check => check_command(&mut vault),
+        Command::History { name, reveal } => history_command(name, reveal, &vault),
+        Command::Rollback { name, to } => rollback_command(name, to, &vault),
+        Command::Audit { cmd, since, name } => audit_command(cmd, since, name, &vault),
+    };
+
+    if let Err(e) = result {
+        exit_with_error(e);
     }
 }
 
+/// Prints a friendly message for the error, and exits with its exit code
+pub fn exit_with_error(e: EnvnError) -> ! {
+    bunt::eprintln!("{$red}{}{/$}", e);
+    match e {
+        EnvnError::Crypto(_) => {
+            bunt::eprintln!("Run {$yellow}envn verify{/$} to check every secret")
+        }
+        EnvnError::Prompt(_) => {
+            bunt::eprintln!("Pass the arguments on the command line to skip the prompts")
+        }
+        _ => {}
+    }
+    std::process::exit(e.exit_code());
+}
+
+/// Reads a value from stdin, without the line break it ends with
+fn read_value_from_stdin() -> Result<String> {
+    let mut value = String::new();
+    std::io::stdin().read_to_string(&mut value)?;
+    if value.ends_with('\n') {
+        value.pop();
+        if value.ends_with('\r') {
+            value.pop();
+        }
+    }
+    Ok(value)
+}
+
 /// The Add command
-fn add_command(name: Option<String>) {
+fn add_command(
+    name: Option<String>,
+    key: Option<String>,
+    value: Option<String>,
+    value_stdin: bool,
+    metadata: MetadataArgs,
+    vault: &Vault,
+) -> Result<()> {
     print!("The {$yellow}Setter{/$}");
+    // a bad --expires fails before anything is prompted for
+    let metadata = metadata.update()?;
 
     //if name is not provided, ask for it
     let name = match name {
@@ -43,295 +163,914 @@ fn add_command(name: Option<String>) {
             bunt::println!("{$yellow}Name{/$}: {$green}{}{/$}", name);
             name
         }
-        None => inquire::Text::new("The identifier for this secret")
-            .prompt()
-            .unwrap(),
+        None => prompt::text("The identifier for this secret")?,
+    };
+    let key = match key {
+        Some(key) => key,
+        None => prompt::text("Enter the Key")?,
+    };
+    let value = match value {
+        Some(value) => value,
+        None if value_stdin => read_value_from_stdin()?,
+        None => prompt::text("Enter the Value")?,
     };
-    let key = inquire::Text::new("Enter the Key").prompt().unwrap();
-    let value = inquire::Text::new("Enter the Value").prompt().unwrap();
-
-    // create a struct to store the data
-    let env_entry = construct_struct(name, key, value);
 
-    if crate::db::insert_env(env_entry) {
-        print!("{$green}Secret Saved{/$}");
-    } else {
-        print!("{$red}Secret Not Saved{/$}");
+    vault.set(&name, &key, &value)?;
+    if !metadata.is_empty() {
+        vault.update_metadata(&name, &metadata)?;
     }
+    vault.audit("add", Some(&name))?;
+    print!("{$green}Secret Saved{/$}");
+    Ok(())
 }
 
-fn get_command(name: Option<String>) {
-    print!("The {$yellow}Getter{/$}");
+fn get_command(
+    name: Option<String>,
+    format: Option<Format>,
+    value_only: bool,
+    reveal: bool,
+    vault: &Vault,
+) -> Result<()> {
+    let piped = format.is_some() || value_only;
+    if !piped {
+        print!("The {$yellow}Getter{/$}");
+    }
 
     let name = match name {
         Some(name) => name,
-        None => inquire::Text::new("Secret Name").prompt().unwrap(),
+        None => pick_secret(vault)?,
     };
 
-    if !crate::db::does_exist(&name) {
-        print!("{$red}Secret Not Found{/$}");
-        return;
-    }
+    let env = vault.get(&name)?;
+    vault.audit("get", Some(&name))?;
 
-    let env_entry = get_by_name(&name);
+    match format {
+        Some(format) => std::print!("{}", output::render_one(&env, format, reveal)?),
+        None if value_only => std::print!("{}", env.value),
+        None => display_env(env, reveal),
+    }
+    Ok(())
+}
 
-    let env_entry = env_entry.unwrap();
+/// A secret in the picker of `get`
+struct Choice {
+    name: String,
+    key: String,
+}
 
-    let env = decrypt_struct(env_entry);
+impl std::fmt::Display for Choice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.name, self.key)
+    }
+}
 
-    crate::utils::display_env(env);
+/// Lets the user pick one of the secrets by typing part of its name or key.
+fn pick_secret(vault: &Vault) -> Result<String> {
+    let mut choices: Vec<Choice> = vault
+        .list()?
+        .into_iter()
+        .map(|env| Choice {
+            name: env.name,
+            key: env.key,
+        })
+        .collect();
+    if choices.is_empty() {
+        return Err(EnvnError::NotFound("Secrets to pick from".to_string()));
+    }
+    choices.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(prompt::pick("Secret Name", choices)?.name)
 }
 
-fn save_command(filename: Option<String>) {
+fn save_command(names: Option<Vec<String>>, output: Option<String>, vault: &Vault) -> Result<()> {
     print!("The {$yellow}File{/$}");
     print!("{$yellow}Warning:{/$} This will {$underline}overwrite{/$} any existing file with the same name");
+
+    let names = match names {
+        Some(names) => names,
+        None => match pick_secrets(vault)? {
+            Some(names) => names,
+            None => return Ok(()),
+        },
+    };
+
+    let names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+    let content = vault.export(&names)?;
+    for name in &names {
+        vault.audit("save", Some(name))?;
+    }
+    print!("Loaded {$yellow}{}{/$} secrets to memory", names.len());
+
+    let filename = match output {
+        Some(filename) => filename,
+        None => prompt::text_with_default("File Name", ".env")?,
+    };
+
+    write_env_file(Path::new(&filename), &content)
+}
+
+/// Writes the lines of a .env file, replacing the file if it exists
+fn write_env_file(path: &Path, content: &str) -> Result<()> {
+    std::fs::write(path, content)?;
+    print!("{$green}File Saved{/$}");
+    Ok(())
+}
+
+/// Lets the user pick secrets one by one, until 'quit' is picked
+///
+/// # Returns
+///
+/// The names of the picked secrets, or `None` if the user did not want to pick any.
+fn pick_secrets(vault: &Vault) -> Result<Option<Vec<String>>> {
     print!("Pressing enter will take you into add mode. Just press 'quit' to exit add mode");
 
-    let confirm = inquire::Confirm::new("Enter add mode")
-        .with_default(true)
-        .prompt()
-        .unwrap();
-    if !confirm {
-        return;
+    if !prompt::confirm("Enter add mode", true)? {
+        return Ok(None);
     }
 
-    let envs = get_all_names();
+    let envs = vault.list()?;
     let mut env_names = envs
         .iter()
         .map(|env| env.name.clone())
         .collect::<Vec<String>>();
     env_names.push("quit".to_string());
-    let mut envs_to_write: Vec<DisplayEnv> = Vec::new();
+    let mut picked = Vec::new();
 
     loop {
         if env_names.len() == 1 {
             break;
         }
 
-        let to_add = inquire::Select::new("Select a secret to add", env_names.clone())
-            .prompt()
-            .unwrap();
+        let to_add = prompt::select("Select a secret to add", env_names.clone())?;
 
         if to_add == "quit" {
             break;
         }
 
-        if !env_names.contains(&to_add) {
-            print!("{$red}Secret Not Found{/$}");
-            continue;
+        if let Some(position) = env_names.iter().position(|x| *x == to_add) {
+            env_names.remove(position);
         }
-
-        let env = get_by_name(&to_add).unwrap();
-        let final_env = decrypt_struct(env);
-        envs_to_write.push(final_env);
+        picked.push(to_add);
 
         print!("{$yellow}Secret Added{/$}");
-
-        env_names.remove(env_names.iter().position(|x| *x == to_add).unwrap());
     }
 
-    print!(
-        "Loaded {$yellow}{}{/$} secrets to memory",
-        envs_to_write.len()
-    );
+    Ok(Some(picked))
+}
 
-    let filename = match filename {
-        Some(filename) => filename,
-        None => inquire::Text::new("File Name")
-            .with_default(".env")
-            .prompt()
-            .unwrap(),
+fn all_command(
+    range: Option<String>,
+    format: Option<Format>,
+    filter: FilterArgs,
+    list: ListArgs,
+    reveal: bool,
+    vault: &Vault,
+) -> Result<()> {
+    if format.is_none() {
+        print!("The {$yellow}Show{/$}");
+    }
+    let expiring_before = match (filter.expired, filter.expiring_within) {
+        (true, _) => Some(now()),
+        (false, Some(within)) => Some(now() + parse_duration(&within)?),
+        (false, None) => None,
     };
 
-    let file = std::fs::File::create(filename).unwrap();
-    let mut writer = std::io::BufWriter::new(file);
+    let mut envs = vault.list()?;
+    vault.audit("all", None)?;
+
+    if let Some(tag) = &filter.tag {
+        envs.retain(|env| env.metadata.has_tag(tag));
+    }
+    if let Some(time) = expiring_before {
+        envs.retain(|env| env.metadata.expires_before(time));
+    }
 
-    for env in envs_to_write {
-        //format as key=value
-        let line = format!("{}={}\n", env.key, env.value);
-        writer.write_all(line.as_bytes()).unwrap();
+    let range: usize = match range {
+        Some(range) => range
+            .parse()
+            .map_err(|_| EnvnError::Parse(format!("{} is not a valid range", range)))?,
+        None => 0,
+    };
+    if let Some(by) = list.sort {
+        output::sort(&mut envs, by);
+    }
+    if list.reverse {
+        envs.reverse();
     }
 
-    print!("{$green}File Saved{/$}");
-}
+    // the range is the size of a page, and only the first page is shown without --page
+    match list.page {
+        Some(0) => return Err(EnvnError::Parse("Pages start at 1".to_string())),
+        Some(page) => {
+            let per_page = if range == 0 { DEFAULT_PAGE_SIZE } else { range };
+            envs = envs
+                .into_iter()
+                .skip((page - 1).saturating_mul(per_page))
+                .take(per_page)
+                .collect();
+        }
+        None if range != 0 => envs.truncate(range),
+        None => {}
+    }
 
-fn all_command(range: Option<String>) {
-    print!("The {$yellow}Show{/$}");
-    let envs = get_all_names();
+    if let Some(format) = format {
+        std::print!("{}", output::render_all(&envs, format, reveal)?);
+        return Ok(());
+    }
 
     if envs.is_empty() {
         print!("{$red}No Secrets Found{/$}");
-        return;
+        return Ok(());
     }
 
-    let range: u8 = match range {
-        Some(range) => range.parse().unwrap(),
-        None => 0,
+    for env in envs {
+        display_env(env, reveal);
+    }
+    Ok(())
+}
+
+fn search_command(
+    pattern: Option<String>,
+    regex: bool,
+    in_values: bool,
+    format: Option<Format>,
+    reveal: bool,
+    vault: &Vault,
+) -> Result<()> {
+    if format.is_none() {
+        print!("The {$yellow}Search{/$}");
+    }
+    let pattern = match pattern {
+        Some(pattern) => pattern,
+        None => prompt::text("Search for")?,
+    };
+    let query = match regex {
+        true => Query::regex(&pattern, in_values)?,
+        false => Query::glob(&pattern, in_values)?,
     };
 
-    for (i, env) in envs.iter().enumerate() {
-        if range != 0 && i as u8 == range {
-            break;
-        }
-        display_env(env.clone());
+    let mut envs = vault.list()?;
+    envs.retain(|env| query.matches(env));
+    // every value is decrypted, and the matches are shown like `all` shows them
+    vault.audit("search", None)?;
+    output::sort(&mut envs, SortBy::Name);
+
+    if let Some(format) = format {
+        std::print!("{}", output::render_all(&envs, format, reveal)?);
+        return Ok(());
     }
+    if envs.is_empty() {
+        print!("{$red}No Secrets Found{/$}");
+        return Ok(());
+    }
+    for env in envs {
+        display_env(env, reveal);
+    }
+    Ok(())
 }
 
-fn append_env(name: Option<String>) {
+fn append_env(name: Option<String>, vault: &Vault) -> Result<()> {
     print!("The {$yellow}Appender{/$}");
 
     let name = match name {
         Some(name) => name,
-        None => inquire::Text::new("Secret Name").prompt().unwrap(),
+        None => prompt::text("Secret Name")?,
     };
 
-    if !does_exist(&name) {
-        print!("{$red}Secret Not Found{/$}");
-        return;
-    }
-
-    let env_entry = get_by_name(&name).unwrap();
-
-    let env = decrypt_struct(env_entry);
+    let env = vault.get(&name)?;
+    vault.audit("append", Some(&name))?;
 
     if !file_exists(Path::new(".env")) {
-        let _ = std::fs::File::create(".env");
+        std::fs::File::create(".env")?;
     }
 
-    let mut file = std::fs::OpenOptions::new()
-        .append(true)
-        .open(".env")
-        .unwrap();
+    let mut file = std::fs::OpenOptions::new().append(true).open(".env")?;
 
-    let line = format!("{}={}\n", env.key, env.value);
+    let line = dotenv::write_pair(&env.key, &env.value)?;
 
-    file.write_all(line.as_bytes()).unwrap();
+    file.write_all(line.as_bytes())?;
 
     print!("{$green}Secret Appended{/$}");
+    Ok(())
 }
 
-fn edit_entry(entry: Option<String>) {
+fn edit_entry(
+    entry: Option<String>,
+    key: Option<String>,
+    value: Option<String>,
+    metadata: MetadataArgs,
+    vault: &Vault,
+) -> Result<()> {
+    let metadata = metadata.update()?;
     let entry = match entry {
         Some(entry) => entry,
-        None => inquire::Text::new("Secret Name").prompt().unwrap(),
+        None => prompt::text("Secret Name")?,
     };
 
-    if !does_exist(&entry) {
-        print!("{$red}Secret Not Found{/$}");
-        return;
-    }
-
-    let env_entry = get_by_name(&entry).unwrap();
-
-    let env = decrypt_struct(env_entry);
+    let env = vault.get(&entry)?;
 
     print!("The {$yellow}Editor{/$}");
 
-    let key = inquire::Text::new("Secret Name")
-        .with_initial_value(env.key.as_str())
-        .prompt()
-        .unwrap();
-
-    let value = inquire::Text::new("Secret Value")
-        .with_initial_value(env.value.as_str())
-        .prompt()
-        .unwrap();
-
-    let new_env = construct_struct(entry.clone(), key, value);
-
-    //delete old entry
-    delete_entry_by_name(&entry);
+    // passing either of them keeps the other one as it is,
+    // passing only metadata leaves the secret itself alone
+    let changed = match (key, value) {
+        (None, None) if !metadata.is_empty() => None,
+        (None, None) => Some((
+            prompt::text_with_initial("Secret Name", &env.key)?,
+            prompt::text_with_initial("Secret Value", &env.value)?,
+        )),
+        (key, value) => Some((key.unwrap_or(env.key), value.unwrap_or(env.value))),
+    };
 
-    //insert new entry
-    insert_env(new_env);
+    if let Some((key, value)) = changed {
+        vault.set(&entry, &key, &value)?;
+    }
+    if !metadata.is_empty() {
+        vault.update_metadata(&entry, &metadata)?;
+    }
+    vault.audit("edit", Some(&entry))?;
 
     print!("{$green}Secret Edited{/$}");
+    Ok(())
 }
 
-fn delete_entry(name: Option<String>) {
+fn delete_entry(name: Option<String>, yes: bool, vault: &Vault) -> Result<()> {
     let name = match name {
         Some(name) => name,
-        None => inquire::Text::new("Secret Name").prompt().unwrap(),
+        None => prompt::text("Secret Name")?,
     };
 
-    if !does_exist(&name) {
-        print!("{$red}Secret Not Found{/$}");
-        return;
+    if !vault.exists(&name)? {
+        return Err(EnvnError::NotFound(format!("Secret {}", name)));
     }
 
-    let confirmation = inquire::Confirm::new("Delete from file as well?")
-        .with_default(true)
-        .prompt()
-        .unwrap();
-
-    if confirmation {
-        delete_entry_by_name(&name);
+    if yes || prompt::confirm("Delete from file as well?", true)? {
+        vault.delete(&name)?;
+        vault.audit("delete", Some(&name))?;
         print!("{$green}Secret Deleted{/$}");
     } else {
         print!("{$red}Aborted{/$}");
     }
+    Ok(())
 }
 
-fn load_file(name: Option<String>) {
+fn load_file(name: Option<String>, on_conflict: OnConflict, vault: &Vault) -> Result<()> {
     let name = match name {
         Some(name) => name,
-        None => inquire::Text::new("File Name").prompt().unwrap(),
+        None => prompt::text("File Name")?,
     };
 
     if !file::file_exists(Path::new(&name)) {
         print!("{$red}File Not Found{/$}");
-        let confirm = inquire::Confirm::new("Wanna Load instead?")
-            .with_default(true)
-            .prompt()
-            .unwrap();
-        if !confirm {
-            return;
+        if !prompt::confirm("Wanna Load instead?", true)? {
+            return Ok(());
         } else {
-            save_command(Some(name));
-            return;
+            return save_command(None, Some(name), vault);
         }
     }
 
-    let envs = load_file_to_insert_in_db(Path::new(&name));
-    print!("Loaded {$yellow}{}{/$} secrets to memory", envs.len());
+    let pairs = read_env_file(Path::new(&name))?;
+    print!("Loaded {$yellow}{}{/$} secrets to memory", pairs.len());
 
-    for env in envs {
-        if does_exist(&env.name) {
-            print!("{$red}Secret Already Exists{/$}, use edit instead");
-            continue;
+    let secrets = pairs
+        .into_iter()
+        .map(|(key, value)| SharedSecret {
+            name: key.to_lowercase(),
+            key,
+            value,
+        })
+        .collect();
+    import_secrets(secrets, on_conflict, "load", vault)?;
+
+    print!("{$green}Secrets Saved{/$}");
+    Ok(())
+}
+
+/// Stores secrets from a .env file or a bundle in the environment.
+/// Secrets that are already set to the same key and value are left alone.
+///
+/// # Arguments
+///
+/// * `secrets` - The secrets to store.
+/// * `on_conflict` - What to do with a secret that is set to something else.
+/// * `command` - The command recorded in the audit log.
+/// * `vault` - The vault to store them in.
+///
+/// # Returns
+///
+/// The number of secrets that were stored.
+fn import_secrets(
+    secrets: Vec<SharedSecret>,
+    on_conflict: OnConflict,
+    command: &str,
+    vault: &Vault,
+) -> Result<usize> {
+    let mut stored = 0;
+    for secret in secrets {
+        if vault.exists(&secret.name)? {
+            let current = vault.get(&secret.name)?;
+            if current.key == secret.key && current.value == secret.value {
+                continue;
+            }
+            let overwrite = match on_conflict {
+                OnConflict::Skip => false,
+                OnConflict::Overwrite => true,
+                OnConflict::Ask => prompt::confirm(
+                    &format!("{} already exists, overwrite it?", secret.name),
+                    false,
+                )?,
+            };
+            if !overwrite {
+                print!(
+                    "{$red}Secret {} Already Exists{/$}, use edit instead",
+                    secret.name
+                );
+                continue;
+            }
         }
-        insert_env(env);
+        vault.set(&secret.name, &secret.key, &secret.value)?;
+        vault.audit(command, Some(&secret.name))?;
+        stored += 1;
     }
+    Ok(stored)
+}
 
-    print!("{$green}Secrets Saved{/$}");
+fn identity_command(cmd: IdentityCommand, vault: &Vault) -> Result<()> {
+    match cmd {
+        IdentityCommand::Create { force } => {
+            if let Some(recipient) = vault.recipient()? {
+                bunt::println!(
+                    "{$yellow}Warning:{/$} Bundles shared with {$yellow}{}{/$} can not be received anymore",
+                    recipient
+                );
+                if !force && !prompt::confirm("Replace your identity?", false)? {
+                    return Ok(());
+                }
+            }
+            let recipient = vault.create_identity()?;
+            print!("{$green}Identity Created{/$}, share this with the people who share secrets with you:");
+            println!("{}", recipient);
+        }
+        IdentityCommand::Show => match vault.recipient()? {
+            Some(recipient) => println!("{}", recipient),
+            None => return Err(missing_identity()),
+        },
+    }
+    Ok(())
+}
+
+/// The error for a store without an identity, with a hint on how to create one
+fn missing_identity() -> EnvnError {
+    bunt::eprintln!("Create one with {$yellow}envn identity create{/$}");
+    EnvnError::NotFound("Your identity".to_string())
+}
+
+fn share_command(
+    to: Option<String>,
+    names: Option<Vec<String>>,
+    output: Option<String>,
+    vault: &Vault,
+) -> Result<()> {
+    if vault.recipient()?.is_none() {
+        return Err(missing_identity());
+    }
+    let recipient: Recipient = match to {
+        Some(to) => to,
+        None => prompt::text("Their recipient")?,
+    }
+    .parse()?;
+    let names = ask_names(names)?;
+    let names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+
+    let bundle = vault.share(&names, &recipient)?;
+    for name in &names {
+        vault.audit("share", Some(name))?;
+    }
+
+    match output {
+        Some(output) => {
+            write_env_file(Path::new(&output), &bundle)?;
+            print!(
+                "{$green}Shared {} secrets{/$} in {$yellow}{}{/$}, only {} can open it",
+                names.len(),
+                output,
+                recipient
+            );
+        }
+        None => std::print!("{}", bundle),
+    }
+    Ok(())
+}
+
+/// Asks for the names of secrets, unless they were passed in
+fn ask_names(names: Option<Vec<String>>) -> Result<Vec<String>> {
+    match names {
+        Some(names) => Ok(names),
+        None => Ok(
+            prompt::text("The names of the secrets, separated by commas")?
+                .split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect(),
+        ),
+    }
+}
+
+fn receive_command(
+    file: Option<String>,
+    from: Option<String>,
+    on_conflict: OnConflict,
+    vault: &Vault,
+) -> Result<()> {
+    print!("The {$yellow}Receiver{/$}");
+    if vault.recipient()?.is_none() {
+        return Err(missing_identity());
+    }
+    let file = match file {
+        Some(file) => file,
+        None => prompt::text("The bundle")?,
+    };
+    let from: Option<Recipient> = from.map(|from| from.parse()).transpose()?;
+
+    let bundle = std::fs::read_to_string(&file)?;
+    let (sender, secrets) = vault.open_bundle(&bundle)?;
+    if from.is_some_and(|from| from != sender) {
+        return Err(EnvnError::Auth(format!(
+            "The bundle was shared by {}, not by who --from names",
+            sender
+        )));
+    }
+    bunt::println!(
+        "Opened {$yellow}{}{/$} secrets shared by {$yellow}{}{/$}",
+        secrets.len(),
+        sender
+    );
+
+    let stored = import_secrets(secrets, on_conflict, "receive", vault)?;
+    print!("{$green}Received {} secrets{/$}", stored);
+    Ok(())
+}
+
+/// The identity of the store, with a hint on how to create one if there is none
+fn store_identity(vault: &Vault) -> Result<Identity> {
+    if vault.recipient()?.is_none() {
+        return Err(missing_identity());
+    }
+    vault.identity()
+}
+
+/// The vault file to work on, the one passed in or the one of the current directory
+fn find_vault_file(file: Option<String>) -> Result<PathBuf> {
+    match file {
+        Some(file) => Ok(PathBuf::from(file)),
+        None => VaultFile::find(&std::env::current_dir()?).ok_or_else(|| {
+            bunt::eprintln!("Create one with {$yellow}envn vault init{/$}");
+            EnvnError::NotFound(format!("{} in this directory or its parents", VAULT_FILE))
+        }),
+    }
+}
+
+fn vault_command(file: Option<String>, cmd: VaultCommand, vault: &Vault) -> Result<()> {
+    print!("The {$yellow}Vault Keeper{/$}");
+
+    if let VaultCommand::Init { names } = cmd {
+        let identity = store_identity(vault)?;
+        let path = PathBuf::from(file.unwrap_or(VAULT_FILE.to_string()));
+        if path.exists() {
+            return Err(EnvnError::Io(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} already exists", path.display()),
+            )));
+        }
+        let mut contents = VaultFile::new(&[identity.recipient()]);
+        if let Some(names) = names {
+            push_secrets(&names, &mut contents, vault)?;
+        }
+        write_vault_file(&path, &contents, &identity, vault)?;
+        print!(
+            "{$green}Vault File Created{/$} at {$yellow}{}{/$}, commit it and let others read it with {$yellow}envn vault add-recipient{/$}",
+            path.display()
+        );
+        return Ok(());
+    }
+
+    let path = find_vault_file(file)?;
+    let text = std::fs::read_to_string(&path)?;
+    if let VaultCommand::List = cmd {
+        let listing = vaultfile::list(&text)?;
+        let you = vault.recipient()?;
+        let recipients: Vec<Recipient> = listing.recipients.iter().map(|(r, _)| *r).collect();
+        print!("{$yellow}Recipients{/$}");
+        for (recipient, sealed_by) in &listing.recipients {
+            match Some(*recipient) == you {
+                true => print!("  {} {$dimmed}(you){/$}", recipient),
+                false => println!("  {}", recipient),
+            }
+            match recipients.contains(sealed_by) {
+                true => print!("    {$dimmed}sealed by {}{/$}", sealed_by),
+                false => print!(
+                    "    {$red}sealed by {}, who is not a recipient{/$}",
+                    sealed_by
+                ),
+            }
+        }
+        print!("{$yellow}Secrets{/$}");
+        let width = listing.secrets.iter().map(|(name, _)| name.len()).max();
+        for (name, key) in &listing.secrets {
+            println!("  {:<width$}  {}", name, key, width = width.unwrap_or(0));
+        }
+        return Ok(());
+    }
+
+    let identity = store_identity(vault)?;
+    let mut contents = VaultFile::open(&text, &identity)?;
+    if let Some(sealed_by) = contents.sealed_by() {
+        bunt::println!(
+            "Opened {$yellow}{}{/$}, its key was sealed to you by {$yellow}{}{/$}",
+            path.display(),
+            sealed_by
+        );
+    }
+    check_vault_trust(&path, &contents, vault)?;
+    match cmd {
+        VaultCommand::Push { names } => {
+            let names = ask_names(names)?;
+            push_secrets(&names, &mut contents, vault)?;
+            print!(
+                "{$green}Pushed {} secrets{/$} to {$yellow}{}{/$}",
+                names.len(),
+                path.display()
+            );
+        }
+        VaultCommand::Pull { names, on_conflict } => {
+            let secrets = match names {
+                Some(names) => names
+                    .iter()
+                    .map(|name| {
+                        contents
+                            .get(name)
+                            .cloned()
+                            .ok_or(EnvnError::NotFound(format!(
+                                "Secret {} in the vault file",
+                                name
+                            )))
+                    })
+                    .collect::<Result<Vec<_>>>()?,
+                None => contents.secrets().cloned().collect(),
+            };
+            let stored = import_secrets(secrets, on_conflict, "pull", vault)?;
+            print!("{$green}Pulled {} secrets{/$}", stored);
+            return Ok(());
+        }
+        VaultCommand::AddRecipient { recipient } => {
+            let recipient: Recipient = recipient.parse()?;
+            if !contents.add_recipient(&recipient) {
+                print!("{} can read the vault file already", recipient);
+                return Ok(());
+            }
+            print!(
+                "{$green}Recipient Added{/$}, {} can read the vault file once it is committed",
+                recipient
+            );
+        }
+        VaultCommand::RemoveRecipient { recipient } => {
+            let recipient: Recipient = recipient.parse()?;
+            if !contents.recipients().contains(&recipient) {
+                return Err(EnvnError::NotFound(format!(
+                    "Recipient {} of the vault file",
+                    recipient
+                )));
+            }
+            if contents.recipients().len() == 1 {
+                return Err(EnvnError::Parse(
+                    "The vault file needs at least one recipient".to_string(),
+                ));
+            }
+            if recipient == identity.recipient()
+                && !prompt::confirm(
+                    "You will not be able to read the vault file anymore, continue?",
+                    false,
+                )?
+            {
+                return Ok(());
+            }
+            contents.remove_recipient(&recipient);
+            print!("{$green}Recipient Removed{/$}, the vault file was encrypted with a new key");
+            bunt::println!(
+                "{$yellow}Warning:{/$} {} can still read the versions in the git history, change the secrets they could read",
+                recipient
+            );
+        }
+        // handled above
+        VaultCommand::Init { .. } | VaultCommand::List => {}
+    }
+
+    write_vault_file(&path, &contents, &identity, vault)
+}
+
+/// Compares the recipients of a vault file and who sealed its data key to us with what the
+/// store trusted the last time it used the file, and asks before using a file that changed.
+/// A file used for the first time is trusted as it is.
+fn check_vault_trust(path: &Path, contents: &VaultFile, vault: &Vault) -> Result<()> {
+    let trust = match contents.trust() {
+        Some(trust) => trust,
+        None => return Ok(()),
+    };
+    let path = path.canonicalize()?;
+    match vault.vault_trust(&path)? {
+        None => bunt::println!(
+            "{$dimmed}First time using {}, its recipients are trusted from now on{/$}",
+            path.display()
+        ),
+        Some(trusted) => {
+            let changes = trusted.changes(&trust);
+            if changes.is_empty() {
+                return Ok(());
+            }
+            bunt::println!(
+                "{$yellow}Warning:{/$} The vault file changed since you last used it, anyone who can write to the repository could have changed it"
+            );
+            for change in &changes {
+                println!("  {}", change);
+            }
+            if !prompt::is_interactive() {
+                bunt::eprintln!(
+                    "Run it again without {$yellow}--non-interactive{/$} to review the changes"
+                );
+                return Err(EnvnError::Auth(format!(
+                    "{} changed since it was trusted",
+                    path.display()
+                )));
+            }
+            if !prompt::confirm("Trust the vault file as it is now?", false)? {
+                return Err(EnvnError::Auth(format!(
+                    "{} is not trusted",
+                    path.display()
+                )));
+            }
+        }
+    }
+    vault.trust_vault_file(&path, &trust)
 }
 
-fn reset_command(command: Option<String>) {
+/// Writes a vault file and trusts it as written, unless we can not read it anymore
+fn write_vault_file(
+    path: &Path,
+    contents: &VaultFile,
+    identity: &Identity,
+    vault: &Vault,
+) -> Result<()> {
+    let text = contents.render(identity)?;
+    std::fs::write(path, &text)?;
+    if !contents.recipients().contains(&identity.recipient()) {
+        return Ok(());
+    }
+    match VaultFile::open(&text, identity)?.trust() {
+        Some(trust) => vault.trust_vault_file(&path.canonicalize()?, &trust),
+        None => Ok(()),
+    }
+}
+
+/// Merges the vault file for git, see `envn git install`.
+/// The result is written to `ours`. Secrets both sides changed are reported and left
+/// as our side has them, and the process exits with 1, so that git marks the file as conflicted.
+fn merge_driver_command(base: &str, ours: &str, theirs: &str, vault: &Vault) -> Result<()> {
+    let identity = store_identity(vault)?;
+    let open = |path: &str, version: &str| -> Result<VaultFile> {
+        let text = std::fs::read_to_string(path)?;
+        // git passes an empty file if both sides added the vault file
+        if version == "common" && text.trim().is_empty() {
+            return Ok(VaultFile::new(&[]));
+        }
+        VaultFile::open(&text, &identity).inspect_err(|_| {
+            bunt::eprintln!(
+                "{$red}The {} version of the vault file could not be opened{/$}",
+                version
+            )
+        })
+    };
+    let (merged, conflicts) = vaultfile::merge(
+        &open(base, "common")?,
+        &open(ours, "our")?,
+        &open(theirs, "their")?,
+    );
+    if merged.recipients().is_empty() {
+        return Err(EnvnError::Parse(
+            "The merged vault file would have no recipient left".to_string(),
+        ));
+    }
+    std::fs::write(ours, merged.render(&identity)?)?;
+
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    for conflict in &conflicts {
+        bunt::eprintln!(
+            "{$red}Conflict{/$} in {$yellow}{}{/$}: {} on our side, {} on theirs, kept ours",
+            conflict.name,
+            conflict.ours,
+            conflict.theirs
+        );
+    }
+    bunt::eprintln!(
+        "Put the right values in with {$yellow}envn vault push{/$}, or remove them, then git add the vault file"
+    );
+    std::process::exit(1);
+}
+
+/// Registers `envn merge-driver` for the vault file, in the config of the repository
+/// and in the .gitattributes file at its root
+pub fn git_command(cmd: &GitCommand) -> Result<()> {
+    match cmd {
+        GitCommand::Install => {
+            let root = git(&["rev-parse", "--show-toplevel"])?;
+            git(&["config", "merge.envn-vault.name", "envn vault file"])?;
+            git(&[
+                "config",
+                "merge.envn-vault.driver",
+                "envn merge-driver %O %A %B",
+            ])?;
+
+            let path = Path::new(root.trim()).join(".gitattributes");
+            let attribute = format!("{} merge=envn-vault", VAULT_FILE);
+            let mut attributes = match path.exists() {
+                true => std::fs::read_to_string(&path)?,
+                false => String::new(),
+            };
+            if !attributes.lines().any(|line| line.trim() == attribute) {
+                if !attributes.is_empty() && !attributes.ends_with('\n') {
+                    attributes.push('\n');
+                }
+                attributes.push_str(&attribute);
+                attributes.push('\n');
+                std::fs::write(&path, attributes)?;
+            }
+            print!(
+                "{$green}Merge Driver Installed{/$}, commit {$yellow}{}{/$} so that others only have to run {$yellow}envn git install{/$}",
+                path.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Runs git with the given arguments in the current directory.
+///
+/// # Returns
+///
+/// What git printed, or an error with what it printed to stderr if it failed.
+fn git(args: &[&str]) -> Result<String> {
+    let output = std::process::Command::new("git").args(args).output()?;
+    if !output.status.success() {
+        return Err(EnvnError::Io(std::io::Error::other(format!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Copies secrets of the store into the vault file, looked up like `get` does
+fn push_secrets(names: &[String], contents: &mut VaultFile, vault: &Vault) -> Result<()> {
+    for name in names {
+        let env = vault.get(name)?;
+        contents.set(SharedSecret {
+            name: env.name,
+            key: env.key,
+            value: env.value,
+        })?;
+        vault.audit("push", Some(name))?;
+    }
+    Ok(())
+}
+
+fn reset_command(command: Option<String>, yes: bool, vault: Vault) -> Result<()> {
     let cmd = match command {
         Some(cmd) => cmd,
-        None => inquire::Select::new("Select a command to reset", vec!["all", "db", "password"])
-            .prompt()
-            .unwrap()
-            .to_string(),
+        None => {
+            prompt::select("Select a command to reset", vec!["all", "db", "password"])?.to_string()
+        }
     };
 
-    let auth_file = file::join_app_path("auth");
-    let key_file = file::join_app_path("key");
-    let nonce_file = file::join_app_path("nonce");
-    let db_file = file::join_app_path("env.db");
+    // the database is closed before its file is removed
+    let dir = vault.dir().to_path_buf();
+    drop(vault);
+
+    let auth_file = dir.join("auth");
+    let key_file = dir.join("key");
+    let keyring_file = dir.join("keyring");
+    let nonce_file = dir.join("nonce");
+    let db_file = dir.join("env.db");
 
     match cmd.as_str() {
         "all" => {
             bunt::println!("{$yellow}Warning:{/$} This will {$underline}delete{/$} all your secrets and the password");
-            let confirm = inquire::Confirm::new("Are you sure?")
-                .with_default(false)
-                .prompt()
-                .unwrap();
-            if !confirm {
-                return;
+            if !yes && !prompt::confirm("Are you sure?", false)? {
+                return Ok(());
             }
             let _ = std::fs::remove_file(auth_file);
             let _ = std::fs::remove_file(key_file);
+            let _ = std::fs::remove_file(keyring_file);
             let _ = std::fs::remove_file(nonce_file);
             let _ = std::fs::remove_file(db_file);
             print!("{$green}Reset Complete{/$}");
@@ -340,35 +1079,36 @@ fn reset_command(command: Option<String>) {
             bunt::println!(
                 "{$yellow}Warning:{/$} This will {$underline}delete{/$} all your secrets"
             );
-            let confirm = inquire::Confirm::new("Are you sure?")
-                .with_default(false)
-                .prompt()
-                .unwrap();
-            if !confirm {
-                return;
+            if !yes && !prompt::confirm("Are you sure?", false)? {
+                return Ok(());
             }
             let _ = std::fs::remove_file(db_file);
             let _ = std::fs::remove_file(key_file);
+            let _ = std::fs::remove_file(keyring_file);
             let _ = std::fs::remove_file(nonce_file);
             print!("{$green}Reset Complete{/$}");
         }
         "password" => {
-            bunt::println!("{$yellow}Warning:{/$} This will {$underline}delete{/$} your password");
-            let confirm = inquire::Confirm::new("Are you sure?")
-                .with_default(false)
-                .prompt()
-                .unwrap();
-            if !confirm {
-                return;
+            // the key is wrapped with the password, so the secrets go with it
+            bunt::println!("{$yellow}Warning:{/$} This will {$underline}delete{/$} your password and all your secrets");
+            if !yes && !prompt::confirm("Are you sure?", false)? {
+                return Ok(());
             }
             let _ = std::fs::remove_file(auth_file);
+            let _ = std::fs::remove_file(key_file);
+            let _ = std::fs::remove_file(keyring_file);
+            let _ = std::fs::remove_file(nonce_file);
+            let _ = std::fs::remove_file(db_file);
             print!("{$green}Reset Complete{/$}");
         }
-        _ => bunt::println!("{$red}Command Not Found{/$}"),
+        _ => return Err(EnvnError::NotFound(format!("Reset command {}", cmd))),
     }
+    // the agent still holds the key of the deleted keyring
+    agent::lock(&dir)?;
+    Ok(())
 }
 
-fn backup_command(name: Option<String>) {
+fn backup_command(name: Option<String>, vault: &Vault) -> Result<()> {
     let name = match name {
         Some(name) => match name.ends_with(".tar") {
             true => name,
@@ -376,28 +1116,476 @@ fn backup_command(name: Option<String>) {
         },
         None => format!("envn_backup_{}.tar", get_date_time()),
     };
-    match compress(&name) {
-        Ok(_) => bunt::println!("{$green}Backup {$white}{}{/$} Created{/$}", name),
-        Err(_) => bunt::println!("{$red}Backup Failed{/$}"),
-    }
+    vault.backup(&name)?;
+    bunt::println!("{$green}Backup {$white}{}{/$} Created{/$}", name);
+    Ok(())
 }
 
-fn restore_command(name: Option<String>) {
+fn restore_command(name: Option<String>, vault: Vault) -> Result<()> {
     let name = match name {
         Some(name) => match name.ends_with(".tar") {
             true => name,
             false => format!("{}.tar", name),
         },
-        None => inquire::Text::new("Backup File Name").prompt().unwrap(),
+        None => prompt::text("Backup File Name")?,
+    };
+
+    vault.restore(&name)?;
+    bunt::println!("{$green}Backup restored from {$white}{}{/$}{/$}", name);
+    Ok(())
+}
+
+fn verify_command(vault: &Vault) -> Result<()> {
+    print!("The {$yellow}Verifier{/$}");
+
+    let (total, failed) = vault.verify()?;
+
+    if failed.is_empty() {
+        print!(
+            "{$green}All {} secrets passed the integrity check{/$}",
+            total
+        );
+        return Ok(());
+    }
+
+    for name in &failed {
+        print!("{$red}Integrity check failed:{/$} {}", name);
+    }
+    Err(EnvnError::Crypto(format!(
+        "{} of {} secrets failed the integrity check",
+        failed.len(),
+        total
+    )))
+}
+
+/// Changes the password, which was checked when the store was opened
+fn passwd_command(
+    new_password_fd: Option<i32>,
+    new_password_file: Option<PathBuf>,
+    vault: &mut Vault,
+) -> Result<()> {
+    print!("The {$yellow}Password Changer{/$}");
+
+    let password = match (new_password_fd, new_password_file) {
+        (Some(fd), _) => envn::password::from_fd(fd)?,
+        (None, Some(file)) => envn::password::from_file(&file)?,
+        (None, None) => prompt::new_password("Enter your new password 👀")?,
+    };
+
+    vault.change_password(&password)?;
+    // the key the agent holds only unwraps the keyring of the old password
+    agent::lock(vault.dir())?;
+    print!("{$green}Password Changed{/$}");
+    Ok(())
+}
+
+fn unlock_command(timeout: &str, vault: &Vault) -> Result<()> {
+    // a bad timeout fails before an agent is started with it
+    let idle = agent_timeout(timeout)?;
+    if !agent::is_running(vault.dir()) {
+        agent::spawn(vault.dir(), timeout)?;
+    }
+    agent::unlock(vault.dir(), vault.wrapping_key(), idle)?;
+    print!(
+        "{$green}Unlocked{/$}, until no command used the store for {$yellow}{}{/$}",
+        timeout
+    );
+    Ok(())
+}
+
+/// Parses the `--timeout` of `envn unlock` and `envn agent`
+fn agent_timeout(timeout: &str) -> Result<std::time::Duration> {
+    parse_duration(timeout)?
+        .to_std()
+        .map_err(|_| EnvnError::Parse(format!("{} is not a positive time", timeout)))
+}
+
+/// Handles `envn lock`, which needs no password
+pub fn lock_command(dir: &Path) -> Result<()> {
+    match agent::lock(dir)? {
+        true => print!("{$green}Locked{/$}"),
+        false => print!("{$yellow}No agent was running{/$}"),
+    }
+    Ok(())
+}
+
+/// Handles `envn agent`, which runs until it is locked or idle for `timeout`
+pub fn agent_command(timeout: &str, dir: &Path) -> Result<()> {
+    let timeout = agent_timeout(timeout)?;
+    let socket = agent::socket_path(dir);
+    bunt::println!("Agent listening on {$yellow}{}{/$}", socket.display());
+    agent::serve(dir, timeout)
+}
+
+fn rotate_key_command(vault: &mut Vault) -> Result<()> {
+    print!("The {$yellow}Key Rotator{/$}");
+
+    // the backup holds the old keyring together with the old rows
+    let backup = format!("envn_pre_rotation_{}.tar", get_date_time());
+    vault.backup(&backup)?;
+    print!("{$green}Backup {$white}{}{/$} Created{/$}", backup);
+
+    let (version, count) = vault.rotate_key()?;
+    print!("Re-encrypted {$yellow}{}{/$} secrets", count);
+    print!(
+        "{$green}Key Rotated{/$} to version {$yellow}{}{/$}",
+        version
+    );
+    Ok(())
+}
+
+fn run_command(
+    names: Option<Vec<String>>,
+    tag: Option<String>,
+    command: Vec<String>,
+    vault: Vault,
+) -> Result<()> {
+    let names = match (names, tag) {
+        (Some(names), _) => Some(names),
+        // the same secrets `envn all --tag` shows
+        (None, Some(tag)) => Some(
+            vault
+                .list()?
+                .into_iter()
+                .filter(|env| env.metadata.has_tag(&tag))
+                .map(|env| env.name)
+                .collect(),
+        ),
+        (None, None) => None,
+    };
+    let vars = match names {
+        Some(names) => {
+            let names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+            let vars = vault.env_vars(&names)?;
+            for name in names {
+                vault.audit("run", Some(name))?;
+            }
+            vars
+        }
+        None => {
+            let vars = vault
+                .list()?
+                .into_iter()
+                .map(|env| (env.key, env.value))
+                .collect();
+            vault.audit("run", None)?;
+            vars
+        }
     };
+    drop(vault);
 
-    if !join_app_path("backups").join(&name).exists() {
-        bunt::println!("{$red}Backup File Not Found{/$}");
-        return;
+    let code = crate::process::run_with_env(&command, vars)?;
+    std::process::exit(code);
+}
+
+fn projects_command(cmd: ProjectsCommand, vault: &mut Vault) -> Result<()> {
+    match cmd {
+        ProjectsCommand::List => {
+            for project in vault.projects()? {
+                let marker = match project.name == vault.project() {
+                    true => "*",
+                    false => " ",
+                };
+                let dir = project.dir.unwrap_or_default();
+                bunt::println!(
+                    "{} {$yellow}{}{/$} {} secrets {$dimmed}{}{/$}",
+                    marker,
+                    project.name,
+                    project.secrets,
+                    dir
+                );
+            }
+        }
+        ProjectsCommand::Create { name, dir } => {
+            let dir = match dir {
+                Some(dir) => Some(std::path::absolute(dir)?),
+                None => None,
+            };
+            vault.create_project(&name, dir.as_deref())?;
+            bunt::println!("{$green}Project {$white}{}{/$} Created{/$}", name);
+        }
+        ProjectsCommand::Rename { name, new_name } => {
+            vault.rename_project(&name, &new_name)?;
+            bunt::println!(
+                "{$green}Project {$white}{}{/$} Renamed to {$white}{}{/$}{/$}",
+                name,
+                new_name
+            );
+        }
+        ProjectsCommand::Delete { name, yes } => {
+            bunt::println!("{$yellow}Warning:{/$} This will {$underline}delete{/$} the project and all of its secrets");
+            if !yes && !prompt::confirm("Are you sure?", false)? {
+                return Ok(());
+            }
+            let count = vault.delete_project(&name)?;
+            bunt::println!(
+                "{$green}Project {$white}{}{/$} Deleted{/$} with {} secrets",
+                name,
+                count
+            );
+        }
+    }
+    Ok(())
+}
+
+fn environments_command(cmd: EnvironmentsCommand, vault: &mut Vault) -> Result<()> {
+    match cmd {
+        EnvironmentsCommand::List => {
+            for environment in vault.environments()? {
+                let marker = match environment.name == vault.environment() {
+                    true => "*",
+                    false => " ",
+                };
+                let parent = match environment.parent {
+                    Some(parent) => format!("inherits from {}", parent),
+                    None => String::new(),
+                };
+                bunt::println!(
+                    "{} {$yellow}{}{/$} {} secrets {$dimmed}{}{/$}",
+                    marker,
+                    environment.name,
+                    environment.secrets,
+                    parent
+                );
+            }
+        }
+        EnvironmentsCommand::Create { name, from } => {
+            vault.create_environment(&name, from.as_deref())?;
+            bunt::println!("{$green}Environment {$white}{}{/$} Created{/$}", name);
+        }
+        EnvironmentsCommand::Delete { name, yes } => {
+            bunt::println!("{$yellow}Warning:{/$} This will {$underline}delete{/$} the environment and the secrets set in it");
+            if !yes && !prompt::confirm("Are you sure?", false)? {
+                return Ok(());
+            }
+            let count = vault.delete_environment(&name)?;
+            bunt::println!(
+                "{$green}Environment {$white}{}{/$} Deleted{/$} with {} secrets",
+                name,
+                count
+            );
+        }
+    }
+    Ok(())
+}
+
+fn diff_command(environments: &[String], vault: &Vault) -> Result<()> {
+    let (first, second) = (&environments[0], &environments[1]);
+    print!(
+        "Comparing {$yellow}{}{/$} and {$yellow}{}{/$}",
+        first, second
+    );
+
+    let differences = vault.diff(first, second)?;
+    if differences.is_empty() {
+        print!("{$green}No differences{/$}");
+        return Ok(());
+    }
+
+    for (name, difference) in differences {
+        match difference {
+            Difference::Differs => print!("{$red}differs{/$}        {}", name),
+            Difference::OnlyIn(environment) => {
+                let status = format!("only in {}", environment);
+                print!("{$yellow}{:<14}{/$} {}", status, name)
+            }
+            Difference::Overridden => print!("{$dimmed}overridden{/$}     {}", name),
+        }
+    }
+    Ok(())
+}
+
+/// Finds and reads the manifest of the current directory
+///
+/// # Returns
+///
+/// The path of the manifest and its contents, or a `NotFound` error if there is none.
+fn find_manifest() -> Result<(std::path::PathBuf, Manifest)> {
+    let path = Manifest::find(&std::env::current_dir()?).ok_or(EnvnError::NotFound(format!(
+        "{} in this directory or its parents",
+        envn::manifest::MANIFEST_FILE
+    )))?;
+    let manifest = Manifest::read(&path)?;
+    Ok((path, manifest))
+}
+
+/// Prints every variable of the manifest, with where its value comes from
+///
+/// # Returns
+///
+/// An error naming the number of required variables without a value, if there are any.
+fn report_manifest(resolved: &[Resolved]) -> Result<()> {
+    let width = resolved.iter().map(|r| r.key.len()).max().unwrap_or(0);
+    let mut missing = 0;
+
+    for r in resolved {
+        let key = format!("{:<width$}", r.key);
+        let description = r.description.as_deref().unwrap_or_default();
+        match (&r.source, r.required) {
+            (Source::Secret(name), _) => print!(
+                "{$green}✓{/$} {}  from {$yellow}{}{/$} {$dimmed}{}{/$}",
+                key, name, description
+            ),
+            (Source::Default, _) => print!(
+                "{$green}✓{/$} {}  default {$dimmed}{}{/$}",
+                key, description
+            ),
+            (Source::Missing, true) => {
+                missing += 1;
+                print!("{$red}✗ {}  missing{/$} {$dimmed}{}{/$}", key, description)
+            }
+            (Source::Missing, false) => print!(
+                "{$dimmed}-{/$} {}  optional, missing {$dimmed}{}{/$}",
+                key, description
+            ),
+        }
+    }
+
+    match missing {
+        0 => Ok(()),
+        1 => Err(EnvnError::NotFound("1 required secret".to_string())),
+        n => Err(EnvnError::NotFound(format!("{} required secrets", n))),
+    }
+}
+
+fn check_command(vault: &mut Vault) -> Result<()> {
+    print!("The {$yellow}Checker{/$}");
+
+    let (path, manifest) = find_manifest()?;
+    print!("Checking {$yellow}{}{/$}", path.display());
+
+    report_manifest(&manifest.resolve(vault)?)?;
+    print!("{$green}Every required secret has a value{/$}");
+    Ok(())
+}
+
+fn sync_command(output: Option<String>, vault: &mut Vault) -> Result<()> {
+    print!("The {$yellow}Syncer{/$}");
+
+    let (path, manifest) = find_manifest()?;
+    let resolved = manifest.resolve(vault)?;
+    vault.audit("sync", None)?;
+    report_manifest(&resolved)?;
+
+    // the output of the manifest is relative to the manifest itself
+    let output = match output {
+        Some(output) => std::path::PathBuf::from(output),
+        None => {
+            let dir = path.parent().unwrap_or(Path::new("."));
+            dir.join(manifest.output.as_deref().unwrap_or(".env"))
+        }
+    };
+
+    let pairs: Vec<(String, String)> = resolved
+        .into_iter()
+        .filter_map(|r| Some((r.key, r.value?)))
+        .collect();
+    print!(
+        "Writing {$yellow}{}{/$} secrets to {}",
+        pairs.len(),
+        output.display()
+    );
+    write_env_file(&output, &dotenv::write(&pairs)?)
+}
+
+fn history_command(name: Option<String>, reveal: bool, vault: &Vault) -> Result<()> {
+    let name = match name {
+        Some(name) => name,
+        None => prompt::text("Secret Name")?,
+    };
+
+    print!("The {$yellow}Historian{/$}");
+
+    let history = vault.history(&name)?;
+    if reveal {
+        vault.audit("history", Some(&name))?;
+    }
+
+    for version in history {
+        let change = match version.restored_from {
+            Some(from) => format!("{:<16}", format!("{} to v{}", version.change, from)),
+            None => format!("{:<16}", version.change),
+        };
+        let secret = match version.secret {
+            Some(env) if reveal => format!("{}={}", env.key, env.value),
+            Some(env) => format!("{}=********", env.key),
+            None => String::new(),
+        };
+        print!(
+            "{$yellow}v{}{/$}  {$dimmed}{}{/$}  {}  {}",
+            version.version, version.created_at, change, secret
+        );
+    }
+    Ok(())
+}
+
+fn rollback_command(name: Option<String>, to: Option<u32>, vault: &Vault) -> Result<()> {
+    let name = match name {
+        Some(name) => name,
+        None => prompt::text("Secret Name")?,
+    };
+
+    print!("The {$yellow}Rollbacker{/$}");
+
+    let to = match to {
+        Some(to) => to,
+        None => {
+            let versions = vault
+                .history(&name)?
+                .into_iter()
+                .filter(|version| version.secret.is_some())
+                .collect();
+            prompt::select("Select a version to restore", versions)?.version
+        }
+    };
+
+    let version = vault.rollback(&name, to)?;
+    vault.audit("rollback", Some(&name))?;
+    print!("{$green}Secret Restored{/$} from v{} as v{}", to, version);
+    Ok(())
+}
+
+fn audit_command(
+    cmd: Option<AuditCommand>,
+    since: Option<String>,
+    name: Option<String>,
+    vault: &Vault,
+) -> Result<()> {
+    print!("The {$yellow}Auditor{/$}");
+
+    if let Some(AuditCommand::Verify) = cmd {
+        let count = vault.verify_audit()?;
+        print!(
+            "{$green}All {} entries of the audit log are intact{/$}",
+            count
+        );
+        return Ok(());
+    }
+
+    let filter = audit::Filter {
+        since: since.as_deref().map(audit::parse_since).transpose()?,
+        name,
+    };
+    let records = vault.audit_log(&filter)?;
+    if records.is_empty() {
+        print!("{$red}Nothing Found{/$}");
+        return Ok(());
     }
 
-    match decompress(&name) {
-        Ok(_) => bunt::println!("{$green}Backup restored from {$white}{}{/$}{/$}", name),
-        Err(_) => bunt::println!("{$red}Backup Failed{/$}"),
+    for record in records {
+        let command = format!("{:<9}", record.command);
+        let name = format!("{:<16}", record.name.as_deref().unwrap_or("*"));
+        print!(
+            "{$dimmed}{}{/$}  {$yellow}{}{/$} {}  {}/{}  {}@{}  {$dimmed}{}{/$}",
+            record.created_at,
+            command,
+            name,
+            record.project,
+            record.environment,
+            record.user,
+            record.host,
+            record.cwd
+        );
     }
+    Ok(())
 }
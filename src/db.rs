@@ -1,34 +1,394 @@
 /// This file is responsible for all the database operations
 /// The database used is SQLite
 /// Handles the basic CRUD operations
-use rusqlite::{params, Connection};
+use std::{collections::HashMap, path::Path};
+
+use rusqlite::{params, Connection, Transaction};
 
 use crate::{
-    file::join_app_path,
-    utils::{decrypt_struct, DisplayEnv, Env},
+    encryption::Keyring,
+    error::{EnvnError, Result},
+    utils::{associated_data, construct_struct, decrypt_struct, DisplayEnv, Env, Metadata},
 };
 
 pub struct Entry {
     pub id: i32,
+    pub project_id: i64,
+    pub environment_id: i64,
     pub name: String,
     pub key: String,
     pub value: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub key_version: u32,
+    /// The version of the secret the value belongs to, `None` for the current value
+    pub version: Option<u32>,
+}
+
+/// The identity of the store, with its private half still encrypted
+pub struct IdentityEntry {
+    /// The public half, which is also the associated data of the encrypted private half
+    pub recipient: String,
+    pub secret: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub key_version: u32,
+}
+
+/// A project, the namespace a secret lives in.
+/// The same name can be used for a secret in several projects.
+#[derive(Debug, Clone)]
+pub struct Project {
+    pub id: i64,
+    pub name: String,
+    /// The directory the project is linked to, if any
+    pub dir: Option<String>,
+}
+
+/// The project every secret from before projects ended up in
+pub const DEFAULT_PROJECT: &str = "default";
+
+/// An environment of a project, like `dev` or `prod`.
+/// Secrets that are not set in an environment are inherited from its parent.
+#[derive(Debug, Clone)]
+pub struct Environment {
+    pub id: i64,
+    pub project_id: i64,
+    pub name: String,
+    /// The environment secrets are inherited from, `None` for the base environment
+    pub parent_id: Option<i64>,
+}
+
+/// The environment every project starts out with, the root of the inheritance
+pub const BASE_ENVIRONMENT: &str = "base";
+
+/// The columns selected for an `Entry`, in the order `row_to_entry` expects them
+const ENTRY_COLUMNS: &str = "id, project_id, environment_id, name, key, value, nonce, key_version";
+
+/// A single step that brings the database up to date.
+enum Migration {
+    /// A plain schema change
+    Sql(&'static str),
+    /// A change to the encrypted values, which needs the unlocked key
+    /// The path is the directory of the store, where older versions kept their files
+    Reencrypt(fn(&Transaction, &Keyring, &Path) -> Result<()>),
+}
+
+/// Migrations, applied in order on top of the original `envs` table.
+/// The `user_version` of the database is the number of migrations applied.
+const MIGRATIONS: &[Migration] = &[
+    // every value carries its own nonce, NULL for rows encrypted with the old global nonce
+    Migration::Sql("ALTER TABLE envs ADD COLUMN nonce BLOB"),
+    Migration::Reencrypt(bind_associated_data),
+    // the version of the key in the keyring that encrypted the value
+    Migration::Sql("ALTER TABLE envs ADD COLUMN key_version INTEGER NOT NULL DEFAULT 1"),
+    // names are only unique within a project, which needs a new table in SQLite
+    Migration::Sql(
+        "CREATE TABLE projects (
+            id INTEGER PRIMARY KEY,
+            name TEXT UNIQUE NOT NULL,
+            dir TEXT
+        );
+        INSERT INTO projects (id, name) VALUES (1, 'default');
+        CREATE TABLE envs_new (
+            id INTEGER PRIMARY KEY,
+            project_id INTEGER NOT NULL REFERENCES projects(id),
+            name TEXT NOT NULL,
+            key TEXT NOT NULL,
+            value BLOB NOT NULL,
+            nonce BLOB,
+            key_version INTEGER NOT NULL DEFAULT 1,
+            UNIQUE (project_id, name)
+        );
+        INSERT INTO envs_new (id, project_id, name, key, value, nonce, key_version)
+            SELECT id, 1, name, key, value, nonce, key_version FROM envs;
+        DROP TABLE envs;
+        ALTER TABLE envs_new RENAME TO envs;",
+    ),
+    Migration::Reencrypt(bind_project),
+    // every project gets a base environment, which holds all of its secrets so far
+    Migration::Sql(
+        "CREATE TABLE environments (
+            id INTEGER PRIMARY KEY,
+            project_id INTEGER NOT NULL REFERENCES projects(id),
+            name TEXT NOT NULL,
+            parent_id INTEGER REFERENCES environments(id),
+            UNIQUE (project_id, name)
+        );
+        INSERT INTO environments (project_id, name) SELECT id, 'base' FROM projects;
+        CREATE TABLE envs_new (
+            id INTEGER PRIMARY KEY,
+            project_id INTEGER NOT NULL REFERENCES projects(id),
+            environment_id INTEGER NOT NULL REFERENCES environments(id),
+            name TEXT NOT NULL,
+            key TEXT NOT NULL,
+            value BLOB NOT NULL,
+            nonce BLOB,
+            key_version INTEGER NOT NULL DEFAULT 1,
+            UNIQUE (environment_id, name)
+        );
+        INSERT INTO envs_new (id, project_id, environment_id, name, key, value, nonce, key_version)
+            SELECT envs.id, envs.project_id, environments.id, envs.name, envs.key,
+                envs.value, envs.nonce, envs.key_version
+            FROM envs JOIN environments ON environments.project_id = envs.project_id;
+        DROP TABLE envs;
+        ALTER TABLE envs_new RENAME TO envs;",
+    ),
+    Migration::Reencrypt(bind_environment),
+    // every value a secret had, the current ones are its first version
+    Migration::Sql(
+        "CREATE TABLE versions (
+            id INTEGER PRIMARY KEY,
+            environment_id INTEGER NOT NULL REFERENCES environments(id),
+            name TEXT NOT NULL,
+            version INTEGER NOT NULL,
+            change TEXT NOT NULL,
+            restored_from INTEGER,
+            key TEXT,
+            value BLOB,
+            nonce BLOB,
+            key_version INTEGER,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            UNIQUE (environment_id, name, version)
+        );
+        INSERT INTO versions (environment_id, name, version, change, key, value, nonce, key_version)
+            SELECT environment_id, name, 1, 'create', key, value, nonce, key_version FROM envs;",
+    ),
+    // rows are only ever appended, each one holds the hash of the one before it
+    Migration::Sql(
+        "CREATE TABLE audit (
+            id INTEGER PRIMARY KEY,
+            created_at TEXT NOT NULL,
+            command TEXT NOT NULL,
+            name TEXT,
+            project TEXT NOT NULL,
+            environment TEXT NOT NULL,
+            user TEXT NOT NULL,
+            host TEXT NOT NULL,
+            cwd TEXT NOT NULL,
+            prev_hash BLOB NOT NULL,
+            hash BLOB NOT NULL
+        );",
+    ),
+    // what a secret is for, the timestamps of existing secrets come from their versions
+    Migration::Sql(
+        "ALTER TABLE envs ADD COLUMN description TEXT;
+        ALTER TABLE envs ADD COLUMN tags TEXT;
+        ALTER TABLE envs ADD COLUMN owner TEXT;
+        ALTER TABLE envs ADD COLUMN url TEXT;
+        ALTER TABLE envs ADD COLUMN created_at TEXT;
+        ALTER TABLE envs ADD COLUMN updated_at TEXT;
+        ALTER TABLE envs ADD COLUMN expires_at TEXT;
+        UPDATE envs SET
+            created_at = (SELECT MIN(created_at) FROM versions
+                WHERE versions.environment_id = envs.environment_id AND versions.name = envs.name),
+            updated_at = (SELECT MAX(created_at) FROM versions
+                WHERE versions.environment_id = envs.environment_id AND versions.name = envs.name);",
+    ),
+    // the key pair secrets are shared with, there is at most one
+    Migration::Sql(
+        "CREATE TABLE identity (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            recipient TEXT NOT NULL,
+            secret BLOB NOT NULL,
+            nonce BLOB NOT NULL,
+            key_version INTEGER NOT NULL,
+            created_at TEXT NOT NULL DEFAULT (datetime('now'))
+        );",
+    ),
+    // the audit log is chained with a key of its own, the head counts its rows
+    Migration::Sql(
+        "CREATE TABLE audit_head (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            rows INTEGER NOT NULL,
+            hash BLOB NOT NULL,
+            mac BLOB NOT NULL,
+            key BLOB NOT NULL,
+            nonce BLOB NOT NULL,
+            key_version INTEGER NOT NULL
+        );",
+    ),
+    Migration::Reencrypt(key_audit_log),
+    // every version holds a ciphertext of its own, bound to its version number
+    Migration::Reencrypt(bind_version),
+    // the vault files opened before, with who could read them and who sealed their key to us
+    Migration::Sql(
+        "CREATE TABLE vault_trust (
+            path TEXT PRIMARY KEY,
+            recipients TEXT NOT NULL,
+            sealed_by TEXT NOT NULL,
+            updated_at TEXT NOT NULL DEFAULT (datetime('now'))
+        );",
+    ),
+];
+
+/// The columns selected for a `Metadata`, in the order `row_to_metadata` expects them
+const METADATA_COLUMNS: &str = "description, tags, owner, url, created_at, updated_at, expires_at";
+
+/// What happened to a secret in one of its versions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Create,
+    Update,
+    Delete,
+    /// The value of an older version was restored
+    Rollback,
+}
+
+impl Change {
+    /// The name of the change, as it is stored in the database
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Change::Create => "create",
+            Change::Update => "update",
+            Change::Delete => "delete",
+            Change::Rollback => "rollback",
+        }
+    }
+
+    fn parse(change: &str) -> Result<Change> {
+        match change {
+            "create" => Ok(Change::Create),
+            "update" => Ok(Change::Update),
+            "delete" => Ok(Change::Delete),
+            "rollback" => Ok(Change::Rollback),
+            _ => Err(EnvnError::Parse(format!("Unknown change {}", change))),
+        }
+    }
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+/// A recorded version of a secret, with its value still encrypted
+pub struct VersionEntry {
+    pub version: u32,
+    pub change: Change,
+    /// When the change was made, in UTC
+    pub created_at: String,
+    /// The version whose value a rollback restored
+    pub restored_from: Option<u32>,
+    /// The secret as it was after the change, `None` if it was deleted
+    pub entry: Option<Entry>,
+}
+
+/// Converts a row selected with `ENTRY_COLUMNS` into an `Entry`.
+fn row_to_entry(row: &rusqlite::Row) -> rusqlite::Result<Entry> {
+    Ok(Entry {
+        id: row.get(0)?,
+        project_id: row.get(1)?,
+        environment_id: row.get(2)?,
+        name: row.get(3)?,
+        key: row.get(4)?,
+        value: row.get(5)?,
+        nonce: row.get(6)?,
+        key_version: row.get(7)?,
+        version: None,
+    })
+}
+
+/// Converts the `METADATA_COLUMNS` of a row, starting at `first`, into a `Metadata`.
+fn row_to_metadata(row: &rusqlite::Row, first: usize) -> rusqlite::Result<Metadata> {
+    let tags: Option<String> = row.get(first + 1)?;
+    Ok(Metadata {
+        description: row.get(first)?,
+        tags: tags
+            .map(|tags| tags.split(',').map(str::to_string).collect())
+            .unwrap_or_default(),
+        owner: row.get(first + 2)?,
+        url: row.get(first + 3)?,
+        created_at: row.get(first + 4)?,
+        updated_at: row.get(first + 5)?,
+        expires_at: row.get(first + 6)?,
+    })
+}
+
+/// Converts a row of `id, project_id, name, parent_id` into an `Environment`.
+fn row_to_environment(row: &rusqlite::Row) -> rusqlite::Result<Environment> {
+    Ok(Environment {
+        id: row.get(0)?,
+        project_id: row.get(1)?,
+        name: row.get(2)?,
+        parent_id: row.get(3)?,
+    })
+}
+
+/// Converts a row of `id, name, dir` into a `Project`.
+fn row_to_project(row: &rusqlite::Row) -> rusqlite::Result<Project> {
+    Ok(Project {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        dir: row.get(2)?,
+    })
+}
+
+/// The associated data of the rows from before projects, only used by the migrations.
+fn name_key_associated_data(entry: &Entry) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(&(&entry.name, &entry.key))?)
+}
+
+/// The associated data of the rows from before environments, only used by the migrations.
+fn project_associated_data(entry: &Entry) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(&(
+        entry.project_id,
+        &entry.name,
+        &entry.key,
+    ))?)
 }
 
 /// Connects to the database.
 ///
+/// # Arguments
+///
+/// * `path` - The path to the database file, created if it does not exist.
+///
 /// # Returns
 ///
 /// Returns a `Connection` object representing the connection to the database.
-fn connect_to_db() -> Connection {
-    Connection::open(join_app_path("env.db")).expect("Failed to connect to db")
+pub fn connect_to_db(path: &Path) -> Result<Connection> {
+    Ok(Connection::open(path)?)
+}
+
+/// Checks whether the database of a store holds anything encrypted, without creating it.
+///
+/// # Returns
+///
+/// `true` if any secret, version, identity or audit log is stored in it.
+pub fn has_encrypted_rows(path: &Path) -> Result<bool> {
+    if !path.is_file() {
+        return Ok(false);
+    }
+    let conn = Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    for table in ["envs", "versions", "identity", "audit_head"] {
+        let exists: bool = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+            [table],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            continue;
+        }
+        let sql = format!("SELECT EXISTS (SELECT 1 FROM {})", table);
+        if conn.query_row(&sql, [], |row| row.get(0))? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
 }
 
 /// Prepares the database for usage.
 ///
-/// This function initializes the necessary resources and configurations for the database.
-pub fn prepare_db() {
-    let conn = connect_to_db();
+/// This function initializes the necessary resources and configurations for the database,
+/// and runs any pending migration. Each migration runs in its own transaction together
+/// with the bump of the `user_version`, so it is applied exactly once.
+///
+/// # Arguments
+///
+/// * `conn` - The connection to the database.
+/// * `dir` - The directory of the store.
+/// * `user_key` - The unlocked keyring, needed to re-encrypt the values.
+pub fn prepare_db(conn: &mut Connection, dir: &Path, user_key: &Keyring) -> Result<()> {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS envs (
             id INTEGER PRIMARY KEY,
@@ -37,120 +397,905 @@ pub fn prepare_db() {
             value BLOB NOT NULL
         )",
         [],
+    )?;
+
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        match migration {
+            Migration::Sql(sql) => tx.execute_batch(sql)?,
+            Migration::Reencrypt(migrate) => migrate(&tx, user_key, dir)?,
+        }
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+
+    // every row has its own nonce by now
+    let _ = std::fs::remove_file(dir.join("nonce"));
+    Ok(())
+}
+
+/// Chains the existing rows of the audit log with a key from the keyring.
+fn key_audit_log(tx: &Transaction, user_key: &Keyring, _dir: &Path) -> Result<()> {
+    crate::audit::rechain(tx, user_key)
+}
+
+/// Re-encrypts every row with a fresh nonce, with its `name` and `key` as associated data.
+/// Rows of older versions are either encrypted with the global `nonce` file,
+/// or with their own nonce but without any associated data.
+/// They all predate key rotation, so they are encrypted with the first key.
+fn bind_associated_data(tx: &Transaction, user_key: &Keyring, dir: &Path) -> Result<()> {
+    let legacy_nonce = std::fs::read(dir.join("nonce")).ok();
+    let data_key = user_key.get(1).ok_or(EnvnError::NotFound(
+        "The first key of the keyring".to_string(),
+    ))?;
+
+    // rows without a nonce of their own get an empty one here
+    let rows: Vec<Entry> = {
+        let mut stmt = tx.prepare("SELECT id, name, key, value, nonce FROM envs")?;
+        let rows = stmt.query_map([], |row| {
+            Ok(Entry {
+                id: row.get(0)?,
+                project_id: 1,
+                environment_id: 0,
+                name: row.get(1)?,
+                key: row.get(2)?,
+                value: row.get(3)?,
+                nonce: row.get::<_, Option<Vec<u8>>>(4)?.unwrap_or_default(),
+                key_version: 1,
+                version: None,
+            })
+        })?;
+        rows.collect::<rusqlite::Result<_>>()?
+    };
+
+    for entry in &rows {
+        let nonce = match entry.nonce.is_empty() {
+            false => &entry.nonce,
+            true => legacy_nonce
+                .as_ref()
+                .ok_or(EnvnError::NotFound("The nonce file".to_string()))?,
+        };
+        let plain = crate::encryption::decrypt(data_key, nonce, &entry.value, &[])?;
+
+        let nonce = crate::encryption::get_nonce();
+        let value =
+            crate::encryption::encrypt(data_key, nonce, &plain, &name_key_associated_data(entry)?)?;
+        tx.execute(
+            "UPDATE envs SET value = ?1, nonce = ?2 WHERE id = ?3",
+            params![value, nonce.to_vec(), entry.id],
+        )?;
+    }
+
+    if !rows.is_empty() {
+        bunt::eprintln!(
+            "{$yellow}Re-encrypted {} secrets to bind them to their name and key{/$}",
+            rows.len()
+        );
+    }
+    Ok(())
+}
+
+/// Re-encrypts every row with its project as part of the associated data,
+/// so that moving a value to another project makes the decryption fail.
+/// At this point, every row is in the default project.
+fn bind_project(tx: &Transaction, user_key: &Keyring, _dir: &Path) -> Result<()> {
+    let rows: Vec<Entry> = {
+        let mut stmt =
+            tx.prepare("SELECT id, project_id, name, key, value, nonce, key_version FROM envs")?;
+        let rows = stmt.query_map([], |row| {
+            Ok(Entry {
+                id: row.get(0)?,
+                project_id: row.get(1)?,
+                environment_id: 0,
+                name: row.get(2)?,
+                key: row.get(3)?,
+                value: row.get(4)?,
+                nonce: row.get(5)?,
+                key_version: row.get(6)?,
+                version: None,
+            })
+        })?;
+        rows.collect::<rusqlite::Result<_>>()?
+    };
+    rebind_rows(
+        tx,
+        user_key,
+        "envs",
+        rows,
+        name_key_associated_data,
+        project_associated_data,
+    )
+}
+
+/// Re-encrypts every row with its environment as part of the associated data,
+/// so that moving a value to another environment makes the decryption fail.
+/// At this point, every row is in the base environment of its project.
+fn bind_environment(tx: &Transaction, user_key: &Keyring, _dir: &Path) -> Result<()> {
+    let rows: Vec<Entry> = {
+        let mut stmt = tx.prepare(
+            "SELECT id, project_id, environment_id, name, key, value, nonce, key_version FROM envs",
+        )?;
+        let rows = stmt.query_map([], row_to_entry)?;
+        rows.collect::<rusqlite::Result<_>>()?
+    };
+    rebind_rows(
+        tx,
+        user_key,
+        "envs",
+        rows,
+        project_associated_data,
+        |entry| {
+            associated_data(
+                entry.project_id,
+                entry.environment_id,
+                &entry.name,
+                &entry.key,
+                None,
+            )
+        },
     )
-    .expect("Failed to create table");
+}
+
+/// Re-encrypts every version with its version number as part of the associated data,
+/// so that versions can not be swapped with each other, nor copied back as the current value.
+/// Until now, every version held a copy of the ciphertext of the current value.
+fn bind_version(tx: &Transaction, user_key: &Keyring, _dir: &Path) -> Result<()> {
+    let rows = get_all_version_entries(tx)?;
+    rebind_rows(
+        tx,
+        user_key,
+        "versions",
+        rows,
+        |entry| {
+            associated_data(
+                entry.project_id,
+                entry.environment_id,
+                &entry.name,
+                &entry.key,
+                None,
+            )
+        },
+        |entry| {
+            associated_data(
+                entry.project_id,
+                entry.environment_id,
+                &entry.name,
+                &entry.key,
+                entry.version,
+            )
+        },
+    )
+}
+
+/// Re-encrypts rows from one associated data to another, keeping their key version.
+/// The queries of the migrations list their columns themselves,
+/// since later migrations may add more.
+fn rebind_rows(
+    tx: &Transaction,
+    user_key: &Keyring,
+    table: &str,
+    rows: Vec<Entry>,
+    old: impl Fn(&Entry) -> Result<Vec<u8>>,
+    new: impl Fn(&Entry) -> Result<Vec<u8>>,
+) -> Result<()> {
+    for entry in rows {
+        let data_key = user_key
+            .get(entry.key_version)
+            .ok_or(EnvnError::NotFound(format!(
+                "Key version {} in the keyring",
+                entry.key_version
+            )))?;
+        let plain =
+            crate::encryption::decrypt(data_key, &entry.nonce, &entry.value, &old(&entry)?)?;
+
+        let nonce = crate::encryption::get_nonce();
+        let value = crate::encryption::encrypt(data_key, nonce, &plain, &new(&entry)?)?;
+        tx.execute(
+            &format!("UPDATE {} SET value = ?1, nonce = ?2 WHERE id = ?3", table),
+            params![value, nonce.to_vec(), entry.id],
+        )?;
+    }
+    Ok(())
 }
 
 /// Inserts an environment variable into the database.
 ///
 /// # Arguments
 ///
+/// * `conn` - The connection to the database.
 /// * `env` - The environment variable to be inserted.
+/// * `user_key` - The unlocked keyring, which the value is encrypted with again for its version.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if the insertion is successful, an error otherwise.
+pub fn insert_env(conn: &Connection, env: Env, user_key: &Keyring) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    let change = match does_exist(&tx, env.environment_id, &env.name)? {
+        true => Change::Update,
+        false => Change::Create,
+    };
+    upsert_env(&tx, &env)?;
+    record_version(
+        &tx,
+        env.environment_id,
+        &env.name,
+        change,
+        Some((&env, user_key)),
+        None,
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Puts the value of an older version back, as a new version.
+///
+/// # Arguments
+///
+/// * `conn` - The connection to the database.
+/// * `env` - The secret, encrypted again with the old value.
+/// * `restored_from` - The version the value comes from.
+/// * `user_key` - The unlocked keyring, which the value is encrypted with again for its version.
+///
+/// # Returns
+///
+/// The number of the new version.
+pub fn restore_env(
+    conn: &Connection,
+    env: Env,
+    restored_from: u32,
+    user_key: &Keyring,
+) -> Result<u32> {
+    let tx = conn.unchecked_transaction()?;
+    upsert_env(&tx, &env)?;
+    let version = record_version(
+        &tx,
+        env.environment_id,
+        &env.name,
+        Change::Rollback,
+        Some((&env, user_key)),
+        Some(restored_from),
+    )?;
+    tx.commit()?;
+    Ok(version)
+}
+
+/// Inserts a row, or updates the one with the same name in the environment.
+/// Unlike a replace, the row keeps its id.
+fn upsert_env(conn: &Connection, env: &Env) -> Result<()> {
+    conn.execute(
+        "INSERT INTO envs
+            (project_id, environment_id, name, key, value, nonce, key_version, created_at, updated_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, datetime('now'), datetime('now'))
+        ON CONFLICT (environment_id, name) DO UPDATE SET
+            key = excluded.key,
+            value = excluded.value,
+            nonce = excluded.nonce,
+            key_version = excluded.key_version,
+            updated_at = excluded.updated_at",
+        params![
+            env.project_id,
+            env.environment_id,
+            env.name,
+            env.key,
+            env.value,
+            env.nonce,
+            env.key_version
+        ],
+    )?;
+    Ok(())
+}
+
+/// Records a change to a secret as its next version.
+///
+/// # Arguments
+///
+/// * `conn` - The connection to the database, inside of the transaction that made the change.
+/// * `environment_id` - The environment the secret is set in.
+/// * `name` - The name of the secret.
+/// * `change` - What happened to it.
+/// * `env` - The secret after the change, with the keyring it is encrypted with,
+///   `None` if it was deleted.
+/// * `restored_from` - The version a rollback restored.
 ///
 /// # Returns
 ///
-/// Returns `true` if the insertion is successful, `false` otherwise.
-pub fn insert_env(env: Env) -> bool {
-    let conn = connect_to_db();
-    let mut stmt = conn
-        .prepare("INSERT OR REPLACE INTO envs (name, key, value) VALUES (?1, ?2, ?3)")
-        .expect("Failed to prepare");
-    let _ = stmt
-        .execute(params![env.name, env.key, env.value])
-        .expect("Failed to execute");
-    true
+/// The number of the new version.
+fn record_version(
+    conn: &Connection,
+    environment_id: i64,
+    name: &str,
+    change: Change,
+    env: Option<(&Env, &Keyring)>,
+    restored_from: Option<u32>,
+) -> Result<u32> {
+    let version: u32 = conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) + 1 FROM versions WHERE environment_id = ?1 AND name = ?2",
+        params![environment_id, name],
+        |row| row.get(0),
+    )?;
+    // the version gets a copy of the value of its own, bound to the version number
+    let env = match env {
+        Some((env, user_key)) => {
+            let current = Entry {
+                id: 0,
+                project_id: env.project_id,
+                environment_id: env.environment_id,
+                name: env.name.clone(),
+                key: env.key.clone(),
+                value: env.value.clone(),
+                nonce: env.nonce.clone(),
+                key_version: env.key_version,
+                version: None,
+            };
+            let plain = decrypt_struct(current, user_key)?;
+            Some(construct_struct(
+                env.project_id,
+                env.environment_id,
+                plain.name,
+                plain.key,
+                plain.value,
+                Some(version),
+                user_key,
+            )?)
+        }
+        None => None,
+    };
+    let env = env.as_ref();
+    conn.execute(
+        "INSERT INTO versions
+            (environment_id, name, version, change, restored_from, key, value, nonce, key_version)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            environment_id,
+            name,
+            version,
+            change.as_str(),
+            restored_from,
+            env.map(|env| &env.key),
+            env.map(|env| &env.value),
+            env.map(|env| &env.nonce),
+            env.map(|env| env.key_version)
+        ],
+    )?;
+    Ok(version)
+}
+
+/// Retrieves every version of a secret, oldest first.
+///
+/// # Arguments
+///
+/// * `conn` - The connection to the database.
+/// * `environment_id` - The environment the secret is set in.
+/// * `name` - The name of the secret.
+pub fn get_versions(
+    conn: &Connection,
+    environment_id: i64,
+    name: &str,
+) -> Result<Vec<VersionEntry>> {
+    let mut stmt = conn.prepare(
+        "SELECT versions.version, versions.change, versions.created_at, versions.restored_from,
+            versions.id, environments.project_id, versions.environment_id, versions.name,
+            versions.key, versions.value, versions.nonce, versions.key_version
+        FROM versions JOIN environments ON environments.id = versions.environment_id
+        WHERE versions.environment_id = ?1 AND versions.name = ?2
+        ORDER BY versions.version",
+    )?;
+    let mut rows = stmt.query(params![environment_id, name])?;
+
+    let mut versions = Vec::new();
+    while let Some(row) = rows.next()? {
+        let change: String = row.get(1)?;
+        let key: Option<String> = row.get(8)?;
+        let entry = match key {
+            Some(key) => Some(Entry {
+                id: row.get(4)?,
+                project_id: row.get(5)?,
+                environment_id: row.get(6)?,
+                name: row.get(7)?,
+                key,
+                value: row.get(9)?,
+                nonce: row.get(10)?,
+                key_version: row.get(11)?,
+                version: Some(row.get(0)?),
+            }),
+            None => None,
+        };
+        versions.push(VersionEntry {
+            version: row.get(0)?,
+            change: Change::parse(&change)?,
+            created_at: row.get(2)?,
+            restored_from: row.get(3)?,
+            entry,
+        });
+    }
+    Ok(versions)
 }
 
 /// Retrieves an entry from the database by its name.
 ///
 /// # Arguments
 ///
+/// * `conn` - The connection to the database.
+/// * `environment_id` - The environment the entry is set in, inherited entries are not looked at.
 /// * `name` - The name of the entry to retrieve.
 ///
 /// # Returns
 ///
 /// An `Option<Entry>` representing the retrieved entry, or `None` if no entry with the given name exists.
-pub fn get_by_name(name: &str) -> Option<Entry> {
-    let conn = connect_to_db();
-    let mut stmt = conn
-        .prepare("SELECT * FROM envs WHERE name = ?1")
-        .expect("Failed to prepare");
-    let mut rows = stmt.query(params![name]).expect("Failed to query");
-    let row = rows
-        .next()
-        .expect("Failed to get row")
-        .expect("Failed to get row");
-    let id: i32 = row.get(0).expect("Failed to get id");
-    let name: String = row.get(1).expect("Failed to get name");
-    let key: String = row.get(2).expect("Failed to get key");
-    let value: Vec<u8> = row.get(3).expect("Failed to get value");
-
-    Some(Entry {
-        id,
-        name,
-        key,
-        value,
-    })
+pub fn get_by_name(conn: &Connection, environment_id: i64, name: &str) -> Result<Option<Entry>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM envs WHERE environment_id = ?1 AND name = ?2",
+        ENTRY_COLUMNS
+    ))?;
+    let mut rows = stmt.query(params![environment_id, name])?;
+
+    match rows.next()? {
+        Some(row) => Ok(Some(row_to_entry(row)?)),
+        None => Ok(None),
+    }
+}
+
+/// Retrieves the metadata of an entry by its name.
+///
+/// # Returns
+///
+/// The metadata, or `None` if no entry with the given name is set in the environment.
+pub fn get_metadata(
+    conn: &Connection,
+    environment_id: i64,
+    name: &str,
+) -> Result<Option<Metadata>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM envs WHERE environment_id = ?1 AND name = ?2",
+        METADATA_COLUMNS
+    ))?;
+    let mut rows = stmt.query(params![environment_id, name])?;
+
+    match rows.next()? {
+        Some(row) => Ok(Some(row_to_metadata(row, 0)?)),
+        None => Ok(None),
+    }
+}
+
+/// Retrieves the metadata of every entry set in an environment, by the name of the entry.
+pub fn get_all_metadata(
+    conn: &Connection,
+    environment_id: i64,
+) -> Result<HashMap<String, Metadata>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT name, {} FROM envs WHERE environment_id = ?1",
+        METADATA_COLUMNS
+    ))?;
+    let metadata = stmt.query_map(params![environment_id], |row| {
+        Ok((row.get(0)?, row_to_metadata(row, 1)?))
+    })?;
+    Ok(metadata.collect::<rusqlite::Result<_>>()?)
+}
+
+/// Stores the metadata of an entry, the timestamps are left as they are.
+///
+/// # Returns
+///
+/// `true` if the entry is set in the environment, otherwise nothing was changed.
+pub fn set_metadata(
+    conn: &Connection,
+    environment_id: i64,
+    name: &str,
+    metadata: &Metadata,
+) -> Result<bool> {
+    let tags = Some(metadata.tags.join(",")).filter(|tags| !tags.is_empty());
+    let updated = conn.execute(
+        "UPDATE envs SET description = ?3, tags = ?4, owner = ?5, url = ?6, expires_at = ?7
+        WHERE environment_id = ?1 AND name = ?2",
+        params![
+            environment_id,
+            name,
+            metadata.description,
+            tags,
+            metadata.owner,
+            metadata.url,
+            metadata.expires_at
+        ],
+    )?;
+    Ok(updated > 0)
 }
 
 /// Checks if a record with the given name exists in the database.
 ///
 /// # Arguments
 ///
+/// * `conn` - The connection to the database.
+/// * `environment_id` - The environment the record is set in.
 /// * `name` - The name of the record to check.
 ///
 /// # Returns
 ///
 /// Returns `true` if a record with the given name exists in the database, otherwise `false`.
-pub fn does_exist(name: &str) -> bool {
-    let conn = connect_to_db();
-    let mut stmt = conn
-        .prepare("SELECT * FROM envs WHERE name = ?1")
-        .expect("Failed to prepare");
-    let mut rows = stmt.query(params![name]).expect("Failed to query");
-    let row = rows.next().expect("Failed to get row");
-    row.is_some()
+pub fn does_exist(conn: &Connection, environment_id: i64, name: &str) -> Result<bool> {
+    let mut stmt = conn.prepare("SELECT * FROM envs WHERE environment_id = ?1 AND name = ?2")?;
+    let mut rows = stmt.query(params![environment_id, name])?;
+    Ok(rows.next()?.is_some())
 }
 
-pub fn delete_entry_by_name(name: &str) -> bool {
-    let conn = connect_to_db();
-    let mut stmt = conn
-        .prepare("DELETE FROM envs WHERE name = ?1")
-        .expect("Failed to prepare");
-    let _ = stmt.execute(params![name]).expect("Failed to execute");
-    true
+/// Deletes the entry with the given name in the environment, if it exists.
+/// The deletion is recorded as a version, the older versions are kept.
+pub fn delete_entry_by_name(conn: &Connection, environment_id: i64, name: &str) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    let deleted = tx.execute(
+        "DELETE FROM envs WHERE environment_id = ?1 AND name = ?2",
+        params![environment_id, name],
+    )?;
+    if deleted > 0 {
+        record_version(&tx, environment_id, name, Change::Delete, None, None)?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Retrieves all the raw, still encrypted, entries from the database, of every project.
+///
+/// # Returns
+///
+/// A vector of `Entry` structs.
+pub fn get_all_entries(conn: &Connection) -> Result<Vec<Entry>> {
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM envs", ENTRY_COLUMNS))?;
+    let entries = stmt.query_map([], row_to_entry)?;
+    Ok(entries.collect::<rusqlite::Result<_>>()?)
+}
+
+/// Retrieves the raw, still encrypted, entries set in an environment.
+pub fn get_environment_entries(conn: &Connection, environment_id: i64) -> Result<Vec<Entry>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM envs WHERE environment_id = ?1",
+        ENTRY_COLUMNS
+    ))?;
+    let entries = stmt.query_map(params![environment_id], row_to_entry)?;
+    Ok(entries.collect::<rusqlite::Result<_>>()?)
 }
 
 /// Retrieves all names from the database.
+/// Entries that fail the integrity check are skipped with a warning.
+///
+/// # Arguments
+///
+/// * `conn` - The connection to the database.
+/// * `environment_id` - The environment to retrieve the names of, without the inherited ones.
+/// * `user_key` - The unlocked keyring, used to decrypt the values.
 ///
 /// # Returns
 ///
 /// A vector of `DisplayEnv` structs representing the names.
-pub fn get_all_names() -> Vec<DisplayEnv> {
-    let conn = connect_to_db();
-    let mut stmt = conn
-        .prepare("SELECT * FROM envs")
-        .expect("Failed to prepare");
-    let mut rows = stmt.query([]).expect("Failed to query");
+pub fn get_all_names(
+    conn: &Connection,
+    environment_id: i64,
+    user_key: &Keyring,
+) -> Result<Vec<DisplayEnv>> {
     let mut envs = Vec::new();
+    let mut metadata = get_all_metadata(conn, environment_id)?;
 
-    while let Some(row) = rows.next().expect("Failed to get row") {
-        let id: i32 = row.get(0).expect("Failed to get id");
-        let name: String = row.get(1).expect("Failed to get name");
-        let key: String = row.get(2).expect("Failed to get key");
-        let value: Vec<u8> = row.get(3).expect("Failed to get value");
+    for entry in get_environment_entries(conn, environment_id)? {
+        match decrypt_struct(entry, user_key) {
+            Ok(mut env) => {
+                env.metadata = metadata.remove(&env.name).unwrap_or_default();
+                envs.push(env)
+            }
+            Err(e @ EnvnError::Crypto(_)) => {
+                bunt::eprintln!("{$red}{}{/$}, run {$yellow}envn verify{/$}", e)
+            }
+            Err(e) => return Err(e),
+        }
+    }
 
-        let env = Entry {
-            id,
-            name,
-            key,
-            value,
-        };
+    Ok(envs)
+}
+
+/// Re-encrypts every row with the current key of the keyring.
+/// All rows are converted in a single transaction, so either all of them
+/// or none of them end up under the current key.
+///
+/// # Arguments
+///
+/// * `conn` - The connection to the database.
+/// * `user_key` - The unlocked keyring, holding both the old and the current key.
+///
+/// # Returns
+///
+/// The number of re-encrypted rows, or an error if any of them failed the integrity check,
+/// in which case nothing was changed. The versions of the secrets, the identity and the key
+/// of the audit log are re-encrypted as well.
+pub fn reencrypt_all(conn: &mut Connection, user_key: &Keyring) -> Result<usize> {
+    let entries = get_all_entries(conn)?;
+    let total = entries.len();
+    let versions = get_all_version_entries(conn)?;
+
+    let tx = conn.transaction()?;
+
+    for entry in versions {
+        let (id, project_id, environment_id) = (entry.id, entry.project_id, entry.environment_id);
+        let version = entry.version;
+        let env = decrypt_struct(entry, user_key)?;
+        let env = construct_struct(
+            project_id,
+            environment_id,
+            env.name,
+            env.key,
+            env.value,
+            version,
+            user_key,
+        )?;
+        tx.execute(
+            "UPDATE versions SET value = ?1, nonce = ?2, key_version = ?3 WHERE id = ?4",
+            params![env.value, env.nonce, env.key_version, id],
+        )?;
+    }
+
+    for entry in entries {
+        let (id, project_id, environment_id) = (entry.id, entry.project_id, entry.environment_id);
+        let env = decrypt_struct(entry, user_key)?;
+        let env = construct_struct(
+            project_id,
+            environment_id,
+            env.name,
+            env.key,
+            env.value,
+            None,
+            user_key,
+        )?;
+        tx.execute(
+            "UPDATE envs SET value = ?1, nonce = ?2, key_version = ?3 WHERE id = ?4",
+            params![env.value, env.nonce, env.key_version, id],
+        )?;
+    }
+
+    if let Some(entry) = get_identity(&tx)? {
+        let identity = crate::identity::decrypt_identity(&entry, user_key)?;
+        set_identity(
+            &tx,
+            &crate::identity::encrypt_identity(&identity, user_key)?,
+        )?;
+    }
+    crate::audit::reencrypt_key(&tx, user_key)?;
+
+    tx.commit()?;
+    Ok(total)
+}
 
-        let env = decrypt_struct(env);
+/// Retrieves the identity of the store, if it has one.
+pub fn get_identity(conn: &Connection) -> Result<Option<IdentityEntry>> {
+    let mut stmt =
+        conn.prepare("SELECT recipient, secret, nonce, key_version FROM identity WHERE id = 1")?;
+    let mut rows = stmt.query([])?;
 
-        envs.push(env);
+    match rows.next()? {
+        Some(row) => Ok(Some(IdentityEntry {
+            recipient: row.get(0)?,
+            secret: row.get(1)?,
+            nonce: row.get(2)?,
+            key_version: row.get(3)?,
+        })),
+        None => Ok(None),
     }
+}
+
+/// Stores the identity of the store, replacing the one it had.
+pub fn set_identity(conn: &Connection, entry: &IdentityEntry) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO identity (id, recipient, secret, nonce, key_version)
+        VALUES (1, ?1, ?2, ?3, ?4)",
+        params![
+            entry.recipient,
+            entry.secret,
+            entry.nonce,
+            entry.key_version
+        ],
+    )?;
+    Ok(())
+}
+
+/// Retrieves what the store trusts of the vault file at `path`.
+///
+/// # Returns
+///
+/// The recipients separated by spaces and who sealed the data key,
+/// or `None` if the file was never opened.
+pub fn get_vault_trust(conn: &Connection, path: &str) -> Result<Option<(String, String)>> {
+    let mut stmt = conn.prepare("SELECT recipients, sealed_by FROM vault_trust WHERE path = ?1")?;
+    let mut rows = stmt.query(params![path])?;
+
+    match rows.next()? {
+        Some(row) => Ok(Some((row.get(0)?, row.get(1)?))),
+        None => Ok(None),
+    }
+}
+
+/// Stores what the store trusts of the vault file at `path`, replacing what it trusted before.
+pub fn set_vault_trust(
+    conn: &Connection,
+    path: &str,
+    recipients: &str,
+    sealed_by: &str,
+) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO vault_trust (path, recipients, sealed_by, updated_at)
+        VALUES (?1, ?2, ?3, datetime('now'))",
+        params![path, recipients, sealed_by],
+    )?;
+    Ok(())
+}
+
+/// Retrieves the still encrypted versions of every secret, leaving out deletions.
+fn get_all_version_entries(conn: &Connection) -> Result<Vec<Entry>> {
+    let mut stmt = conn.prepare(
+        "SELECT versions.id, environments.project_id, versions.environment_id, versions.name,
+            versions.key, versions.value, versions.nonce, versions.key_version, versions.version
+        FROM versions JOIN environments ON environments.id = versions.environment_id
+        WHERE versions.key IS NOT NULL",
+    )?;
+    let entries = stmt.query_map([], |row| {
+        Ok(Entry {
+            version: Some(row.get(8)?),
+            ..row_to_entry(row)?
+        })
+    })?;
+    Ok(entries.collect::<rusqlite::Result<_>>()?)
+}
+
+/// Retrieves all the projects, ordered by name.
+pub fn get_projects(conn: &Connection) -> Result<Vec<Project>> {
+    let mut stmt = conn.prepare("SELECT id, name, dir FROM projects ORDER BY name")?;
+    let projects = stmt.query_map([], row_to_project)?;
+    Ok(projects.collect::<rusqlite::Result<_>>()?)
+}
+
+/// Retrieves a project by its name.
+///
+/// # Returns
+///
+/// The project, or `None` if there is no project with this name.
+pub fn get_project_by_name(conn: &Connection, name: &str) -> Result<Option<Project>> {
+    let mut stmt = conn.prepare("SELECT id, name, dir FROM projects WHERE name = ?1")?;
+    let mut rows = stmt.query(params![name])?;
+
+    match rows.next()? {
+        Some(row) => Ok(Some(row_to_project(row)?)),
+        None => Ok(None),
+    }
+}
+
+/// Creates a project, together with its base environment.
+///
+/// # Arguments
+///
+/// * `conn` - The connection to the database.
+/// * `name` - The name of the project, which has to be unused.
+/// * `dir` - The directory to link the project to, if any.
+pub fn create_project(conn: &mut Connection, name: &str, dir: Option<&str>) -> Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute(
+        "INSERT INTO projects (name, dir) VALUES (?1, ?2)",
+        params![name, dir],
+    )?;
+    tx.execute(
+        "INSERT INTO environments (project_id, name) VALUES (?1, ?2)",
+        params![tx.last_insert_rowid(), BASE_ENVIRONMENT],
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Renames a project. Its secrets stay bound to it, since they are bound to its id.
+pub fn rename_project(conn: &Connection, id: i64, name: &str) -> Result<()> {
+    conn.execute(
+        "UPDATE projects SET name = ?1 WHERE id = ?2",
+        params![name, id],
+    )?;
+    Ok(())
+}
+
+/// Deletes a project together with all of its environments and secrets.
+pub fn delete_project(conn: &mut Connection, id: i64) -> Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM envs WHERE project_id = ?1", params![id])?;
+    tx.execute(
+        "DELETE FROM versions WHERE environment_id IN
+            (SELECT id FROM environments WHERE project_id = ?1)",
+        params![id],
+    )?;
+    tx.execute(
+        "DELETE FROM environments WHERE project_id = ?1",
+        params![id],
+    )?;
+    tx.execute("DELETE FROM projects WHERE id = ?1", params![id])?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Counts the secrets in a project.
+pub fn count_entries(conn: &Connection, project_id: i64) -> Result<usize> {
+    Ok(conn.query_row(
+        "SELECT COUNT(*) FROM envs WHERE project_id = ?1",
+        params![project_id],
+        |row| row.get(0),
+    )?)
+}
+
+/// Retrieves the environments of a project, ordered by name.
+pub fn get_environments(conn: &Connection, project_id: i64) -> Result<Vec<Environment>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, project_id, name, parent_id FROM environments
+        WHERE project_id = ?1 ORDER BY name",
+    )?;
+    let environments = stmt.query_map(params![project_id], row_to_environment)?;
+    Ok(environments.collect::<rusqlite::Result<_>>()?)
+}
+
+/// Retrieves an environment of a project by its name.
+///
+/// # Returns
+///
+/// The environment, or `None` if the project has no environment with this name.
+pub fn get_environment_by_name(
+    conn: &Connection,
+    project_id: i64,
+    name: &str,
+) -> Result<Option<Environment>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, project_id, name, parent_id FROM environments
+        WHERE project_id = ?1 AND name = ?2",
+    )?;
+    let mut rows = stmt.query(params![project_id, name])?;
+
+    match rows.next()? {
+        Some(row) => Ok(Some(row_to_environment(row)?)),
+        None => Ok(None),
+    }
+}
+
+/// Retrieves an environment by its id.
+pub fn get_environment(conn: &Connection, id: i64) -> Result<Option<Environment>> {
+    let mut stmt =
+        conn.prepare("SELECT id, project_id, name, parent_id FROM environments WHERE id = ?1")?;
+    let mut rows = stmt.query(params![id])?;
+
+    match rows.next()? {
+        Some(row) => Ok(Some(row_to_environment(row)?)),
+        None => Ok(None),
+    }
+}
+
+/// Creates an environment in a project.
+///
+/// # Arguments
+///
+/// * `conn` - The connection to the database.
+/// * `project_id` - The project the environment is in.
+/// * `name` - The name of the environment, which has to be unused in the project.
+/// * `parent_id` - The environment secrets are inherited from.
+pub fn create_environment(
+    conn: &Connection,
+    project_id: i64,
+    name: &str,
+    parent_id: i64,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO environments (project_id, name, parent_id) VALUES (?1, ?2, ?3)",
+        params![project_id, name, parent_id],
+    )?;
+    Ok(())
+}
 
-    envs
+/// Deletes an environment together with the secrets set in it, and their versions.
+pub fn delete_environment(conn: &mut Connection, id: i64) -> Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM envs WHERE environment_id = ?1", params![id])?;
+    tx.execute(
+        "DELETE FROM versions WHERE environment_id = ?1",
+        params![id],
+    )?;
+    tx.execute("DELETE FROM environments WHERE id = ?1", params![id])?;
+    tx.commit()?;
+    Ok(())
 }
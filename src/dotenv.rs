@@ -0,0 +1,401 @@
+/// This file parses and writes .env files
+///
+/// The format follows what most dotenv implementations accept:
+/// * Blank lines and lines starting with `#` are skipped
+/// * Keys may be prefixed with `export`, like in a shell script
+/// * Unquoted values end at the line break, or at a ` #` inline comment
+/// * Single quoted and backtick quoted values are taken literally
+/// * Double quoted values understand the `\n`, `\r`, `\t`, `\"`, `\\` and `\$` escapes
+/// * Quoted values may span multiple lines
+///
+/// The writer only leaves a value unquoted if every loader reads it the same way,
+/// everything else is double quoted, so that `parse` gives back exactly what was written
+use crate::error::{EnvnError, Result};
+
+/// Parses the contents of a .env file.
+///
+/// # Arguments
+///
+/// * `input` - The contents of the file.
+///
+/// # Returns
+///
+/// The keys and values, in the order they appear in, or a `Parse` error
+/// naming the line the problem is on.
+pub fn parse(input: &str) -> Result<Vec<(String, String)>> {
+    let input = input.strip_prefix('\u{feff}').unwrap_or(input);
+    let input = input.replace("\r\n", "\n");
+    let mut parser = Parser {
+        chars: input.chars().collect(),
+        pos: 0,
+        line: 1,
+    };
+
+    let mut pairs = Vec::new();
+    loop {
+        parser.skip_while(char::is_whitespace);
+        match parser.peek() {
+            None => break,
+            Some('#') => parser.skip_while(|c| c != '\n'),
+            Some(_) => pairs.push(parser.pair()?),
+        }
+    }
+    Ok(pairs)
+}
+
+/// Formats pairs as the lines of a .env file.
+///
+/// # Returns
+///
+/// The `KEY=value` lines, or a `Parse` error if a key can not be written to a .env file.
+pub fn write(pairs: &[(String, String)]) -> Result<String> {
+    pairs
+        .iter()
+        .map(|(key, value)| write_pair(key, value))
+        .collect()
+}
+
+/// Formats a single `KEY=value` line, ending with a line break.
+/// The value is quoted and escaped if it needs to be.
+pub fn write_pair(key: &str, value: &str) -> Result<String> {
+    if !is_key(key) {
+        return Err(EnvnError::Parse(format!(
+            "{} can not be used as a key in a .env file",
+            key
+        )));
+    }
+
+    if value.chars().all(is_plain) {
+        return Ok(format!("{}={}\n", key, value));
+    }
+
+    let mut quoted = String::with_capacity(value.len() + 2);
+    for c in value.chars() {
+        match c {
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '"' | '\\' | '$' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c => quoted.push(c),
+        }
+    }
+    Ok(format!("{}=\"{}\"\n", key, quoted))
+}
+
+/// Checks if the parser reads the whole string as a key
+fn is_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+/// Characters that can be written without quotes
+fn is_plain(c: char) -> bool {
+    c.is_ascii_alphanumeric()
+        || matches!(c, '_' | '.' | '-' | '/' | ':' | '@' | '%' | '+' | ',' | '=')
+}
+
+/// Walks over the characters of a file, keeping track of the line it is on
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn skip_while(&mut self, skip: impl Fn(char) -> bool) {
+        while self.peek().is_some_and(&skip) {
+            self.next();
+        }
+    }
+
+    /// Skips spaces and tabs, but not line breaks.
+    /// Returns true if anything was skipped.
+    fn skip_spaces(&mut self) -> bool {
+        let start = self.pos;
+        self.skip_while(|c| c == ' ' || c == '\t');
+        self.pos > start
+    }
+
+    fn error(&self, line: usize, message: &str) -> EnvnError {
+        EnvnError::Parse(format!("Line {}: {}", line, message))
+    }
+
+    /// Parses a `KEY=value` pair, including an `export` in front of it
+    fn pair(&mut self) -> Result<(String, String)> {
+        let line = self.line;
+        let mut key = self.word();
+        if key == "export" && self.skip_spaces() && self.peek() != Some('=') {
+            key = self.word();
+        }
+
+        if key.is_empty() {
+            return Err(self.error(line, "expected a key"));
+        }
+
+        self.skip_spaces();
+        if self.next() != Some('=') {
+            return Err(self.error(line, &format!("expected = after {}", key)));
+        }
+
+        let spaced = self.skip_spaces();
+        let value = match self.peek() {
+            Some(quote @ ('\'' | '`')) => self.literal(quote)?,
+            Some('"') => self.escaped()?,
+            _ => return Ok((key, self.unquoted(spaced))),
+        };
+
+        // only a comment may follow the closing quote
+        let line = self.line;
+        let spaced = self.skip_spaces();
+        match self.peek() {
+            None | Some('\n') => {}
+            Some('#') if spaced => self.skip_while(|c| c != '\n'),
+            Some(_) => return Err(self.error(line, "unexpected text after the closing quote")),
+        }
+        Ok((key, value))
+    }
+
+    /// Reads a key, made of letters, digits, `_`, `.` and `-`
+    fn word(&mut self) -> String {
+        let mut word = String::new();
+        while let Some(c) = self
+            .peek()
+            .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+        {
+            word.push(c);
+            self.next();
+        }
+        word
+    }
+
+    /// Reads a value up to the end of the line, without an inline comment
+    /// A `#` only starts a comment after a space, so `URL=http://host/#top` keeps its fragment
+    fn unquoted(&mut self, mut spaced: bool) -> String {
+        let mut value = String::new();
+        while let Some(c) = self.peek().filter(|c| *c != '\n') {
+            if c == '#' && spaced {
+                self.skip_while(|c| c != '\n');
+                break;
+            }
+            spaced = c == ' ' || c == '\t';
+            value.push(c);
+            self.next();
+        }
+        value.trim_end().to_string()
+    }
+
+    /// Reads a value between two `quote`s, taking everything in between literally
+    fn literal(&mut self, quote: char) -> Result<String> {
+        let line = self.line;
+        self.next();
+
+        let mut value = String::new();
+        loop {
+            match self.next() {
+                Some(c) if c == quote => return Ok(value),
+                Some(c) => value.push(c),
+                None => return Err(self.error(line, &format!("unterminated {} quote", quote))),
+            }
+        }
+    }
+
+    /// Reads a value between two double quotes, resolving the escape sequences
+    /// Unknown escapes are kept as they are, backslash included
+    fn escaped(&mut self) -> Result<String> {
+        let line = self.line;
+        self.next();
+
+        let mut value = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(value),
+                Some('\\') => match self.next() {
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some('t') => value.push('\t'),
+                    Some(c @ ('"' | '\\' | '$')) => value.push(c),
+                    Some(c) => {
+                        value.push('\\');
+                        value.push(c);
+                    }
+                    None => return Err(self.error(line, "unterminated \" quote")),
+                },
+                Some(c) => value.push(c),
+                None => return Err(self.error(line, "unterminated \" quote")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, write, write_pair};
+    use crate::error::EnvnError;
+    use proptest::prelude::*;
+
+    fn pairs(input: &str) -> Vec<(String, String)> {
+        parse(input).unwrap()
+    }
+
+    fn pair(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    fn error_line(input: &str) -> String {
+        match parse(input) {
+            Err(EnvnError::Parse(message)) => message,
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_comments_blank_lines_and_export() {
+        let input = "\
+# the database
+DATABASE_URL=postgres://localhost/app?sslmode=require
+
+   # indented comment
+export TOKEN=abc==
+export   SPACED = value with spaces   \n\
+EMPTY=
+export=not a prefix
+";
+        assert_eq!(
+            pairs(input),
+            vec![
+                pair("DATABASE_URL", "postgres://localhost/app?sslmode=require"),
+                pair("TOKEN", "abc=="),
+                pair("SPACED", "value with spaces"),
+                pair("EMPTY", ""),
+                pair("export", "not a prefix"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_inline_comments() {
+        let input = "\
+A=value # a comment
+B=http://host/#fragment
+C= # only a comment
+D=\"quoted # not a comment\" # a comment
+E='x'#
+";
+        assert_eq!(
+            error_line(input),
+            "Line 5: unexpected text after the closing quote"
+        );
+        assert_eq!(
+            pairs(&input.replace("E='x'#", "E='x'")),
+            vec![
+                pair("A", "value"),
+                pair("B", "http://host/#fragment"),
+                pair("C", ""),
+                pair("D", "quoted # not a comment"),
+                pair("E", "x"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_quoting_and_escapes() {
+        let input = r#"
+SINGLE='no \n escapes, "double" inside'
+DOUBLE="line\nbreak, tab\t, quote \", backslash \\, dollar \$, unknown \q"
+BACKTICK=`both ' and " inside`
+UNQUOTED=it's "fine"
+"#;
+        assert_eq!(
+            pairs(input),
+            vec![
+                pair("SINGLE", r#"no \n escapes, "double" inside"#),
+                pair(
+                    "DOUBLE",
+                    "line\nbreak, tab\t, quote \", backslash \\, dollar $, unknown \\q"
+                ),
+                pair("BACKTICK", r#"both ' and " inside"#),
+                pair("UNQUOTED", r#"it's "fine""#),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multi_line_values() {
+        let input =
+            "KEY=\"-----BEGIN KEY-----\r\nabc\r\n-----END KEY-----\"\r\nNEXT='a\nb'\nLAST=1";
+        assert_eq!(
+            pairs(input),
+            vec![
+                pair("KEY", "-----BEGIN KEY-----\nabc\n-----END KEY-----"),
+                pair("NEXT", "a\nb"),
+                pair("LAST", "1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors_name_the_line() {
+        assert_eq!(
+            error_line("A=1\n\nnot a pair\n"),
+            "Line 3: expected = after not"
+        );
+        assert_eq!(error_line("A=1\n=2\n"), "Line 2: expected a key");
+        assert_eq!(
+            error_line("A=1\nB=\"open\nstill open\n"),
+            "Line 2: unterminated \" quote"
+        );
+        assert_eq!(
+            error_line("A='x' y\n"),
+            "Line 1: unexpected text after the closing quote"
+        );
+    }
+
+    #[test]
+    fn test_writing() {
+        assert_eq!(
+            write_pair("A", "plain/value=1").unwrap(),
+            "A=plain/value=1\n"
+        );
+        assert_eq!(write_pair("A", "").unwrap(), "A=\n");
+        assert_eq!(
+            write_pair("A", "two words # \"quoted\" $HOME\\\n").unwrap(),
+            "A=\"two words # \\\"quoted\\\" \\$HOME\\\\\\n\"\n"
+        );
+        assert!(write_pair("NOT A KEY", "x").is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn test_write_then_parse(
+            pairs in prop::collection::vec(("[A-Za-z_][A-Za-z0-9_.-]{0,16}", any::<String>()), 0..8)
+        ) {
+            prop_assert_eq!(parse(&write(&pairs).unwrap()).unwrap(), pairs);
+        }
+
+        #[test]
+        fn test_write_then_parse_tricky_values(
+            value in "[ \t\r\n#'`\"\\\\$=a-z]{0,24}"
+        ) {
+            let pairs = vec![("KEY".to_string(), value)];
+            prop_assert_eq!(parse(&write(&pairs).unwrap()).unwrap(), pairs);
+        }
+    }
+}
@@ -8,12 +8,42 @@
 // The key, on the other hand, is kept secret and is used to encrypt and decrypt the data.
 // In our case, the key is generated using the OsRng, which is a cryptographically secure random number generator.
 // Hence, this is a relatively secure way of generating a key.
+// The key itself is never stored in plaintext. It is wrapped (encrypted) with a second key
+// that is derived from the master password using Argon2id and a random salt.
 // The implementation is a bit messy, but it is not too complicated.
 
 use aes_gcm::{
-    aead::{Aead, Nonce, OsRng},
+    aead::{Aead, Nonce, OsRng, Payload},
     AeadCore, Aes256Gcm, Key, KeyInit,
 };
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{EnvnError, Result};
+
+/// The cost parameters for the Argon2id key derivation.
+/// These are stored alongside the wrapped key, so that changing
+/// the defaults never locks anyone out of an existing store.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct KdfParams {
+    /// Memory cost in KiB
+    pub m_cost: u32,
+    /// Number of iterations
+    pub t_cost: u32,
+    /// Degree of parallelism
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
 
 /// Generates a key for AES-256-GCM encryption.
 ///
@@ -35,20 +65,29 @@ pub fn get_nonce() -> Nonce<Aes256Gcm> {
 
 /// Encrypts the data using the key and nonce
 /// The key and nonce are generated using the get_key and get_nonce functions.
+/// The associated data is not encrypted, but decryption fails if it does not match.
 ///
 /// ## Arguments
 ///
 /// * `key` - The encryption key.
 /// * `nonce` - The nonce value.
 /// * `data` - The data to be encrypted.
+/// * `aad` - The associated data to authenticate along with it.
 ///
 /// ## Returns
 ///
 /// The encrypted data as a vector of bytes.
-pub fn encrypt(key: Key<Aes256Gcm>, nonce: Nonce<Aes256Gcm>, data: &[u8]) -> Vec<u8> {
+pub fn encrypt(
+    key: Key<Aes256Gcm>,
+    nonce: Nonce<Aes256Gcm>,
+    data: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
     let cipher = Aes256Gcm::new(&key);
 
-    cipher.encrypt(&nonce, data.as_ref()).unwrap()
+    cipher
+        .encrypt(&nonce, Payload { msg: data, aad })
+        .map_err(|_| EnvnError::Crypto("Failed to encrypt".to_string()))
 }
 
 /// Decrypts the given data using the specified key and nonce.
@@ -56,22 +95,226 @@ pub fn encrypt(key: Key<Aes256Gcm>, nonce: Nonce<Aes256Gcm>, data: &[u8]) -> Vec
 /// ## Arguments
 ///
 /// * `key` - The encryption key to use for decryption.
-/// * `nonce` - The nonce value to use for decryption.
+/// * `nonce` - The nonce value to use for decryption, as stored next to the data.
 /// * `data` - The data to be decrypted.
+/// * `aad` - The associated data the data was encrypted with.
 ///
 /// ## Returns
 ///
-/// The decrypted data as a vector of bytes.
-pub fn decrypt(key: Key<Aes256Gcm>, nonce: Nonce<Aes256Gcm>, data: Vec<u8>) -> Vec<u8> {
+/// The decrypted data as a vector of bytes, or an error if the key, the nonce, the data
+/// or the associated data do not match.
+pub fn decrypt(key: Key<Aes256Gcm>, nonce: &[u8], data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    if nonce.len() != 12 {
+        return Err(EnvnError::Crypto("Invalid nonce".to_string()));
+    }
     let cipher = Aes256Gcm::new(&key);
-    let nonce = Nonce::<Aes256Gcm>::from_slice(nonce.as_ref());
 
-    cipher.decrypt(nonce, data.as_ref()).unwrap()
+    cipher
+        .decrypt(
+            Nonce::<Aes256Gcm>::from_slice(nonce),
+            Payload { msg: data, aad },
+        )
+        .map_err(|_| EnvnError::Crypto("Failed to decrypt".to_string()))
+}
+
+/// Generates a random salt for the key derivation.
+pub fn get_salt() -> Vec<u8> {
+    let mut salt = vec![0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+/// Derives a key from the master password using Argon2id.
+///
+/// ## Arguments
+///
+/// * `password` - The master password.
+/// * `salt` - The salt stored alongside the wrapped key.
+/// * `params` - The Argon2id cost parameters.
+///
+/// ## Returns
+///
+/// The derived key, used to wrap and unwrap the data key.
+pub fn derive_key(password: &str, salt: &[u8], params: KdfParams) -> Result<Key<Aes256Gcm>> {
+    let params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32))
+        .map_err(|e| EnvnError::Crypto(format!("Invalid key derivation parameters: {}", e)))?;
+    let argon = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+
+    let mut key = Key::<Aes256Gcm>::default();
+    argon
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|e| EnvnError::Crypto(format!("Failed to derive key: {}", e)))?;
+    Ok(key)
+}
+
+/// A keyring, as it is stored on disk.
+/// The data keys are wrapped with a key derived from the master password.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SealedKeyring {
+    salt: Vec<u8>,
+    kdf: KdfParams,
+    nonce: Vec<u8>,
+    wrapped_key: Vec<u8>,
+}
+
+/// The key derived from the password, which unwraps the keyring.
+/// The agent holds on to it, so that the password is not needed again
+/// until the password changes.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WrappingKey {
+    salt: Vec<u8>,
+    kdf: KdfParams,
+    key: Vec<u8>,
+}
+
+/// The unlocked data keys.
+///
+/// Every key has a version, which is recorded on the rows it encrypted.
+/// New values are always encrypted with the newest key, older keys are only
+/// kept around until a key rotation re-encrypted all of their rows.
+#[derive(Clone)]
+pub struct Keyring {
+    salt: Vec<u8>,
+    kdf: KdfParams,
+    kek: Key<Aes256Gcm>,
+    keys: Vec<(u32, Key<Aes256Gcm>)>,
+}
+
+impl Keyring {
+    /// Creates a keyring holding a single key, wrapped with the password.
+    pub fn new(password: &str, kdf: KdfParams, key: Key<Aes256Gcm>) -> Result<Self> {
+        let salt = get_salt();
+        Ok(Keyring {
+            kek: derive_key(password, &salt, kdf)?,
+            salt,
+            kdf,
+            keys: vec![(1, key)],
+        })
+    }
+
+    /// Unwraps a sealed keyring with the password.
+    ///
+    /// ## Returns
+    ///
+    /// The keyring, or an error if the password was wrong or the keyring was tampered with.
+    pub fn unseal(sealed: &SealedKeyring, password: &str) -> Result<Self> {
+        let kek = derive_key(password, &sealed.salt, sealed.kdf)?;
+        Keyring::unseal_with_kek(sealed, kek)
+    }
+
+    /// Unwraps a sealed keyring with a wrapping key, without deriving it from the password.
+    ///
+    /// ## Returns
+    ///
+    /// The keyring, or an `Auth` error if the keyring was wrapped again since,
+    /// with another password or salt.
+    pub fn unseal_with(sealed: &SealedKeyring, wrapping_key: &WrappingKey) -> Result<Self> {
+        if wrapping_key.salt != sealed.salt
+            || wrapping_key.kdf != sealed.kdf
+            || wrapping_key.key.len() != 32
+        {
+            return Err(EnvnError::Auth(
+                "The keyring was wrapped with another password".to_string(),
+            ));
+        }
+        let kek = Key::<Aes256Gcm>::clone_from_slice(&wrapping_key.key);
+        Keyring::unseal_with_kek(sealed, kek)
+    }
+
+    fn unseal_with_kek(sealed: &SealedKeyring, kek: Key<Aes256Gcm>) -> Result<Self> {
+        let plain = decrypt(kek, &sealed.nonce, &sealed.wrapped_key, &[])
+            .map_err(|_| EnvnError::Auth("The keyring could not be unlocked".to_string()))?;
+
+        // keyrings from before key rotation hold a single raw key
+        let keys: Vec<(u32, Vec<u8>)> = match plain.len() {
+            32 => vec![(1, plain)],
+            _ => bincode::deserialize(&plain)?,
+        };
+        if keys.is_empty() || keys.iter().any(|(_, key)| key.len() != 32) {
+            return Err(EnvnError::Parse("The keyring is malformed".to_string()));
+        }
+
+        Ok(Keyring {
+            salt: sealed.salt.clone(),
+            kdf: sealed.kdf,
+            kek,
+            keys: keys
+                .into_iter()
+                .map(|(version, key)| (version, Key::<Aes256Gcm>::clone_from_slice(&key)))
+                .collect(),
+        })
+    }
+
+    /// Wraps the keys, ready to be stored on disk.
+    pub fn seal(&self) -> Result<SealedKeyring> {
+        let keys: Vec<(u32, Vec<u8>)> = self
+            .keys
+            .iter()
+            .map(|(version, key)| (*version, key.to_vec()))
+            .collect();
+        let plain = bincode::serialize(&keys)?;
+
+        let nonce = get_nonce();
+        Ok(SealedKeyring {
+            salt: self.salt.clone(),
+            kdf: self.kdf,
+            nonce: nonce.to_vec(),
+            wrapped_key: encrypt(self.kek, nonce, &plain, &[])?,
+        })
+    }
+
+    /// The key the keyring is wrapped with.
+    pub fn wrapping_key(&self) -> WrappingKey {
+        WrappingKey {
+            salt: self.salt.clone(),
+            kdf: self.kdf,
+            key: self.kek.to_vec(),
+        }
+    }
+
+    /// Wraps the keys with a new password from now on.
+    pub fn rewrap(&mut self, password: &str, kdf: KdfParams) -> Result<()> {
+        self.salt = get_salt();
+        self.kdf = kdf;
+        self.kek = derive_key(password, &self.salt, kdf)?;
+        Ok(())
+    }
+
+    /// The newest key and its version, used for everything that is encrypted.
+    /// A keyring always holds at least one key.
+    pub fn current(&self) -> (u32, Key<Aes256Gcm>) {
+        self.keys[self.keys.len() - 1]
+    }
+
+    /// The key with the given version, if the keyring still holds it.
+    pub fn get(&self, version: u32) -> Option<Key<Aes256Gcm>> {
+        self.keys
+            .iter()
+            .find(|(v, _)| *v == version)
+            .map(|(_, key)| *key)
+    }
+
+    /// Adds a key, which becomes the current one.
+    ///
+    /// ## Returns
+    ///
+    /// The version of the new key.
+    pub fn add(&mut self, key: Key<Aes256Gcm>) -> u32 {
+        let version = self.current().0 + 1;
+        self.keys.push((version, key));
+        version
+    }
+
+    /// Drops every key but the current one.
+    pub fn retain_current(&mut self) {
+        let current = self.current();
+        self.keys = vec![current];
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{decrypt, encrypt, get_key, get_nonce};
+    use super::{decrypt, encrypt, get_key, get_nonce, KdfParams, Keyring};
 
     #[test]
     fn test_encryption_and_decryption() {
@@ -80,10 +323,42 @@ mod tests {
 
         let data = "Envn is awesome 123!".as_bytes();
 
-        let encrypted_data = encrypt(key, nonce, data);
+        let encrypted_data = encrypt(key, nonce, data, &[]).unwrap();
 
-        let decrypted_data = decrypt(key, nonce, encrypted_data.clone());
+        let decrypted_data = decrypt(key, &nonce, &encrypted_data, &[]).unwrap();
 
         assert_eq!(decrypted_data, data);
     }
+
+    #[test]
+    fn test_associated_data_mismatch() {
+        let key = get_key();
+        let nonce = get_nonce();
+
+        let encrypted_data = encrypt(key, nonce, b"postgres://prod", b"prod_db").unwrap();
+
+        assert!(decrypt(key, &nonce, &encrypted_data, b"prod_db").is_ok());
+        assert!(decrypt(key, &nonce, &encrypted_data, b"dev_db").is_err());
+    }
+
+    #[test]
+    fn test_sealing_and_unsealing() {
+        // keep the test fast, the real defaults are much more expensive
+        let kdf = KdfParams {
+            m_cost: 1024,
+            t_cost: 1,
+            p_cost: 1,
+        };
+        let key = get_key();
+        let mut keyring = Keyring::new("hunter2", kdf, key).unwrap();
+        let new_key = get_key();
+        assert_eq!(keyring.add(new_key), 2);
+
+        let sealed = keyring.seal().unwrap();
+        assert!(Keyring::unseal(&sealed, "hunter3").is_err());
+
+        let unsealed = Keyring::unseal(&sealed, "hunter2").unwrap();
+        assert_eq!(unsealed.get(1), Some(key));
+        assert_eq!(unsealed.current(), (2, new_key));
+    }
 }
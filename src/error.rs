@@ -0,0 +1,108 @@
+/// This file holds the error type shared by the whole program
+/// Every fallible function returns an `EnvnError`, which the command
+/// handler turns into a friendly message and a distinct exit code
+use std::fmt;
+
+/// The errors envn can run into
+#[derive(Debug)]
+pub enum EnvnError {
+    /// Reading or writing a file failed
+    Io(std::io::Error),
+    /// The database could not be read or written
+    Sqlite(rusqlite::Error),
+    /// A value could not be encrypted or decrypted, usually because it was tampered with
+    Crypto(String),
+    /// A file or a value is not in the expected format
+    Parse(String),
+    /// The password is wrong, or there is none
+    Auth(String),
+    /// The requested secret or file does not exist
+    NotFound(String),
+    /// A prompt could not be shown or was cancelled
+    Prompt(String),
+}
+
+/// The result type used throughout envn
+pub type Result<T> = std::result::Result<T, EnvnError>;
+
+impl EnvnError {
+    /// The exit code the process ends with for this error
+    /// These are stable, so that scripts can branch on them
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            EnvnError::Io(_) => 3,
+            EnvnError::Sqlite(_) => 4,
+            EnvnError::Crypto(_) => 5,
+            EnvnError::Parse(_) => 6,
+            EnvnError::Auth(_) => 7,
+            EnvnError::NotFound(_) => 8,
+            EnvnError::Prompt(_) => 9,
+        }
+    }
+}
+
+impl fmt::Display for EnvnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvnError::Io(e) => write!(f, "File error: {}", e),
+            EnvnError::Sqlite(e) => write!(f, "Database error: {}", e),
+            EnvnError::Crypto(msg) => write!(f, "Encryption error: {}", msg),
+            EnvnError::Parse(msg) => write!(f, "Parse error: {}", msg),
+            EnvnError::Auth(msg) => write!(f, "Authentication error: {}", msg),
+            EnvnError::NotFound(what) => write!(f, "{} not found", what),
+            EnvnError::Prompt(msg) => write!(f, "Prompt error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for EnvnError {}
+
+impl From<std::io::Error> for EnvnError {
+    fn from(e: std::io::Error) -> Self {
+        EnvnError::Io(e)
+    }
+}
+
+impl From<rusqlite::Error> for EnvnError {
+    fn from(e: rusqlite::Error) -> Self {
+        EnvnError::Sqlite(e)
+    }
+}
+
+impl From<bincode::Error> for EnvnError {
+    fn from(e: bincode::Error) -> Self {
+        EnvnError::Parse(e.to_string())
+    }
+}
+
+impl From<inquire::InquireError> for EnvnError {
+    fn from(e: inquire::InquireError) -> Self {
+        EnvnError::Prompt(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EnvnError;
+
+    #[test]
+    fn test_exit_codes() {
+        // the codes are documented in the README, scripts rely on them
+        let errors = [
+            (EnvnError::Io(std::io::Error::other("disk full")), 3),
+            (EnvnError::Sqlite(rusqlite::Error::InvalidQuery), 4),
+            (EnvnError::Crypto("tampered".to_string()), 5),
+            (EnvnError::Parse("line 1".to_string()), 6),
+            (EnvnError::Auth("Wrong password".to_string()), 7),
+            (EnvnError::NotFound("Secret db".to_string()), 8),
+            (EnvnError::Prompt("cancelled".to_string()), 9),
+        ];
+        for (error, code) in errors {
+            assert_eq!(error.exit_code(), code, "{}", error);
+        }
+        assert_eq!(
+            EnvnError::NotFound("Secret db".to_string()).to_string(),
+            "Secret db not found"
+        );
+    }
+}
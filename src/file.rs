@@ -1,12 +1,16 @@
 use std::{
     fs::File,
-    io::BufRead,
+    io::Write,
     path::{Path, PathBuf},
 };
 
+use aes_gcm::{Aes256Gcm, Key};
 use tar::Builder;
 
-use crate::utils::{construct_struct, Env};
+use crate::{
+    encryption::{KdfParams, Keyring, SealedKeyring, WrappingKey},
+    error::{EnvnError, Result},
+};
 
 /// Returns the home path.
 ///
@@ -15,11 +19,11 @@ use crate::utils::{construct_struct, Env};
 /// ```
 /// use envn::file::get_home_path;
 ///
-/// let home_path = get_home_path();
-/// println!("Home path: {}", home_path);
+/// let home_path = get_home_path().unwrap();
+/// println!("Home path: {}", home_path.display());
 /// ```
-pub fn get_home_path() -> PathBuf {
-    dirs::home_dir().expect("Failed to get home directory")
+pub fn get_home_path() -> Result<PathBuf> {
+    dirs::home_dir().ok_or(EnvnError::NotFound("The home directory".to_string()))
 }
 
 /// Returns the path to the application directory.
@@ -27,38 +31,85 @@ pub fn get_home_path() -> PathBuf {
 /// # Returns
 ///
 /// The path to the application directory as a `PathBuf` object.
-pub fn get_app_dir_path() -> PathBuf {
-    let config = get_config_file();
+pub fn get_app_dir_path() -> Result<PathBuf> {
+    let config = get_config_file()?;
     let base_dir = config.base_dir.as_str();
 
     let path = Path::new(base_dir);
 
     if !path.exists() {
-        std::fs::create_dir_all(path).expect("Failed to create App directory");
+        std::fs::create_dir_all(path)?;
     }
 
-    path.to_path_buf()
+    Ok(path.to_path_buf())
 }
 /// Represents the configuration file
+///
+/// The password is always required, since it is needed to unwrap the
+/// encryption key. Use `--password-fd`, `--password-file`, the `ENVN_PASSWORD`
+/// environment variable or `password_command` to skip the prompt.
 #[derive(serde::Deserialize, Debug)]
 pub struct Config {
     pub base_dir: String,
-    pub ask_for_password: bool,
+    /// Argon2id memory cost in KiB, used when the key is (re)wrapped
+    #[serde(default = "default_kdf_memory_cost")]
+    pub kdf_memory_cost: u32,
+    /// Argon2id iterations, used when the key is (re)wrapped
+    #[serde(default = "default_kdf_time_cost")]
+    pub kdf_time_cost: u32,
+    /// Argon2id parallelism, used when the key is (re)wrapped
+    #[serde(default = "default_kdf_parallelism")]
+    pub kdf_parallelism: u32,
+    /// Whether `get` shows values without `--reveal`, `all` never does
+    #[serde(default)]
+    pub reveal_values: bool,
+    /// A command that prints the password, like `pass show envn`
+    #[serde(default)]
+    pub password_command: Option<String>,
+}
+
+impl Config {
+    /// The key derivation parameters for newly wrapped keys
+    pub fn kdf_params(&self) -> KdfParams {
+        KdfParams {
+            m_cost: self.kdf_memory_cost,
+            t_cost: self.kdf_time_cost,
+            p_cost: self.kdf_parallelism,
+        }
+    }
+}
+
+fn default_kdf_memory_cost() -> u32 {
+    KdfParams::default().m_cost
+}
+
+fn default_kdf_time_cost() -> u32 {
+    KdfParams::default().t_cost
+}
+
+fn default_kdf_parallelism() -> u32 {
+    KdfParams::default().p_cost
 }
 
 /// Returns the default config
-fn default_config() -> String {
-    format!(
-        "base_dir = \"{}\"\nask_for_password = true",
-        Path::new(&get_home_path()).join(".envn").to_str().unwrap()
-    )
+fn default_config() -> Result<String> {
+    let kdf = KdfParams::default();
+    Ok(format!(
+        "base_dir = {:?}\nkdf_memory_cost = {}\nkdf_time_cost = {}\nkdf_parallelism = {}\nreveal_values = false",
+        get_home_path()?.join(".envn").to_string_lossy(),
+        kdf.m_cost,
+        kdf.t_cost,
+        kdf.p_cost
+    ))
 }
 
 /// Convert config from string to Config struct
-fn convert_config(config: String) -> Config {
-    let config: Config =
-        toml::from_str(config.as_str()).unwrap_or(toml::from_str(&default_config()).unwrap());
-    config
+/// Falls back to the default config if it can not be parsed
+fn convert_config(config: String) -> Result<Config> {
+    match toml::from_str(config.as_str()) {
+        Ok(config) => Ok(config),
+        Err(_) => toml::from_str(&default_config()?).map_err(|e| EnvnError::Parse(e.to_string())),
+    }
 }
 
 /// Check if a file exists
@@ -67,10 +118,10 @@ pub fn file_exists(path: &Path) -> bool {
 }
 
 /// Write a file to the specified path
-pub fn write_file(path: &Path, content: String) -> bool {
+pub fn write_file(path: &Path, content: String) -> Result<()> {
     //TODO: Add warning if file exists
-    let _ = std::fs::write(path, content);
-    true
+    std::fs::write(path, content)?;
+    Ok(())
 }
 
 /// Retrieves the configuration file.
@@ -78,88 +129,244 @@ pub fn write_file(path: &Path, content: String) -> bool {
 /// # Returns
 ///
 /// The `Config` struct representing the configuration file.
-pub fn get_config_file() -> Config {
+pub fn get_config_file() -> Result<Config> {
     let config_path = dirs::config_local_dir()
-        .expect("Unable to get local config path")
+        .ok_or(EnvnError::NotFound(
+            "The local config directory".to_string(),
+        ))?
         .join("envn");
     if !&config_path.exists() {
-        std::fs::create_dir_all(&config_path).expect("Failed to create config directory");
+        std::fs::create_dir_all(&config_path)?;
     }
 
     let config_file = config_path.join("config.toml");
     if !&config_file.exists() {
-        let _ = std::fs::write(&config_file, default_config());
+        std::fs::write(&config_file, default_config()?)?;
     }
 
-    let config = std::fs::read_to_string(config_file).expect("Failed to read config file");
+    let config = std::fs::read_to_string(config_file)?;
     convert_config(config)
 }
 
 /// Join any path to the config path
-pub fn join_app_path(joiner: &str) -> PathBuf {
-    let path = get_app_dir_path();
-    path.join(joiner).clone()
+pub fn join_app_path(joiner: &str) -> Result<PathBuf> {
+    let path = get_app_dir_path()?;
+    Ok(path.join(joiner))
 }
 
-/// Sets the password for the database
+/// Sets up the auth and keyring files of a store, without any prompt.
 /// This password is used for the encryption and decryption of the database
 /// Also, a hashed version of the password is stored in the auth file
 /// The auth algorithm is bcrypt
 /// Which is a hashing algorithm that is used to hash passwords and used to
 /// verify the password when the user tries to access the database
+/// The encryption key is then wrapped with a key derived from the same password
+///
+/// # Arguments
+///
+/// * `dir` - The directory of the store, created if it does not exist.
+/// * `password` - The master password.
+/// * `kdf` - The key derivation parameters to wrap the key with.
+///
 /// # Returns
 ///
-/// * `true` - If the password was set successfully
-/// * `false` - If the password was not set successfully
+/// The keyring of the store.
+pub fn init_store(dir: &Path, password: &str, kdf: KdfParams) -> Result<Keyring> {
+    std::fs::create_dir_all(dir)?;
+
+    // keep the secrets of an old plaintext key file, if there is one
+    let key = match read_legacy_key(dir)? {
+        Some(key) => key,
+        None => crate::encryption::get_key(),
+    };
+    let keyring = Keyring::new(password, kdf, key)?;
+    write_keyring(dir, &keyring)?;
+
+    write_file(&dir.join("auth"), hash_password(password)?)?;
+    Ok(keyring)
+}
+
+/// Checks the password against the auth file of a store.
+///
+/// # Returns
 ///
-/// # Panics
-/// * If the password could not be hashed
-/// * If the password could not be written to the file
-pub fn set_password() -> bool {
-    let password = inquire::Password::new("Enter your password 👀")
-        .with_display_mode(inquire::PasswordDisplayMode::Masked)
-        .prompt()
-        .expect("Failed to get password");
-    let key_file = join_app_path("auth");
+/// An `Auth` error if the password is wrong, a `NotFound` error if the store has no password.
+pub fn verify_password(dir: &Path, password: &str) -> Result<()> {
+    let auth_file = dir.join("auth");
+    if !file_exists(&auth_file) {
+        return Err(EnvnError::NotFound("The password file".to_string()));
+    }
+
+    let hashed = std::fs::read_to_string(auth_file)?;
+
+    match bcrypt::verify(password, hashed.trim()) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(EnvnError::Auth("Wrong password".to_string())),
+        Err(e) => Err(EnvnError::Auth(format!("Failed to verify password: {}", e))),
+    }
+}
+
+/// Hashes the password with bcrypt, for the auth file.
+fn hash_password(password: &str) -> Result<String> {
+    bcrypt::hash(password, bcrypt::DEFAULT_COST)
+        .map_err(|e| EnvnError::Crypto(format!("Failed to hash password: {}", e)))
+}
+
+/// Reads the plaintext key file that older versions of envn stored, if it exists.
+fn read_legacy_key(dir: &Path) -> Result<Option<Key<Aes256Gcm>>> {
+    let key = match std::fs::read(dir.join("key")) {
+        Ok(key) => key,
+        Err(_) => return Ok(None),
+    };
+    if key.len() != 32 {
+        return Err(EnvnError::Parse("The key file is malformed".to_string()));
+    }
+    Ok(Some(Key::<Aes256Gcm>::clone_from_slice(&key)))
+}
 
-    // hash the password
-    let hashed = bcrypt::hash(password, bcrypt::DEFAULT_COST).expect("Failed to hash password");
+/// Serializes a sealed keyring into the bytes of the keyring file.
+fn keyring_bytes(keyring: &Keyring) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(&keyring.seal()?)?)
+}
 
-    bunt::println!("{$green}Password Set{/$}");
-    bunt::println!("Restart the program to use the password");
+/// Seals the keyring and writes it to the keyring file.
+/// The file is written next to the old one and renamed into place,
+/// so the keyring on disk is always complete.
+pub fn write_keyring(dir: &Path, keyring: &Keyring) -> Result<()> {
+    let keyring_tmp = dir.join("keyring.tmp");
+    write_synced(&keyring_tmp, &keyring_bytes(keyring)?)?;
+    std::fs::rename(keyring_tmp, dir.join("keyring"))?;
+    Ok(())
+}
 
-    write_file(&key_file, hashed)
+/// Writes a file and flushes it to disk, so that it can safely be renamed into place.
+fn write_synced(path: &Path, content: &[u8]) -> Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    Ok(())
 }
 
-/// This function returns a tuple containing two vectors: the keys and the nonce.
-/// The keys vector contains a sequence of bytes representing the encryption keys,
-/// while the nonce vector contains a sequence of bytes representing the unique
-/// number used once in encryption algorithms.
+/// Changes the master password, keeping the data keys and with them all the secrets.
+///
+/// The new `auth` and `keyring` files are first written next to the old ones as
+/// `auth.new` and `keyring.new`, then renamed into place: the keyring first, then the auth file.
+/// `recover_password_change` finishes or rolls back a change that was interrupted midway,
+/// so a crash never leaves a keyring that no password can unlock.
+///
+/// # Arguments
+///
+/// * `dir` - The directory of the store.
+/// * `password` - The new master password.
+/// * `kdf` - The key derivation parameters to wrap the keys with.
+/// * `keyring` - The unlocked keyring.
 ///
 /// # Returns
 ///
-/// A tuple `(keys, nonce)` where `keys` is a vector of bytes representing the encryption keys,
-/// and `nonce` is a vector of bytes representing the nonce.
-pub fn get_keys_and_nonce() -> (Vec<u8>, Vec<u8>) {
-    let key_path = join_app_path("key");
-    let nonce_path = join_app_path("nonce");
+/// The keyring, wrapped with the new password.
+pub fn change_password(
+    dir: &Path,
+    password: &str,
+    kdf: KdfParams,
+    keyring: &Keyring,
+) -> Result<Keyring> {
+    let auth_new = dir.join("auth.new");
+    let keyring_new = dir.join("keyring.new");
+
+    let hashed = hash_password(password)?;
+    let mut keyring = keyring.clone();
+    keyring.rewrap(password, kdf)?;
+
+    // the keyring goes first, so that `auth.new` is only ever alone once the keyring was renamed
+    write_synced(&keyring_new, &keyring_bytes(&keyring)?)?;
+    write_synced(&auth_new, hashed.as_bytes())?;
+
+    std::fs::rename(keyring_new, dir.join("keyring"))?;
+    std::fs::rename(auth_new, dir.join("auth"))?;
+    Ok(keyring)
+}
 
-    if !file_exists(&key_path) || !file_exists(&nonce_path) {
-        let key = crate::encryption::get_key();
-        let nonce = crate::encryption::get_nonce();
+/// Finishes or rolls back a password change that was interrupted by a crash.
+///
+/// * If `keyring.new` is still around, nothing was renamed yet and the old password
+///   is still valid, so the new files are removed.
+/// * If only `auth.new` is left, the new keyring is already in place, so the
+///   auth file is renamed as well.
+pub fn recover_password_change(dir: &Path) -> Result<()> {
+    let auth_new = dir.join("auth.new");
+    let keyring_new = dir.join("keyring.new");
+
+    if file_exists(&keyring_new) {
+        let _ = std::fs::remove_file(keyring_new);
+        let _ = std::fs::remove_file(auth_new);
+    } else if file_exists(&auth_new) {
+        std::fs::rename(auth_new, dir.join("auth"))?;
+    }
+    Ok(())
+}
 
-        //write the bytes to the file
-        let _ = std::fs::write(&key_path, key);
-        let _ = std::fs::write(&nonce_path, nonce);
+/// Unlocks the data keys using the (already verified) master password.
+///
+/// If a plaintext `key` file from an older version exists, it is wrapped
+/// into the keyring and removed. If there is no key at all, a new one is generated,
+/// unless the database already holds secrets, which no new key could decrypt.
+///
+/// # Arguments
+///
+/// * `dir` - The directory of the store.
+/// * `password` - The master password.
+/// * `kdf` - The key derivation parameters, used if the key has to be wrapped.
+///
+/// # Returns
+///
+/// The keyring, a `Crypto` error if the keyring is missing from a store with secrets,
+/// or an error if it could not be unwrapped with the password.
+pub fn unlock_key(dir: &Path, password: &str, kdf: KdfParams) -> Result<Keyring> {
+    let keyring_path = dir.join("keyring");
+
+    if let Some(key) = read_legacy_key(dir)? {
+        let keyring = Keyring::new(password, kdf, key)?;
+        write_keyring(dir, &keyring)?;
+        std::fs::remove_file(dir.join("key"))?;
+        bunt::eprintln!("{$yellow}Migrated the key file to the password protected keyring{/$}");
+        return Ok(keyring);
     }
 
-    let key = std::fs::read(key_path).expect("Failed to read key file");
-    let nonce = std::fs::read(nonce_path).expect("Failed to read nonce file");
+    if !file_exists(&keyring_path) {
+        if crate::db::has_encrypted_rows(&dir.join("env.db"))? {
+            return Err(EnvnError::Crypto(
+                "The keyring file is missing, but env.db holds secrets encrypted with it, restore it from a backup"
+                    .to_string(),
+            ));
+        }
+        let keyring = Keyring::new(password, kdf, crate::encryption::get_key())?;
+        write_keyring(dir, &keyring)?;
+        return Ok(keyring);
+    }
 
-    (key, nonce)
+    Keyring::unseal(&read_keyring(dir)?, password)
 }
 
-/// Loads a file and inserts its contents into the database.
+/// Unwraps the keyring of a store with the key the agent holds, without the password.
+///
+/// # Returns
+///
+/// The keyring, or an `Auth` error if the password changed since the agent was unlocked.
+pub fn unlock_key_with(dir: &Path, wrapping_key: &WrappingKey) -> Result<Keyring> {
+    Keyring::unseal_with(&read_keyring(dir)?, wrapping_key)
+}
+
+/// Reads the sealed keyring of a store.
+fn read_keyring(dir: &Path) -> Result<SealedKeyring> {
+    let keyring_path = dir.join("keyring");
+    if !file_exists(&keyring_path) {
+        return Err(EnvnError::NotFound("The keyring file".to_string()));
+    }
+    let bytes = std::fs::read(keyring_path)?;
+    Ok(bincode::deserialize(&bytes)?)
+}
+
+/// Reads the `KEY=VALUE` pairs of a .env file.
 ///
 /// # Arguments
 ///
@@ -167,55 +374,45 @@ pub fn get_keys_and_nonce() -> (Vec<u8>, Vec<u8>) {
 ///
 /// # Returns
 ///
-/// A vector of `Env` structs representing the contents of the file.
-pub fn load_file_to_insert_in_db(path: &Path) -> Vec<Env> {
-    let mut envs: Vec<Env> = Vec::new();
-    let file = std::fs::File::open(path).expect("Failed to open file");
-    let reader = std::io::BufReader::new(file);
-
-    for line in reader.lines() {
-        let line = line.expect("Failed to read line");
-        let split: Vec<&str> = line.split('=').collect();
-        let key = split[0];
-        let value = split[1];
-        let env = construct_struct(key.to_lowercase(), key.to_string(), value.to_string());
-        envs.push(env);
-    }
-    envs
+/// The keys and values in the file, in order, or a `Parse` error naming the file and line.
+pub fn read_env_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let content = std::fs::read_to_string(path)?;
+    crate::dotenv::parse(&content).map_err(|e| match e {
+        EnvnError::Parse(message) => EnvnError::Parse(format!("{}, {}", path.display(), message)),
+        e => e,
+    })
 }
 
-/// Compresses the database and key + nonce files into a tar
-pub fn compress(name: &str) -> Result<(), std::io::Error> {
-    if !join_app_path("backups").exists() {
-        std::fs::create_dir_all(join_app_path("backups")).unwrap();
+/// Compresses the database, keyring and auth files of a store into a tar in its `backups` directory
+/// The auth file goes along, so that a restored keyring is checked against the password it was wrapped with
+pub fn compress(dir: &Path, name: &str) -> Result<()> {
+    let backups = dir.join("backups");
+    if !backups.exists() {
+        std::fs::create_dir_all(&backups)?;
     }
 
-    let archive_file = File::create(join_app_path("backups").join(name)).unwrap();
+    let archive_file = File::create(backups.join(name))?;
     let mut archive = Builder::new(archive_file);
 
-    let mut db_file = File::open(join_app_path("env.db")).unwrap();
-    let mut key_file = File::open(join_app_path("key")).unwrap();
-    let mut nonce_file = File::open(join_app_path("nonce")).unwrap();
+    let mut db_file = File::open(dir.join("env.db"))?;
+    let mut keyring_file = File::open(dir.join("keyring"))?;
+    let mut auth_file = File::open(dir.join("auth"))?;
 
-    archive
-        .append_file("env.db", &mut db_file)
-        .expect("Failed to append db file");
-    archive
-        .append_file("key", &mut key_file)
-        .expect("Failed to append key file");
-    archive
-        .append_file("nonce", &mut nonce_file)
-        .expect("Failed to append nonce file");
+    archive.append_file("env.db", &mut db_file)?;
+    archive.append_file("keyring", &mut keyring_file)?;
+    archive.append_file("auth", &mut auth_file)?;
 
-    archive.finish()
+    archive.finish()?;
+    Ok(())
 }
 
-/// Decompresses the database and key + nonce files from a tar
-pub fn decompress(name: &str) -> Result<(), std::io::Error> {
-    let archive_file = File::open(join_app_path("backups").join(name)).unwrap();
+/// Decompresses the database, keyring and auth files of a store from a tar in its `backups` directory
+/// Backups of older versions hold no auth file, the current one is kept for them
+pub fn decompress(dir: &Path, name: &str) -> Result<()> {
+    let archive_file = File::open(dir.join("backups").join(name))?;
     let mut archive = tar::Archive::new(archive_file);
 
-    archive.unpack(get_app_dir_path()).unwrap();
+    archive.unpack(dir)?;
 
     Ok(())
 }
@@ -0,0 +1,237 @@
+/// This file holds the X25519 identities used to hand secrets to other people
+/// An identity is a key pair, its public half is the recipient, like `envn:3q2-7w...`,
+/// which can be passed around freely
+/// Anything sealed to a recipient can only be opened with the matching identity,
+/// and the one opening it learns which identity sealed it
+use std::{fmt, str::FromStr};
+
+use aes_gcm::{aead::OsRng, Aes256Gcm, Key};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use crate::{
+    db::IdentityEntry,
+    encryption::{decrypt, encrypt, get_nonce, Keyring},
+    error::{EnvnError, Result},
+};
+
+/// What every recipient starts with
+pub const RECIPIENT_PREFIX: &str = "envn:";
+
+/// The private half of a key pair, kept encrypted in the store
+#[derive(Clone)]
+pub struct Identity {
+    secret: StaticSecret,
+}
+
+/// The public half of a key pair, which things are sealed to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Recipient(PublicKey);
+
+/// A message sealed to a recipient
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sealed {
+    /// The recipient of the identity that sealed it
+    sender: [u8; 32],
+    /// The public half of the throwaway key pair of this message
+    ephemeral: [u8; 32],
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+impl Identity {
+    /// Generates a new identity.
+    pub fn generate() -> Self {
+        Identity {
+            secret: StaticSecret::random_from_rng(OsRng),
+        }
+    }
+
+    /// The recipient of the identity, for others to seal things to.
+    pub fn recipient(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.secret))
+    }
+
+    /// Seals a message, so that only `recipient` can open it.
+    ///
+    /// # Arguments
+    ///
+    /// * `recipient` - Who can open it.
+    /// * `context` - What the message is for, it can only be opened for the same.
+    /// * `plaintext` - The message.
+    pub fn seal(&self, recipient: &Recipient, context: &str, plaintext: &[u8]) -> Result<Sealed> {
+        let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral_public = PublicKey::from(&ephemeral);
+        let sender = self.recipient();
+
+        let key = derive_key(
+            ephemeral.diffie_hellman(&recipient.0).as_bytes(),
+            self.secret.diffie_hellman(&recipient.0).as_bytes(),
+            [&ephemeral_public, &sender.0, &recipient.0],
+            context,
+        )?;
+        let nonce = get_nonce();
+        Ok(Sealed {
+            sender: sender.0.to_bytes(),
+            ephemeral: ephemeral_public.to_bytes(),
+            nonce: nonce.to_vec(),
+            ciphertext: encrypt(key, nonce, plaintext, context.as_bytes())?,
+        })
+    }
+
+    /// Opens a message sealed to this identity.
+    ///
+    /// # Returns
+    ///
+    /// The message, or a `Crypto` error if it was sealed to someone else,
+    /// for another context, or tampered with.
+    pub fn open(&self, sealed: &Sealed, context: &str) -> Result<Vec<u8>> {
+        let ephemeral = PublicKey::from(sealed.ephemeral);
+        let sender = PublicKey::from(sealed.sender);
+        let first = self.secret.diffie_hellman(&ephemeral);
+        let second = self.secret.diffie_hellman(&sender);
+        if !first.was_contributory() || !second.was_contributory() {
+            return Err(EnvnError::Crypto(
+                "The sealed message is malformed".to_string(),
+            ));
+        }
+
+        let key = derive_key(
+            first.as_bytes(),
+            second.as_bytes(),
+            [&ephemeral, &sender, &self.recipient().0],
+            context,
+        )?;
+        decrypt(key, &sealed.nonce, &sealed.ciphertext, context.as_bytes()).map_err(|_| {
+            EnvnError::Crypto(
+                "It was not sealed to your identity, or it was tampered with".to_string(),
+            )
+        })
+    }
+}
+
+impl Sealed {
+    /// The recipient of the identity that sealed the message.
+    /// It can only be trusted once the message was opened.
+    pub fn sender(&self) -> Recipient {
+        Recipient(PublicKey::from(self.sender))
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}",
+            RECIPIENT_PREFIX,
+            URL_SAFE_NO_PAD.encode(self.0.as_bytes())
+        )
+    }
+}
+
+impl FromStr for Recipient {
+    type Err = EnvnError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            EnvnError::Parse(format!(
+                "{} is not a recipient, they look like {}...",
+                s, RECIPIENT_PREFIX
+            ))
+        };
+        let encoded = s
+            .trim()
+            .strip_prefix(RECIPIENT_PREFIX)
+            .ok_or_else(invalid)?;
+        let bytes: [u8; 32] = URL_SAFE_NO_PAD
+            .decode(encoded)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(invalid)?;
+        Ok(Recipient(PublicKey::from(bytes)))
+    }
+}
+
+/// Derives the key of a sealed message from both Diffie-Hellman results,
+/// bound to every public key involved and to the context.
+fn derive_key(
+    ephemeral: &[u8; 32],
+    sender: &[u8; 32],
+    public_keys: [&PublicKey; 3],
+    context: &str,
+) -> Result<Key<Aes256Gcm>> {
+    let salt: Vec<u8> = public_keys
+        .iter()
+        .flat_map(|key| key.as_bytes().to_vec())
+        .collect();
+    let secret = [ephemeral.as_slice(), sender.as_slice()].concat();
+
+    let mut key = Key::<Aes256Gcm>::default();
+    Hkdf::<Sha256>::new(Some(&salt), &secret)
+        .expand(context.as_bytes(), &mut key)
+        .map_err(|e| EnvnError::Crypto(format!("Failed to derive key: {}", e)))?;
+    Ok(key)
+}
+
+/// Encrypts an identity with the current key of the keyring, to be stored.
+pub fn encrypt_identity(identity: &Identity, keyring: &Keyring) -> Result<IdentityEntry> {
+    let recipient = identity.recipient().to_string();
+    let (key_version, key) = keyring.current();
+    let nonce = get_nonce();
+    Ok(IdentityEntry {
+        secret: encrypt(key, nonce, identity.secret.as_bytes(), recipient.as_bytes())?,
+        nonce: nonce.to_vec(),
+        key_version,
+        recipient,
+    })
+}
+
+/// Decrypts a stored identity.
+///
+/// # Returns
+///
+/// The identity, or a `Crypto` error if it was tampered with.
+pub fn decrypt_identity(entry: &IdentityEntry, keyring: &Keyring) -> Result<Identity> {
+    let key = keyring
+        .get(entry.key_version)
+        .ok_or(EnvnError::Crypto(format!(
+            "The key version {} of your identity is not in the keyring",
+            entry.key_version
+        )))?;
+    let secret: [u8; 32] = decrypt(key, &entry.nonce, &entry.secret, entry.recipient.as_bytes())?
+        .try_into()
+        .map_err(|_| EnvnError::Crypto("Your identity is malformed".to_string()))?;
+    Ok(Identity {
+        secret: StaticSecret::from(secret),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Identity, Recipient};
+
+    #[test]
+    fn test_seal_and_open() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let eve = Identity::generate();
+
+        let recipient: Recipient = bob.recipient().to_string().parse().unwrap();
+        assert_eq!(recipient, bob.recipient());
+        assert!("envn:nope".parse::<Recipient>().is_err());
+
+        let sealed = alice.seal(&recipient, "test", b"hunter2").unwrap();
+        assert_eq!(sealed.sender(), alice.recipient());
+        assert_eq!(bob.open(&sealed, "test").unwrap(), b"hunter2");
+        assert!(bob.open(&sealed, "other").is_err());
+        assert!(eve.open(&sealed, "test").is_err());
+
+        // claiming to be someone else breaks the key
+        let mut forged = sealed.clone();
+        forged.sender = eve.recipient().0.to_bytes();
+        assert!(bob.open(&forged, "test").is_err());
+    }
+}
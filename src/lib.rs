@@ -0,0 +1,23 @@
+//! envn keeps secrets and `.env` values encrypted in a local store.
+//!
+//! The `Vault` is the way into a store for other programs,
+//! the `envn` CLI is built on top of it.
+
+pub mod agent;
+pub mod audit;
+pub mod db;
+pub mod dotenv;
+pub mod encryption;
+pub mod error;
+pub mod file;
+pub mod identity;
+pub mod manifest;
+pub mod password;
+pub mod search;
+pub mod share;
+pub mod utils;
+pub mod vault;
+pub mod vaultfile;
+
+pub use error::{EnvnError, Result};
+pub use vault::Vault;
@@ -1,32 +1,610 @@
-use clap::Parser;
+use clap::{error::ContextKind, error::ErrorKind, CommandFactory, Parser, Subcommand};
 use correct_word::{correct_word, Algorithm::Levenshtein};
-use file::join_app_path;
+use std::path::{Path, PathBuf};
 
-#[derive(Parser, Debug)]
-#[command(name="evnv", author="Ishan Joshi", version, about="Quickly write env's efficiently", long_about = None)]
+use envn::{
+    agent, error, file,
+    manifest::Manifest,
+    utils::{parse_expiry, MetadataUpdate},
+    Vault,
+};
+use output::{Format, SortBy};
 
+#[derive(Parser, Debug)]
+#[command(name="envn", author="Ishan Joshi", version, about="Quickly write env's efficiently", long_about = None)]
 /// The Args struct is used to parse the command line arguments
 /// In order to make the command line arguments more user friendly
 /// the user has the option to not pass in the command name
 /// If the user does not pass in the command name, then the program
 /// will prompt the user to enter the command name
 struct Args {
-    #[arg(required = false)]
-    cmd: Option<String>,
+    #[command(subcommand)]
+    cmd: Option<Command>,
+
+    /// Fail instead of prompting for anything that was not passed as an argument
+    #[arg(long, global = true)]
+    non_interactive: bool,
+
+    /// The project to work on, inferred from the current directory if left out
+    #[arg(long, global = true)]
+    project: Option<String>,
+
+    /// The environment of the project to work on, like dev or prod, base if left out
+    #[arg(long = "env", global = true)]
+    environments: Vec<String>,
+
+    /// Read the password from this open file descriptor
+    #[arg(long, global = true, conflicts_with = "password_file")]
+    password_fd: Option<i32>,
+
+    /// Read the password from this file, which only you may be able to read
+    #[arg(long, global = true)]
+    password_file: Option<PathBuf>,
+}
+
+/// Where the password comes from, unless it is prompted for
+struct PasswordSource<'a> {
+    fd: Option<i32>,
+    file: Option<&'a Path>,
+    command: Option<&'a str>,
+}
+
+/// The commands of the CLI
+/// Every argument is optional, anything left out is prompted for
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Get a secret
+    #[command(alias = "show")]
+    Get {
+        /// The name of the secret
+        name: Option<String>,
+        /// Print the secret in a machine-readable format
+        #[arg(short, long, value_enum)]
+        format: Option<Format>,
+        /// Print only the value, exactly as it is stored
+        #[arg(long, conflicts_with = "format")]
+        value_only: bool,
+        /// Show the value instead of masking it
+        #[arg(long)]
+        reveal: bool,
+    },
+    /// Set a secret
+    Add {
+        /// The identifier for this secret
+        name: Option<String>,
+        /// The environment variable the secret is saved as
+        #[arg(short, long)]
+        key: Option<String>,
+        /// The value of the secret
+        #[arg(short, long, conflicts_with = "value_stdin")]
+        value: Option<String>,
+        /// Read the value from stdin, so that it does not end up in the shell history
+        #[arg(long)]
+        value_stdin: bool,
+        #[command(flatten)]
+        metadata: MetadataArgs,
+    },
+    /// Save the secrets to a file
+    Save {
+        /// The names of the secrets to save, separated by commas
+        #[arg(long, value_delimiter = ',')]
+        names: Option<Vec<String>>,
+        /// The file to write, it is overwritten if it exists
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Append a secret to the .env file
+    Append {
+        /// The name of the secret
+        name: Option<String>,
+    },
+    /// Show all secrets
+    All {
+        /// Only show this many secrets
+        range: Option<String>,
+        /// Print the secrets in a machine-readable format
+        #[arg(short, long, value_enum)]
+        format: Option<Format>,
+        #[command(flatten)]
+        filter: FilterArgs,
+        #[command(flatten)]
+        list: ListArgs,
+        /// Show the values instead of masking them
+        #[arg(long)]
+        reveal: bool,
+    },
+    /// Find secrets by their name or key
+    Search {
+        /// A glob like aws_* or *_TOKEN, text without * or ? matches anywhere
+        pattern: Option<String>,
+        /// Treat the pattern as a regex instead of a glob
+        #[arg(long)]
+        regex: bool,
+        /// Search the values as well, which decrypts every secret
+        #[arg(long)]
+        in_values: bool,
+        /// Print the secrets in a machine-readable format
+        #[arg(short, long, value_enum)]
+        format: Option<Format>,
+        /// Show the values instead of masking them
+        #[arg(long)]
+        reveal: bool,
+    },
+    /// Edit a secret
+    Edit {
+        /// The name of the secret
+        name: Option<String>,
+        /// The new environment variable, the old one is kept if only the value is given
+        #[arg(short, long)]
+        key: Option<String>,
+        /// The new value, the old one is kept if only the key is given
+        #[arg(short, long)]
+        value: Option<String>,
+        #[command(flatten)]
+        metadata: MetadataArgs,
+    },
+    /// Backup your secrets to a tar file
+    Backup {
+        /// The name of the backup
+        name: Option<String>,
+    },
+    /// Restore your secrets from a tar file
+    Restore {
+        /// The name of the backup
+        name: Option<String>,
+    },
+    /// Remove a secret
+    Delete {
+        /// The name of the secret
+        name: Option<String>,
+        /// Do not ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Load secrets from a file
+    Load {
+        /// The file to load
+        file: Option<String>,
+        /// What to do with secrets that already exist
+        #[arg(long, value_enum, default_value_t = OnConflict::Skip)]
+        on_conflict: OnConflict,
+    },
+    /// Manage the key pair secrets are shared with
+    Identity {
+        #[command(subcommand)]
+        cmd: IdentityCommand,
+    },
+    /// Pack secrets into a bundle only one person can open
+    Share {
+        /// Their recipient, as printed by envn identity show
+        #[arg(long)]
+        to: Option<String>,
+        /// The names of the secrets to share, separated by commas
+        #[arg(long, value_delimiter = ',')]
+        names: Option<Vec<String>>,
+        /// The file to write the bundle to, it is printed if left out
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Store the secrets of a bundle shared with you
+    Receive {
+        /// The bundle
+        file: Option<String>,
+        /// Only accept a bundle from this recipient
+        #[arg(long)]
+        from: Option<String>,
+        /// What to do with secrets that already exist
+        #[arg(long, value_enum, default_value_t = OnConflict::Ask)]
+        on_conflict: OnConflict,
+    },
+    /// Reset stuff
+    Reset {
+        /// What to reset: all, db or password
+        what: Option<String>,
+        /// Do not ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Check that no secret was tampered with
+    Verify,
+    /// Change the password
+    Passwd {
+        /// Read the new password from this open file descriptor, instead of asking for it
+        #[arg(long, conflicts_with = "new_password_file")]
+        new_password_fd: Option<i32>,
+        /// Read the new password from this file, which only you may be able to read
+        #[arg(long)]
+        new_password_file: Option<PathBuf>,
+    },
+    /// Keep the store unlocked in a background agent, so the password is asked for only once
+    Unlock {
+        /// Lock again once no command used the agent for this long, like 30m or 8h
+        #[arg(long, default_value = "15m")]
+        timeout: String,
+    },
+    /// Make the agent forget the key, the password is asked for again
+    Lock,
+    /// Run the agent in the foreground, `envn unlock` starts it in the background
+    Agent {
+        /// Exit once no command used the agent for this long, like 30m or 8h
+        #[arg(long, default_value = "15m")]
+        timeout: String,
+    },
+    /// Re-encrypt every secret under a fresh key, after backing them up
+    RotateKey,
+    /// Run a command with secrets set as environment variables
+    Run {
+        /// The names of the secrets to set, separated by commas, all of them if left out
+        #[arg(long, value_delimiter = ',', conflicts_with = "tag")]
+        names: Option<Vec<String>>,
+        /// Only set the secrets with this tag
+        #[arg(long)]
+        tag: Option<String>,
+        /// The command to run, after `--`
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Keep secrets in an encrypted .envn.vault file, which can be committed to the repository
+    Vault {
+        /// The vault file, the .envn.vault of this directory or its parents if left out
+        #[arg(long)]
+        file: Option<String>,
+        #[command(subcommand)]
+        cmd: VaultCommand,
+    },
+    /// Merge two versions of the vault file, git runs this once envn git install registered it
+    MergeDriver {
+        /// The version both sides started from
+        base: String,
+        /// Our version, which the result is written to
+        ours: String,
+        /// Their version
+        theirs: String,
+    },
+    /// Set up the git repository for the vault file
+    Git {
+        #[command(subcommand)]
+        cmd: GitCommand,
+    },
+    /// Manage the projects secrets are grouped in
+    Projects {
+        #[command(subcommand)]
+        cmd: ProjectsCommand,
+    },
+    /// Manage the environments of a project
+    #[command(alias = "envs")]
+    Environments {
+        #[command(subcommand)]
+        cmd: EnvironmentsCommand,
+    },
+    /// List the earlier values of a secret
+    History {
+        /// The name of the secret
+        name: Option<String>,
+        /// Show the values instead of hiding them
+        #[arg(long)]
+        reveal: bool,
+    },
+    /// Restore the value a secret had in an earlier version
+    Rollback {
+        /// The name of the secret
+        name: Option<String>,
+        /// The version to restore, as listed by `envn history`
+        #[arg(long)]
+        to: Option<u32>,
+    },
+    /// Show who read or changed which secret, and when
+    Audit {
+        #[command(subcommand)]
+        cmd: Option<AuditCommand>,
+        /// Only show what happened from this time on, like 2024-01-31, 12h or 7d
+        #[arg(long)]
+        since: Option<String>,
+        /// Only show what happened to this secret
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Write the .env file the .envn.toml manifest asks for
+    Sync {
+        /// The file to write, the output of the manifest or .env if left out
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Check that every secret the .envn.toml manifest requires has a value
+    Check,
+    /// Compare the secrets of two environments, given as `--env a --env b`
+    Diff {
+        /// Filled in from the two `--env` arguments
+        #[arg(skip)]
+        environments: Vec<String>,
+    },
+}
+
+/// What `load` and `receive` do with a secret that already exists with another value
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum OnConflict {
+    /// Keep the secret as it is
+    Skip,
+    /// Replace it, the old value is kept in its history
+    Overwrite,
+    /// Ask for every secret
+    Ask,
+}
+
+/// The subcommands of `envn identity`
+#[derive(Subcommand, Debug)]
+pub enum IdentityCommand {
+    /// Generate the key pair, bundles shared with an older one can not be received anymore
+    Create {
+        /// Replace the identity without asking
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Print the recipient others share secrets with
+    Show,
+}
+
+/// The subcommands of `envn audit`
+#[derive(Subcommand, Debug)]
+pub enum AuditCommand {
+    /// Check that no entry of the audit log was changed or removed
+    Verify,
+}
+
+/// The subcommands of `envn environments`
+#[derive(Subcommand, Debug)]
+pub enum EnvironmentsCommand {
+    /// List the environments of the project
+    List,
+    /// Create an environment
+    Create {
+        /// The name of the environment
+        name: String,
+        /// The environment to inherit secrets from, base if left out
+        #[arg(long)]
+        from: Option<String>,
+    },
+    /// Delete an environment and the secrets set in it
+    Delete {
+        /// The name of the environment
+        name: String,
+        /// Do not ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+}
+
+/// The metadata of a secret, for `add` and `edit`
+/// Passing an empty value clears the field
+#[derive(clap::Args, Debug)]
+pub struct MetadataArgs {
+    /// What the secret is for
+    #[arg(long)]
+    description: Option<String>,
+    /// Tags to find the secret by, separated by commas
+    #[arg(long, value_delimiter = ',')]
+    tags: Option<Vec<String>>,
+    /// Who is responsible for the secret
+    #[arg(long)]
+    owner: Option<String>,
+    /// Where the secret comes from
+    #[arg(long)]
+    url: Option<String>,
+    /// When the secret has to be rotated, a date like 2024-01-31 or a time from now like 90d
+    #[arg(long)]
+    expires: Option<String>,
+}
 
-    #[arg(required = false)]
-    name: Option<String>,
+impl MetadataArgs {
+    /// Turns the arguments into a change to the metadata.
+    /// A `Parse` error if `--expires` is not a time.
+    pub fn update(self) -> error::Result<MetadataUpdate> {
+        Ok(MetadataUpdate {
+            description: self.description,
+            tags: self.tags,
+            owner: self.owner,
+            url: self.url,
+            expires_at: self.expires.as_deref().map(parse_expiry).transpose()?,
+        })
+    }
+}
+
+/// Which secrets `envn all` shows
+#[derive(clap::Args, Debug)]
+pub struct FilterArgs {
+    /// Only secrets with this tag
+    #[arg(long)]
+    pub tag: Option<String>,
+    /// Only secrets that have expired
+    #[arg(long, conflicts_with = "expiring_within")]
+    pub expired: bool,
+    /// Only secrets that expire within this time, like 30d, expired ones included
+    #[arg(long)]
+    pub expiring_within: Option<String>,
+}
+
+/// The order and page of `envn all`
+#[derive(clap::Args, Debug)]
+pub struct ListArgs {
+    /// Sort the secrets, by default they are in the order they were added
+    #[arg(long, value_enum)]
+    pub sort: Option<SortBy>,
+    /// Reverse the order
+    #[arg(long)]
+    pub reverse: bool,
+    /// Show this page, with as many secrets per page as the range, or 20
+    #[arg(long)]
+    pub page: Option<usize>,
+}
+
+/// The subcommands of `envn vault`
+#[derive(Subcommand, Debug)]
+pub enum VaultCommand {
+    /// Create the vault file in this directory, readable by your identity
+    Init {
+        /// The names of the secrets to put in it, separated by commas
+        #[arg(long, value_delimiter = ',')]
+        names: Option<Vec<String>>,
+    },
+    /// List the recipients and secrets of the vault file, without the values
+    List,
+    /// Copy secrets into the vault file
+    Push {
+        /// The names of the secrets, separated by commas
+        #[arg(long, value_delimiter = ',')]
+        names: Option<Vec<String>>,
+    },
+    /// Store the secrets of the vault file
+    Pull {
+        /// The names of the secrets, separated by commas, all of them if left out
+        #[arg(long, value_delimiter = ',')]
+        names: Option<Vec<String>>,
+        /// What to do with secrets that already exist
+        #[arg(long, value_enum, default_value_t = OnConflict::Ask)]
+        on_conflict: OnConflict,
+    },
+    /// Let someone read the vault file
+    AddRecipient {
+        /// Their recipient, as printed by envn identity show
+        recipient: String,
+    },
+    /// Stop someone from reading the vault file, from its next version on
+    RemoveRecipient {
+        /// Their recipient
+        recipient: String,
+    },
+}
+
+/// The subcommands of `envn git`
+#[derive(Subcommand, Debug)]
+pub enum GitCommand {
+    /// Let git merge the vault file with envn merge-driver, in .git/config and .gitattributes
+    Install,
+}
+
+/// The subcommands of `envn projects`
+#[derive(Subcommand, Debug)]
+pub enum ProjectsCommand {
+    /// List the projects
+    List,
+    /// Create a project
+    Create {
+        /// The name of the project
+        name: String,
+        /// Link the project to a directory, it is used whenever envn runs inside of it
+        #[arg(long)]
+        dir: Option<std::path::PathBuf>,
+    },
+    /// Rename a project, keeping its secrets
+    Rename {
+        /// The current name of the project
+        name: String,
+        /// The new name of the project
+        new_name: String,
+    },
+    /// Delete a project and all of its secrets
+    Delete {
+        /// The name of the project
+        name: String,
+        /// Do not ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+}
+
+impl Command {
+    /// Checks if the output of the command is meant for other programs,
+    /// in which case nothing else may be printed to stdout
+    fn is_piped(&self) -> bool {
+        match self {
+            Command::Get {
+                format, value_only, ..
+            } => format.is_some() || *value_only,
+            Command::All { format, .. } | Command::Search { format, .. } => format.is_some(),
+            // the output of `run` belongs to the child
+            Command::Run { .. } => true,
+            Command::Share { output, .. } => output.is_none(),
+            // git shows what the driver prints, the report goes to stderr
+            Command::MergeDriver { .. } => true,
+            Command::Identity {
+                cmd: IdentityCommand::Show,
+            } => true,
+            _ => false,
+        }
+    }
 }
 
 mod commands;
-mod db;
-mod encryption;
-mod file;
-mod utils;
+mod output;
+mod process;
+mod prompt;
 
 /// Gets the command line arguments
-fn get_args() -> Args {
-    Args::parse()
+///
+/// If the user enters an invalid command, we try to
+/// predict the correct command
+/// We do this by using the Levenshtein algorithm with a threshold of 1
+/// This threshold ensures that we only predict the correct command and not something
+/// that is completely different
+fn get_args() -> error::Result<Args> {
+    let mut argv: Vec<String> = std::env::args().collect();
+    let e = match Args::try_parse_from(&argv) {
+        Ok(args) => return Ok(args),
+        Err(e) => e,
+    };
+
+    let invalid = match (e.kind(), e.get(ContextKind::InvalidSubcommand)) {
+        (ErrorKind::InvalidSubcommand, Some(invalid)) => invalid.to_string(),
+        _ => e.exit(),
+    };
+    let args = Args::command();
+    let commands: Vec<&str> = args
+        .get_subcommands()
+        .flat_map(|cmd| std::iter::once(cmd.get_name()).chain(cmd.get_all_aliases()))
+        .collect();
+    let predicted = correct_word(Levenshtein, &invalid, commands, Some(1));
+    let word = match predicted.word {
+        Some(word) if !argv.iter().any(|arg| arg == "--non-interactive") => word,
+        _ => e.exit(),
+    };
+
+    let cmd = prompt::text_with_default(&format!("Did you mean {}?", word), &word)?;
+    bunt::println!("{$yellow}Using {} instead{/$}", cmd);
+    if let Some(arg) = argv.iter_mut().find(|arg| **arg == invalid) {
+        *arg = cmd;
+    }
+    Ok(Args::parse_from(argv))
+}
+
+/// The subcommands of `cmd` that can be run without passing any argument.
+/// A subcommand that needs a subcommand of its own counts if any of those can be run.
+fn pickable(cmd: &clap::Command) -> Vec<&str> {
+    cmd.get_subcommands()
+        .filter(|sub| !sub.is_hide_set())
+        .filter(|sub| !sub.get_arguments().any(|arg| arg.is_required_set()))
+        .filter(|sub| !sub.is_subcommand_required_set() || !pickable(sub).is_empty())
+        .map(|sub| sub.get_name())
+        .collect()
+}
+
+/// Lets the user pick a command, and its subcommand if it needs one,
+/// when no command was passed in.
+fn pick_command() -> error::Result<Command> {
+    let args = Args::command();
+    let name = prompt::select("Enter a command", pickable(&args))?;
+    let mut argv = vec!["envn", name];
+
+    if let Some(cmd) = args.find_subcommand(name) {
+        if cmd.is_subcommand_required_set() {
+            let message = format!("Enter a {} command", name);
+            argv.push(prompt::select(&message, pickable(cmd))?);
+        }
+    }
+
+    Args::try_parse_from(&argv)
+        .map_err(|e| error::EnvnError::Parse(e.to_string()))?
+        .cmd
+        .ok_or(error::EnvnError::NotFound(format!("Command {}", name)))
 }
 
 /// Directly print a cool splash screen
@@ -36,79 +614,216 @@ pub fn print_splash_screen() {
     bunt::println!("{$yellow}+-+-+-+-+-+-+{/$}");
 }
 
+/// Gets the master password from the first source that is set, in the order
+/// `--password-fd`, `--password-file`, `ENVN_PASSWORD` and `password_command`,
+/// or else by asking for it.
+/// The password is checked when the vault is opened with it.
+///
+/// # Arguments
+///
+/// * `source` - The sources set on the command line and in the config.
+/// * `new` - Whether a new password is asked for, which is asked twice.
+fn get_password(source: &PasswordSource, new: bool) -> error::Result<String> {
+    if let Some(fd) = source.fd {
+        return envn::password::from_fd(fd);
+    }
+    if let Some(file) = source.file {
+        return envn::password::from_file(file);
+    }
+    if let Ok(password) = std::env::var("ENVN_PASSWORD") {
+        return Ok(password);
+    }
+    if let Some(command) = source.command {
+        return envn::password::from_command(command);
+    }
+    match new {
+        true => prompt::new_password("Enter your password 👀"),
+        false => prompt::password("Enter your password 👀"),
+    }
+}
+
+/// Unlocks the store with the key the agent holds, or else with the password.
+/// A key that no longer opens the store, like one from before the password was changed
+/// or the store was reset, is passed over.
+///
+/// # Returns
+///
+/// The store, and whether the key of the agent unlocked it.
+fn open_vault(
+    dir: &Path,
+    config: &file::Config,
+    password_source: &PasswordSource,
+) -> error::Result<(Vault, bool)> {
+    if let Some(key) = agent::get_key(dir)? {
+        if let Ok(vault) = Vault::open_with_key(dir, &key, config.kdf_params()) {
+            return Ok((vault, true));
+        }
+    }
+    let password = get_password(password_source, false)?;
+    Ok((
+        Vault::open_with_kdf(dir, &password, config.kdf_params())?,
+        false,
+    ))
+}
+
 fn main() {
-    let args = get_args();
+    if let Err(e) = run() {
+        commands::exit_with_error(e);
+    }
+}
 
-    print_splash_screen();
+/// Unlocks the store and hands the command over to the handler
+fn run() -> error::Result<()> {
+    let args = get_args()?;
+    prompt::set_non_interactive(args.non_interactive);
 
-    let config = file::get_config_file();
+    let piped = args.cmd.as_ref().is_some_and(Command::is_piped);
+    if !piped {
+        print_splash_screen();
+    }
+
+    let dir = file::get_app_dir_path()?;
+    let config = file::get_config_file()?;
+
+    let password_source = PasswordSource {
+        fd: args.password_fd,
+        file: args.password_file.as_deref(),
+        command: config.password_command.as_deref(),
+    };
 
-    if args.cmd.clone().unwrap_or("".to_string()) == "help" {
-        utils::display_help(args.name);
-        std::process::exit(0);
+    // the agent and locking it need no password
+    match &args.cmd {
+        Some(Command::Agent { timeout }) => return commands::agent_command(timeout, &dir),
+        Some(Command::Lock) => return commands::lock_command(&dir),
+        Some(Command::Git { cmd }) => return commands::git_command(cmd),
+        _ => {}
     }
 
+    // finish a password change that was interrupted
+    // before the password file is looked at
+    file::recover_password_change(&dir)?;
+
     // for ux, we make sure that the password file exists
     // before we do anything else
 
-    if !join_app_path("auth").exists() {
+    if !dir.join("auth").exists() {
         bunt::println!("{$red}No password file found{/$}");
-        let password_confirm = inquire::Confirm::new("Do you want to create a password file?")
-            .with_default(true)
-            .prompt()
-            .unwrap();
+        let password_confirm = prompt::confirm("Do you want to create a password file?", true)?;
         if !password_confirm {
-            bunt::println!("{$red}You have to have security!{/$}");
-            std::process::exit(1);
+            return Err(error::EnvnError::Auth(
+                "You have to have security!".to_string(),
+            ));
         }
 
-        file::set_password();
-        std::process::exit(0);
+        let password = get_password(&password_source, true)?;
+        file::init_store(&dir, &password, config.kdf_params())?;
+        bunt::println!("{$green}Password Set{/$}");
+        bunt::println!("Restart the program to use the password");
+        return Ok(());
     }
 
+    let mut cmd = match args.cmd {
+        Some(cmd) => cmd,
+        None => pick_command()?,
+    };
+
     // Small piece of code that checks if the user
-    // has entered the correct password
-    if config.ask_for_password && !utils::check_password() {
-        bunt::println!("{$red}Error with Password:{/$}");
-        std::process::exit(1);
-    }
-
-    let mut cmd = args.cmd;
-
-    let accepted = vec![
-        "add", "show", "save", "append", "all", "load", "get", "edit", "delete", "backup",
-        "restore", "reset",
-    ];
-
-    if cmd.is_none() {
-        cmd = Some(
-            inquire::Select::new("Enter a command", accepted.clone())
-                .prompt()
-                .unwrap()
-                .to_string(),
-        );
-    }
-
-    // If the user enters an invalid command, we try to
-    // predict the correct command
-    // We do this by using the Levenshtein algorithm with a threshold of 1
-    // This threshold ensures that we only predict the correct command and not something
-    // that is completely different
-    if !accepted.contains(&cmd.clone().unwrap().as_str()) {
-        let predicted = correct_word(Levenshtein, &cmd.clone().unwrap(), accepted, Some(1));
-        if let Some(word) = predicted.word {
-            cmd = Some(
-                inquire::Text::new(&format!("Did you mean {}?", word))
-                    .with_default(&word)
-                    .prompt()
-                    .unwrap(),
-            );
-            bunt::println!("{$yellow}Using {} instead{/$}", cmd.clone().unwrap());
-        } else {
-            bunt::println!("{$red}Invalid command{/$}");
-            std::process::exit(1);
+    // has entered the correct password and unlocks the vault with it
+    let (mut vault, by_agent) = open_vault(&dir, &config, &password_source)?;
+
+    // the agent proves nothing about who is changing the password, so it is checked here
+    if by_agent && matches!(cmd, Command::Passwd { .. }) {
+        let password = get_password(&password_source, false)?;
+        file::verify_password(&dir, &password)?;
+    }
+
+    // only a single secret is shown unmasked by default, never all of them
+    if let Command::Get { reveal, .. } = &mut cmd {
+        *reveal |= config.reveal_values;
+    }
+
+    // the manifest of the repository picks the project and environment,
+    // unless they are passed as arguments
+    let cwd = std::env::current_dir()?;
+    let manifest = match Manifest::find(&cwd) {
+        Some(path) => Some(Manifest::read(&path)?),
+        None => None,
+    };
+    let (manifest_project, manifest_environment) = match manifest {
+        Some(manifest) => (manifest.project, manifest.environment),
+        None => (None, None),
+    };
+
+    let project = match args.project.or(manifest_project) {
+        Some(project) => Some(project),
+        None => vault.project_for_dir(&cwd)?,
+    };
+    if let Some(project) = project {
+        vault.select_project(&project)?;
+        if !piped && project != envn::db::DEFAULT_PROJECT {
+            bunt::println!("Using project {$yellow}{}{/$}", project);
+        }
+    }
+
+    let mut environments = args.environments;
+    if environments.is_empty() && !matches!(cmd, Command::Diff { .. }) {
+        environments.extend(manifest_environment);
+    }
+
+    // diff compares two environments, every other command works on one
+    match (&mut cmd, environments.as_slice()) {
+        (
+            Command::Diff {
+                environments: diffed,
+            },
+            [_, _],
+        ) => *diffed = environments,
+        (Command::Diff { .. }, _) => {
+            return Err(error::EnvnError::Parse(
+                "diff needs two environments, like --env dev --env prod".to_string(),
+            ))
+        }
+        (_, []) => {}
+        (_, [environment]) => {
+            vault.select_environment(environment)?;
+            if !piped {
+                bunt::println!("Using environment {$yellow}{}{/$}", environment);
+            }
+        }
+        (_, _) => {
+            return Err(error::EnvnError::Parse(
+                "--env can only be given once, except for diff".to_string(),
+            ))
+        }
+    }
+
+    commands::handle_command(cmd, vault);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pickable, Args};
+    use clap::{CommandFactory, Parser};
+
+    #[test]
+    fn test_picked_commands_parse() {
+        let args = Args::command();
+        let names = pickable(&args);
+        for name in ["get", "run", "identity", "audit", "git"] {
+            assert_eq!(names.contains(&name), name != "run", "{}", name);
+        }
+
+        for name in names {
+            let cmd = args.find_subcommand(name).unwrap();
+            let subs = match cmd.is_subcommand_required_set() {
+                true => pickable(cmd).into_iter().map(Some).collect(),
+                false => vec![None],
+            };
+            for sub in subs {
+                let argv = ["envn", name].into_iter().chain(sub);
+                assert!(Args::try_parse_from(argv).is_ok(), "{} {:?}", name, sub);
+            }
         }
     }
-    db::prepare_db();
-    commands::handle_command(&cmd.unwrap(), args.name);
 }
@@ -0,0 +1,251 @@
+/// This file reads the `.envn.toml` manifest of a repository
+/// The manifest lists the environment variables the repository needs,
+/// and which secrets supply them, so that `envn sync` can write its `.env`
+///
+/// ```toml
+/// project = "api"
+/// environment = "dev"
+///
+/// [secrets.DATABASE_URL]
+/// description = "The Postgres database"
+/// name = "db"
+/// default = "postgres://localhost/api"
+///
+/// [secrets.SENTRY_DSN]
+/// required = false
+/// ```
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    error::{EnvnError, Result},
+    Vault,
+};
+
+/// The name of the manifest file
+pub const MANIFEST_FILE: &str = ".envn.toml";
+
+/// The contents of a `.envn.toml` file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    /// The project the secrets are read from, unless `--project` is passed
+    pub project: Option<String>,
+    /// The environment the secrets are read from, unless `--env` is passed
+    pub environment: Option<String>,
+    /// The file `envn sync` writes, relative to the manifest
+    pub output: Option<String>,
+    /// The environment variables, by their key
+    #[serde(default)]
+    pub secrets: BTreeMap<String, Requirement>,
+}
+
+/// An environment variable the repository needs
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Requirement {
+    /// What the variable is for
+    pub description: Option<String>,
+    /// The secret that supplies the value, the key in lower case if left out
+    pub name: Option<String>,
+    /// The project of the secret, the one of the manifest if left out
+    pub project: Option<String>,
+    /// The environment of the secret, the base environment if only `project` is given
+    pub environment: Option<String>,
+    /// The value used if there is no secret
+    pub default: Option<String>,
+    /// Whether `envn check` fails if the variable has no value
+    #[serde(default = "default_required")]
+    pub required: bool,
+}
+
+fn default_required() -> bool {
+    true
+}
+
+/// Where the value of an environment variable comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// The secret with this name
+    Secret(String),
+    /// The default of the manifest
+    Default,
+    /// Nothing, the variable has no value
+    Missing,
+}
+
+/// An environment variable of the manifest, together with its value
+#[derive(Debug, Clone)]
+pub struct Resolved {
+    pub key: String,
+    pub value: Option<String>,
+    pub source: Source,
+    pub required: bool,
+    pub description: Option<String>,
+}
+
+impl Manifest {
+    /// Parses a manifest.
+    ///
+    /// # Returns
+    ///
+    /// The manifest, or a `Parse` error that says what is wrong with it.
+    pub fn parse(input: &str) -> Result<Self> {
+        toml::from_str(input).map_err(|e| EnvnError::Parse(e.to_string()))
+    }
+
+    /// Reads the manifest at `path`.
+    pub fn read(path: &Path) -> Result<Self> {
+        let input = std::fs::read_to_string(path)?;
+        toml::from_str(&input).map_err(|e| EnvnError::Parse(format!("{}, {}", path.display(), e)))
+    }
+
+    /// Finds the manifest of a directory, in the directory itself or one of its parents.
+    ///
+    /// # Returns
+    ///
+    /// The path of the manifest, or `None` if there is none.
+    pub fn find(dir: &Path) -> Option<PathBuf> {
+        dir.ancestors()
+            .map(|dir| dir.join(MANIFEST_FILE))
+            .find(|path| path.is_file())
+    }
+
+    /// Looks up the value of every environment variable of the manifest.
+    ///
+    /// Secrets are read from the project and environment selected in the vault,
+    /// unless the variable names others. The selection is left as it was.
+    ///
+    /// # Returns
+    ///
+    /// The variables, ordered by their key.
+    pub fn resolve(&self, vault: &mut Vault) -> Result<Vec<Resolved>> {
+        let selected = (vault.project().to_string(), vault.environment().to_string());
+
+        let mut resolved = Vec::new();
+        for (key, requirement) in &self.secrets {
+            let name = requirement
+                .name
+                .clone()
+                .unwrap_or_else(|| key.to_lowercase());
+            let secret = lookup(vault, requirement, &name, &selected);
+
+            // put the selection back before any error is passed on
+            vault.select_project(&selected.0)?;
+            vault.select_environment(&selected.1)?;
+
+            let (value, source) = match (secret?, &requirement.default) {
+                (Some(value), _) => (Some(value), Source::Secret(name)),
+                (None, Some(default)) => (Some(default.clone()), Source::Default),
+                (None, None) => (None, Source::Missing),
+            };
+            resolved.push(Resolved {
+                key: key.clone(),
+                value,
+                source,
+                required: requirement.required,
+                description: requirement.description.clone(),
+            });
+        }
+        Ok(resolved)
+    }
+}
+
+/// Reads the value of a secret, from the project and environment the requirement names.
+///
+/// # Returns
+///
+/// The value, or `None` if there is no such secret.
+fn lookup(
+    vault: &mut Vault,
+    requirement: &Requirement,
+    name: &str,
+    selected: &(String, String),
+) -> Result<Option<String>> {
+    if let Some(project) = &requirement.project {
+        vault.select_project(project)?;
+    }
+    match &requirement.environment {
+        Some(environment) => vault.select_environment(environment)?,
+        None if requirement.project.is_none() => vault.select_environment(&selected.1)?,
+        None => {}
+    }
+
+    match vault.get(name) {
+        Ok(env) => Ok(Some(env.value)),
+        Err(EnvnError::NotFound(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Manifest, Source};
+    use crate::{encryption::KdfParams, Vault};
+
+    #[test]
+    fn test_resolve() {
+        let manifest = Manifest::parse(
+            r#"
+            [secrets.DATABASE_URL]
+            description = "The database"
+            name = "db"
+
+            [secrets.LOG_LEVEL]
+            default = "info"
+
+            [secrets.API_TOKEN]
+
+            [secrets.SENTRY_DSN]
+            required = false
+
+            [secrets.SHARED_KEY]
+            project = "shared"
+            "#,
+        )
+        .unwrap();
+        assert!(Manifest::parse("[secrets.A]\nrequird = false\n").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let kdf = KdfParams {
+            m_cost: 1024,
+            t_cost: 1,
+            p_cost: 1,
+        };
+        let mut vault = Vault::create_with_kdf(dir.path(), "hunter2", kdf).unwrap();
+        vault.set("db", "DB", "postgres://localhost").unwrap();
+        vault.create_project("shared", None).unwrap();
+        vault.select_project("shared").unwrap();
+        vault.set("shared_key", "SHARED_KEY", "shared").unwrap();
+        vault.select_project("default").unwrap();
+
+        let resolved = manifest.resolve(&mut vault).unwrap();
+        let summary: Vec<_> = resolved
+            .iter()
+            .map(|r| (r.key.as_str(), r.value.as_deref(), r.source.clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("API_TOKEN", None, Source::Missing),
+                (
+                    "DATABASE_URL",
+                    Some("postgres://localhost"),
+                    Source::Secret("db".to_string())
+                ),
+                ("LOG_LEVEL", Some("info"), Source::Default),
+                ("SENTRY_DSN", None, Source::Missing),
+                (
+                    "SHARED_KEY",
+                    Some("shared"),
+                    Source::Secret("shared_key".to_string())
+                ),
+            ]
+        );
+        assert_eq!(vault.project(), "default");
+    }
+}
@@ -0,0 +1,172 @@
+/// This file renders secrets for other programs to read
+/// Unlike `display_env`, nothing here is coloured, but values are masked the same way
+use clap::ValueEnum;
+use envn::{
+    dotenv,
+    error::{EnvnError, Result},
+    utils::{mask, DisplayEnv},
+};
+
+/// The machine-readable output formats
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// A JSON object, or an array of them
+    Json,
+    /// A YAML mapping, or a list of them
+    Yaml,
+    /// KEY=value lines, as in a .env file
+    Env,
+    /// An aligned table with a header
+    Table,
+}
+
+/// What `envn all --sort` orders the secrets by
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum SortBy {
+    Name,
+    Key,
+    /// When the secret was first set
+    Created,
+    /// When the secret was last set
+    Updated,
+    /// When the secret expires, the ones that never do come last
+    Expires,
+}
+
+/// Sorts the secrets, the ones that are equal keep their order.
+pub fn sort(envs: &mut [DisplayEnv], by: SortBy) {
+    match by {
+        SortBy::Name => envs.sort_by(|a, b| a.name.cmp(&b.name)),
+        SortBy::Key => envs.sort_by(|a, b| a.key.cmp(&b.key)),
+        SortBy::Created => envs.sort_by(|a, b| a.metadata.created_at.cmp(&b.metadata.created_at)),
+        SortBy::Updated => envs.sort_by(|a, b| a.metadata.updated_at.cmp(&b.metadata.updated_at)),
+        SortBy::Expires => envs.sort_by_key(|env| {
+            let expires_at = env.metadata.expires_at.clone();
+            (expires_at.is_none(), expires_at)
+        }),
+    }
+}
+
+/// Renders a single secret, its value masked unless `reveal` is set.
+pub fn render_one(env: &DisplayEnv, format: Format, reveal: bool) -> Result<String> {
+    let env = masked(env, reveal);
+    match format {
+        Format::Json => to_json(&env),
+        Format::Yaml => to_yaml(&env),
+        Format::Env | Format::Table => render_all(std::slice::from_ref(&env), format, true),
+    }
+}
+
+/// Renders a list of secrets, their values masked unless `reveal` is set.
+pub fn render_all(envs: &[DisplayEnv], format: Format, reveal: bool) -> Result<String> {
+    let envs: Vec<DisplayEnv> = envs.iter().map(|env| masked(env, reveal)).collect();
+    match format {
+        Format::Json => to_json(&envs),
+        Format::Yaml => to_yaml(&envs),
+        Format::Env => envs
+            .iter()
+            .map(|env| dotenv::write_pair(&env.key, &env.value))
+            .collect(),
+        Format::Table => Ok(to_table(&envs)),
+    }
+}
+
+/// The secret, with its value masked unless `reveal` is set.
+fn masked(env: &DisplayEnv, reveal: bool) -> DisplayEnv {
+    let mut env = env.clone();
+    if !reveal {
+        env.value = mask(&env.value);
+    }
+    env
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> Result<String> {
+    let mut out =
+        serde_json::to_string_pretty(value).map_err(|e| EnvnError::Parse(e.to_string()))?;
+    out.push('\n');
+    Ok(out)
+}
+
+fn to_yaml<T: serde::Serialize>(value: &T) -> Result<String> {
+    serde_yaml::to_string(value).map_err(|e| EnvnError::Parse(e.to_string()))
+}
+
+/// Lays the secrets out in columns, each as wide as its longest cell
+fn to_table(envs: &[DisplayEnv]) -> String {
+    let header = ["NAME", "KEY", "VALUE"];
+    let rows: Vec<[&str; 3]> = envs
+        .iter()
+        .map(|env| [env.name.as_str(), env.key.as_str(), env.value.as_str()])
+        .collect();
+
+    let width = |column: usize| {
+        rows.iter()
+            .map(|row| row[column].chars().count())
+            .chain([header[column].len()])
+            .max()
+            .unwrap_or(0)
+    };
+    let (name_width, key_width) = (width(0), width(1));
+
+    std::iter::once(header)
+        .chain(rows.iter().copied())
+        .map(|[name, key, value]| format!("{:name_width$}  {:key_width$}  {}\n", name, key, value))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_all, render_one, sort, Format, SortBy};
+    use envn::utils::DisplayEnv;
+
+    fn envs() -> Vec<DisplayEnv> {
+        vec![
+            DisplayEnv {
+                name: "db".to_string(),
+                key: "DATABASE_URL".to_string(),
+                value: "postgres://localhost".to_string(),
+                ..Default::default()
+            },
+            DisplayEnv {
+                name: "token".to_string(),
+                key: "API_TOKEN".to_string(),
+                value: "abc".to_string(),
+                ..Default::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn test_rendering() {
+        let json = render_all(&envs(), Format::Json, true).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed[1]["key"], "API_TOKEN");
+
+        // values are masked unless they are revealed
+        assert_eq!(
+            render_all(&envs(), Format::Env, false).unwrap(),
+            "DATABASE_URL=\"po****st\"\nAPI_TOKEN=\"********\"\n"
+        );
+        assert!(!render_one(&envs()[1], Format::Yaml, false)
+            .unwrap()
+            .contains("abc"));
+
+        assert_eq!(
+            render_all(&envs(), Format::Table, true).unwrap(),
+            "NAME   KEY           VALUE\n\
+             db     DATABASE_URL  postgres://localhost\n\
+             token  API_TOKEN     abc\n"
+        );
+    }
+
+    #[test]
+    fn test_sorting() {
+        let mut sorted = envs();
+        sort(&mut sorted, SortBy::Key);
+        assert_eq!(sorted[0].name, "token");
+
+        sorted[1].metadata.expires_at = Some("2024-01-31 00:00:00".to_string());
+        sort(&mut sorted, SortBy::Expires);
+        assert_eq!(sorted[0].name, "db");
+    }
+}
@@ -0,0 +1,145 @@
+/// This file reads the master password from somewhere other than a prompt
+/// Scripts and CI can hand it over through a file descriptor, a file only the user
+/// can read, or a command like `pass show envn`, instead of exporting `ENVN_PASSWORD`
+use std::{io::Read, path::Path};
+
+use crate::error::{EnvnError, Result};
+
+/// Reads the password from an open file descriptor, like the `3` of `3<<<"$PASSWORD"`.
+///
+/// # Returns
+///
+/// The first line, a `Parse` error if the descriptor is not open,
+/// or an `Auth` error if there is no line.
+#[cfg(unix)]
+pub fn from_fd(fd: i32) -> Result<String> {
+    use std::os::unix::io::FromRawFd;
+
+    if fd < 0 {
+        return Err(EnvnError::Parse(format!("{} is not a file descriptor", fd)));
+    }
+    // a descriptor that is not open could be taken by any file the program opens later
+    // SAFETY: F_GETFD only reads the flags of the descriptor
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(EnvnError::Parse(format!(
+            "File descriptor {} is not open, pass it like the 3 of 3<<<\"$PASSWORD\"",
+            fd
+        )));
+    }
+    // SAFETY: the descriptor was handed to us to read the password from,
+    // the standard ones are left open for the rest of the program
+    let mut file = std::mem::ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(fd) });
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    if fd > 2 {
+        std::mem::ManuallyDrop::into_inner(file);
+    }
+    first_line(&content, &format!("File descriptor {}", fd))
+}
+
+#[cfg(not(unix))]
+pub fn from_fd(_fd: i32) -> Result<String> {
+    Err(EnvnError::Io(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "--password-fd needs unix file descriptors",
+    )))
+}
+
+/// Reads the password from a file, which other users must not be able to read.
+///
+/// # Returns
+///
+/// The first line, or an `Auth` error if the file is readable by others or empty.
+pub fn from_file(path: &Path) -> Result<String> {
+    if !path.is_file() {
+        return Err(EnvnError::NotFound(format!(
+            "The password file {}",
+            path.display()
+        )));
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = std::fs::metadata(path)?.permissions().mode();
+        if mode & 0o006 != 0 {
+            return Err(EnvnError::Auth(format!(
+                "{} can be read by other users, run chmod 600 on it",
+                path.display()
+            )));
+        }
+    }
+
+    let content = std::fs::read_to_string(path)?;
+    first_line(&content, &path.display().to_string())
+}
+
+/// Runs the `password_command` of the config and reads the password from what it prints.
+/// The command runs in a shell, and can prompt on the terminal itself.
+///
+/// # Returns
+///
+/// The first line it printed, or an `Auth` error if it failed or printed nothing.
+pub fn from_command(command: &str) -> Result<String> {
+    #[cfg(unix)]
+    let mut shell = std::process::Command::new("sh");
+    #[cfg(unix)]
+    shell.arg("-c");
+    #[cfg(not(unix))]
+    let mut shell = std::process::Command::new("cmd");
+    #[cfg(not(unix))]
+    shell.arg("/C");
+
+    let output = shell
+        .arg(command)
+        .stdin(std::process::Stdio::inherit())
+        .stderr(std::process::Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(EnvnError::Auth(format!(
+            "The password_command `{}` failed with {}",
+            command, output.status
+        )));
+    }
+    let content = String::from_utf8(output.stdout)
+        .map_err(|_| EnvnError::Parse("The password_command printed no text".to_string()))?;
+    first_line(&content, &format!("The output of `{}`", command))
+}
+
+/// The first line of `content`, without its line ending.
+/// Everything else on the line is kept, the password may start or end with spaces.
+fn first_line(content: &str, source: &str) -> Result<String> {
+    match content.lines().next() {
+        Some(line) if !line.is_empty() => Ok(line.to_string()),
+        _ => Err(EnvnError::Auth(format!("{} holds no password", source))),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{from_command, from_fd, from_file};
+    use crate::error::EnvnError;
+    use std::os::unix::{fs::PermissionsExt, io::IntoRawFd};
+
+    #[test]
+    fn test_password_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("password");
+        std::fs::write(&path, " hunter2 \nignored\n").unwrap();
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(from_file(&path).is_err());
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(from_file(&path).unwrap(), " hunter2 ");
+
+        let fd = std::fs::File::open(&path).unwrap().into_raw_fd();
+        assert_eq!(from_fd(fd).unwrap(), " hunter2 ");
+        // above the limit of open files, so never open
+        assert!(matches!(from_fd(1 << 30), Err(EnvnError::Parse(_))));
+
+        assert_eq!(from_command("printf 'hunter2\\n'").unwrap(), "hunter2");
+        assert!(from_command("exit 1").is_err());
+        assert!(from_command("true").is_err());
+    }
+}
@@ -0,0 +1,125 @@
+/// This file spawns the child process of `envn run`
+/// The secrets are only ever handed to the child as environment variables,
+/// they never touch the disk
+use std::process::{Command, ExitStatus};
+
+use envn::error::{EnvnError, Result};
+
+/// Runs a program with extra environment variables and waits for it to exit.
+///
+/// Signals sent to envn by another process (with `kill`) are forwarded to the child.
+/// Signals from the terminal, like Ctrl-C, already reach the child on their own,
+/// so they are not forwarded a second time.
+///
+/// # Arguments
+///
+/// * `command` - The program, followed by its arguments.
+/// * `vars` - The environment variables to set, as key and value.
+///
+/// # Returns
+///
+/// The exit code of the child, or `128 + signal` if it was killed by a signal, like shells do.
+pub fn run_with_env(command: &[String], vars: Vec<(String, String)>) -> Result<i32> {
+    let (program, args) = command
+        .split_first()
+        .ok_or(EnvnError::Parse("No command to run".to_string()))?;
+
+    let mut child = Command::new(program);
+    child.args(args).envs(vars);
+
+    let status = spawn_and_wait(&mut child).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => EnvnError::NotFound(format!("The program {}", program)),
+        _ => EnvnError::Io(e),
+    })?;
+
+    Ok(exit_code(status))
+}
+
+#[cfg(unix)]
+fn spawn_and_wait(command: &mut Command) -> std::io::Result<ExitStatus> {
+    use signal_hook::{
+        consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGUSR1, SIGUSR2},
+        iterator::{exfiltrator::WithOrigin, SignalsInfo},
+    };
+
+    // registered before the spawn, so that no signal is missed in between
+    let mut signals =
+        SignalsInfo::<WithOrigin>::new([SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGUSR1, SIGUSR2])?;
+    let handle = signals.handle();
+
+    let mut child = command.spawn()?;
+    let pid = child.id() as libc::pid_t;
+
+    let forwarder = std::thread::spawn(move || {
+        for origin in signals.forever() {
+            if origin.process.is_some() {
+                // SAFETY: kill has no memory safety requirements
+                unsafe {
+                    libc::kill(pid, origin.signal);
+                }
+            }
+        }
+    });
+
+    let status = child.wait();
+    handle.close();
+    let _ = forwarder.join();
+    status
+}
+
+#[cfg(not(unix))]
+fn spawn_and_wait(command: &mut Command) -> std::io::Result<ExitStatus> {
+    command.status()
+}
+
+#[cfg(unix)]
+fn exit_code(status: ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    }
+}
+
+#[cfg(not(unix))]
+fn exit_code(status: ExitStatus) -> i32 {
+    status.code().unwrap_or(1)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::run_with_env;
+    use envn::error::EnvnError;
+
+    fn sh(script: &str) -> Vec<String> {
+        vec!["sh".to_string(), "-c".to_string(), script.to_string()]
+    }
+
+    #[test]
+    fn test_exit_status() {
+        let vars = vec![("DATABASE_URL".to_string(), "postgres://".to_string())];
+        assert_eq!(
+            run_with_env(&sh("test \"$DATABASE_URL\" = postgres://"), vars).unwrap(),
+            0
+        );
+        assert_eq!(run_with_env(&sh("exit 3"), vec![]).unwrap(), 3);
+
+        // killed by a signal, like a shell reports it
+        assert_eq!(
+            run_with_env(&sh("kill -TERM $$"), vec![]).unwrap(),
+            128 + 15
+        );
+        assert_eq!(run_with_env(&sh("kill -KILL $$"), vec![]).unwrap(), 128 + 9);
+
+        assert!(matches!(
+            run_with_env(&["envn-no-such-program".to_string()], vec![]),
+            Err(EnvnError::NotFound(_))
+        ));
+        assert!(matches!(
+            run_with_env(&[], vec![]),
+            Err(EnvnError::Parse(_))
+        ));
+    }
+}
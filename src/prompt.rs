@@ -0,0 +1,136 @@
+/// This file wraps the prompts of the CLI
+/// With `--non-interactive`, every prompt fails with an error that names
+/// what was asked for, instead of waiting for input that never comes
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use envn::error::{EnvnError, Result};
+
+static NON_INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Turns every following prompt into an error
+pub fn set_non_interactive(non_interactive: bool) {
+    NON_INTERACTIVE.store(non_interactive, Ordering::Relaxed);
+}
+
+/// Checks if prompts are allowed
+pub fn is_interactive() -> bool {
+    !NON_INTERACTIVE.load(Ordering::Relaxed)
+}
+
+/// Returns an error if prompts are disabled
+///
+/// # Arguments
+///
+/// * `message` - The prompt that would have been shown
+fn ensure_interactive(message: &str) -> Result<()> {
+    match is_interactive() {
+        true => Ok(()),
+        false => Err(EnvnError::Prompt(format!(
+            "\"{}\" can not be asked with --non-interactive, pass it as an argument instead",
+            message
+        ))),
+    }
+}
+
+/// Asks for a line of text
+pub fn text(message: &str) -> Result<String> {
+    ensure_interactive(message)?;
+    Ok(inquire::Text::new(message).prompt()?)
+}
+
+/// Asks for a line of text, which is `default` if left empty
+pub fn text_with_default(message: &str, default: &str) -> Result<String> {
+    ensure_interactive(message)?;
+    Ok(inquire::Text::new(message).with_default(default).prompt()?)
+}
+
+/// Asks for a line of text, starting out as `initial`
+pub fn text_with_initial(message: &str, initial: &str) -> Result<String> {
+    ensure_interactive(message)?;
+    Ok(inquire::Text::new(message)
+        .with_initial_value(initial)
+        .prompt()?)
+}
+
+/// Asks a yes or no question
+pub fn confirm(message: &str, default: bool) -> Result<bool> {
+    ensure_interactive(message)?;
+    Ok(inquire::Confirm::new(message)
+        .with_default(default)
+        .prompt()?)
+}
+
+/// Asks to pick one of the options
+pub fn select<T: Display>(message: &str, options: Vec<T>) -> Result<T> {
+    ensure_interactive(message)?;
+    Ok(inquire::Select::new(message, options).prompt()?)
+}
+
+/// Asks to pick one of the options, narrowed down by typing any of its characters in order
+pub fn pick<T: Display>(message: &str, options: Vec<T>) -> Result<T> {
+    ensure_interactive(message)?;
+    Ok(inquire::Select::new(message, options)
+        .with_filter(&|input, _, option, _| fuzzy_match(input, option))
+        .prompt()?)
+}
+
+/// Checks if the characters of `input` appear in `option` in the same order, ignoring case
+fn fuzzy_match(input: &str, option: &str) -> bool {
+    let mut option = option.chars().flat_map(char::to_lowercase);
+    input
+        .chars()
+        .flat_map(char::to_lowercase)
+        .filter(|c| !c.is_whitespace())
+        .all(|c| option.any(|o| o == c))
+}
+
+/// Asks for the password, without echoing it
+pub fn password(message: &str) -> Result<String> {
+    ensure_interactive(message)?;
+    Ok(inquire::Password::new(message)
+        .without_confirmation()
+        .with_display_mode(inquire::PasswordDisplayMode::Masked)
+        .prompt()?)
+}
+
+/// Asks for a new password twice, without echoing it
+pub fn new_password(message: &str) -> Result<String> {
+    ensure_interactive(message)?;
+    Ok(inquire::Password::new(message)
+        .with_display_mode(inquire::PasswordDisplayMode::Masked)
+        .prompt()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{confirm, new_password, password, pick, select, set_non_interactive, text};
+    use envn::error::EnvnError;
+
+    #[test]
+    fn test_non_interactive() {
+        // the prompts would wait for input that never comes, if they were shown
+        set_non_interactive(true);
+        let results = [
+            text("Name").map(|_| ()),
+            confirm("Sure?", false).map(|_| ()),
+            select("Command", vec!["get"]).map(|_| ()),
+            pick("Secret", vec!["db"]).map(|_| ()),
+            password("Password").map(|_| ()),
+            new_password("Password").map(|_| ()),
+        ];
+        set_non_interactive(false);
+
+        for result in results {
+            match result {
+                Err(e @ EnvnError::Prompt(_)) => {
+                    assert_eq!(e.exit_code(), 9);
+                    assert!(e.to_string().contains("--non-interactive"));
+                }
+                other => panic!("expected a prompt error, got {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+/// This file matches secrets against the pattern of `envn search`
+/// A pattern is a glob like `aws_*` or `*_TOKEN` unless it is a regex,
+/// and is matched against the name and key of a secret, and optionally its value
+use regex::{Regex, RegexBuilder};
+
+use crate::{
+    error::{EnvnError, Result},
+    utils::DisplayEnv,
+};
+
+/// A compiled search pattern
+#[derive(Debug, Clone)]
+pub struct Query {
+    pattern: Regex,
+    /// Whether the decrypted values are searched as well
+    in_values: bool,
+}
+
+impl Query {
+    /// Compiles a glob, where `*` matches any run of characters and `?` a single one.
+    /// A glob without either of them matches anywhere, like `*pattern*` would.
+    /// Globs ignore case.
+    pub fn glob(glob: &str, in_values: bool) -> Result<Self> {
+        let mut pattern = String::new();
+        for c in glob.chars() {
+            match c {
+                '*' => pattern.push_str(".*"),
+                '?' => pattern.push('.'),
+                c => pattern.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        if glob.contains(['*', '?']) {
+            pattern = format!("^{}$", pattern);
+        }
+        let pattern = RegexBuilder::new(&pattern)
+            .case_insensitive(true)
+            .build()
+            .map_err(|e| EnvnError::Parse(e.to_string()))?;
+        Ok(Self { pattern, in_values })
+    }
+
+    /// Compiles a regex, which matches anywhere unless it is anchored with `^` or `$`.
+    pub fn regex(regex: &str, in_values: bool) -> Result<Self> {
+        let pattern = Regex::new(regex)
+            .map_err(|e| EnvnError::Parse(format!("{} is not a valid regex, {}", regex, e)))?;
+        Ok(Self { pattern, in_values })
+    }
+
+    /// Checks if the name or key of a secret, or its value if asked to, matches.
+    pub fn matches(&self, env: &DisplayEnv) -> bool {
+        self.pattern.is_match(&env.name)
+            || self.pattern.is_match(&env.key)
+            || (self.in_values && self.pattern.is_match(&env.value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Query;
+    use crate::utils::DisplayEnv;
+
+    fn env(name: &str, key: &str, value: &str) -> DisplayEnv {
+        DisplayEnv {
+            name: name.to_string(),
+            key: key.to_string(),
+            value: value.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        let aws = env("aws_deploy", "AWS_SECRET_ACCESS_KEY", "s3cr3t");
+        let db = env("db", "DATABASE_URL", "postgres://aws.example.com");
+
+        let glob = Query::glob("aws_*", false).unwrap();
+        assert!(glob.matches(&aws) && !glob.matches(&db));
+        assert!(Query::glob("*_url", false).unwrap().matches(&db));
+        assert!(!Query::glob("d?", false).unwrap().matches(&aws));
+        assert!(Query::glob("d?", false).unwrap().matches(&db));
+        assert!(Query::glob("secret", false).unwrap().matches(&aws));
+        assert!(!Query::glob("a.s", false).unwrap().matches(&aws));
+
+        assert!(!Query::glob("example", false).unwrap().matches(&db));
+        assert!(Query::glob("example", true).unwrap().matches(&db));
+
+        let regex = Query::regex("^(db|cache)$", false).unwrap();
+        assert!(regex.matches(&db) && !regex.matches(&aws));
+        assert!(Query::regex("(", false).is_err());
+    }
+}
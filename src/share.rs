@@ -0,0 +1,103 @@
+/// This file packs secrets into the bundles of `envn share`, which only one person can open
+/// A bundle is sealed to the recipient of that person with your identity,
+/// and armored as text, so that it can be sent as a file or pasted into a chat
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{EnvnError, Result},
+    identity::{Identity, Recipient, Sealed},
+};
+
+/// What bundles are sealed for, so that nothing sealed for anything else opens as one
+const CONTEXT: &str = "envn share v1";
+
+const BEGIN: &str = "-----BEGIN ENVN BUNDLE-----";
+const END: &str = "-----END ENVN BUNDLE-----";
+
+/// A secret in a bundle
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SharedSecret {
+    pub name: String,
+    pub key: String,
+    pub value: String,
+}
+
+/// Packs secrets into a bundle only `recipient` can open.
+///
+/// # Arguments
+///
+/// * `identity` - The identity of the sender, which the recipient gets to see.
+/// * `recipient` - Who can open the bundle.
+/// * `secrets` - The secrets to hand over.
+///
+/// # Returns
+///
+/// The armored bundle.
+pub fn pack(
+    identity: &Identity,
+    recipient: &Recipient,
+    secrets: &[SharedSecret],
+) -> Result<String> {
+    let sealed = identity.seal(recipient, CONTEXT, &bincode::serialize(secrets)?)?;
+    let encoded = STANDARD.encode(bincode::serialize(&sealed)?);
+
+    let mut bundle = format!("{}\n", BEGIN);
+    for line in encoded.as_bytes().chunks(64) {
+        bundle.push_str(&String::from_utf8_lossy(line));
+        bundle.push('\n');
+    }
+    bundle.push_str(END);
+    bundle.push('\n');
+    Ok(bundle)
+}
+
+/// Opens a bundle that was shared with `identity`.
+///
+/// # Returns
+///
+/// The recipient of whoever packed the bundle, and its secrets.
+/// A `Parse` error if it is no bundle, or a `Crypto` error if it was meant for
+/// someone else or tampered with.
+pub fn unpack(identity: &Identity, bundle: &str) -> Result<(Recipient, Vec<SharedSecret>)> {
+    let not_a_bundle = || EnvnError::Parse("This is not a bundle of envn share".to_string());
+    let armored = bundle
+        .trim()
+        .strip_prefix(BEGIN)
+        .and_then(|rest| rest.strip_suffix(END))
+        .ok_or_else(not_a_bundle)?;
+    let encoded: String = armored.split_whitespace().collect();
+    let bytes = STANDARD.decode(encoded).map_err(|_| not_a_bundle())?;
+
+    let sealed: Sealed = bincode::deserialize(&bytes)?;
+    let secrets = bincode::deserialize(&identity.open(&sealed, CONTEXT)?)?;
+    Ok((sealed.sender(), secrets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pack, unpack, SharedSecret};
+    use crate::identity::Identity;
+
+    #[test]
+    fn test_pack_and_unpack() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let secrets = vec![SharedSecret {
+            name: "db".to_string(),
+            key: "DATABASE_URL".to_string(),
+            value: "postgres://localhost".to_string(),
+        }];
+
+        let bundle = pack(&alice, &bob.recipient(), &secrets).unwrap();
+        assert!(bundle
+            .lines()
+            .all(|line| line.len() <= 64 || line.starts_with("-----")));
+        let (sender, received) = unpack(&bob, &bundle).unwrap();
+        assert_eq!(sender, alice.recipient());
+        assert_eq!(received, secrets);
+
+        assert!(unpack(&alice, &bundle).is_err());
+        assert!(unpack(&bob, "DATABASE_URL=postgres://localhost").is_err());
+    }
+}
@@ -1,123 +1,310 @@
-use aes_gcm::{Aes256Gcm, Key, Nonce};
+use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
 
 use crate::{
     db::Entry,
-    file::{file_exists, set_password},
+    encryption::Keyring,
+    error::{EnvnError, Result},
 };
 
 #[derive(Debug)]
 pub struct Env {
+    pub project_id: i64,
+    pub environment_id: i64,
     pub name: String,
     pub key: String,
     pub value: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub key_version: u32,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, serde::Serialize)]
 /// A struct representing the display environment.
 pub struct DisplayEnv {
     pub name: String,
     pub key: String,
     pub value: String,
+    #[serde(flatten)]
+    pub metadata: Metadata,
 }
 
-/// Retrieves the password from the environment variables.
+/// What a secret is for, and when it has to be rotated.
+/// The metadata is stored next to the secret, it is not encrypted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct Metadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// Where the secret comes from, like the settings page it was created on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// When the secret was first set, in UTC
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    /// When the secret was last set, in UTC
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
+    /// When the secret has to be rotated, in UTC
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>,
+}
+
+impl Metadata {
+    /// Checks if the secret expires before `time`.
+    /// Secrets without an expiry never do.
+    pub fn expires_before(&self, time: NaiveDateTime) -> bool {
+        self.expires_at
+            .as_deref()
+            .and_then(|expires_at| NaiveDateTime::parse_from_str(expires_at, TIME_FORMAT).ok())
+            .is_some_and(|expires_at| expires_at < time)
+    }
+
+    /// Checks if the secret has the tag.
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|own| own == tag)
+    }
+}
+
+/// A change to the metadata of a secret.
+/// Fields that are `None` are kept, empty ones are cleared.
+#[derive(Debug, Clone, Default)]
+pub struct MetadataUpdate {
+    pub description: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub owner: Option<String>,
+    pub url: Option<String>,
+    /// In the format of `TIME_FORMAT`, see `parse_expiry`
+    pub expires_at: Option<String>,
+}
+
+impl MetadataUpdate {
+    /// Checks if the update changes nothing.
+    pub fn is_empty(&self) -> bool {
+        self.description.is_none()
+            && self.tags.is_none()
+            && self.owner.is_none()
+            && self.url.is_none()
+            && self.expires_at.is_none()
+    }
+
+    /// Applies the update to the metadata of a secret.
+    pub fn apply(&self, metadata: &mut Metadata) {
+        let update = |field: &mut Option<String>, value: &Option<String>| {
+            if let Some(value) = value {
+                *field = Some(value.clone()).filter(|value| !value.is_empty());
+            }
+        };
+        update(&mut metadata.description, &self.description);
+        update(&mut metadata.owner, &self.owner);
+        update(&mut metadata.url, &self.url);
+        update(&mut metadata.expires_at, &self.expires_at);
+
+        if let Some(tags) = &self.tags {
+            metadata.tags = tags
+                .iter()
+                .map(|tag| tag.trim().to_string())
+                .filter(|tag| !tag.is_empty())
+                .collect();
+        }
+    }
+}
+
+/// Hides a value, keeping only enough of it to tell values apart.
 ///
 /// # Returns
 ///
-/// - `Ok(String)`: The password retrieved from the environment variables.
-/// - `Err(std::env::VarError)`: If there was an error retrieving the password from the environment variables.
-fn get_password_from_env() -> Result<String, std::env::VarError> {
-    std::env::var("ENVN_PASSWORD")
+/// The first and last two characters around `****`, or only `********`
+/// for values too short to give any of them away.
+///
+/// # Example
+///
+/// ```
+/// use envn::utils::mask;
+///
+/// assert_eq!(mask("postgres://localhost"), "po****st");
+/// assert_eq!(mask("hunter2"), "********");
+/// ```
+pub fn mask(value: &str) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    if chars.len() < 12 {
+        return "********".to_string();
+    }
+    let start: String = chars[..2].iter().collect();
+    let end: String = chars[chars.len() - 2..].iter().collect();
+    format!("{}****{}", start, end)
 }
 
-/// Checks the validity of a password.
-///
-/// Returns `true` if the password is valid, `false` otherwise.
-pub fn check_password() -> bool {
-    let password: String = match get_password_from_env() {
-        Ok(password) => password,
-        Err(_) => inquire::Password::new("Enter your password 👀")
-            .without_confirmation()
-            .with_display_mode(inquire::PasswordDisplayMode::Masked)
-            .prompt()
-            .unwrap(),
-    };
+/// The format timestamps are stored in, always in UTC
+pub const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
 
-    let key_file = crate::file::join_app_path("auth");
+/// Parses an amount of time like `30m`, `12h` or `7d`.
+pub fn parse_duration(input: &str) -> Result<Duration> {
+    let input = input.trim();
+    let amount = input
+        .get(..input.len().saturating_sub(1))
+        .and_then(|amount| amount.parse::<i64>().ok());
+    match (amount, input.chars().last()) {
+        (Some(amount), Some('m')) => Ok(Duration::minutes(amount)),
+        (Some(amount), Some('h')) => Ok(Duration::hours(amount)),
+        (Some(amount), Some('d')) => Ok(Duration::days(amount)),
+        _ => Err(EnvnError::Parse(format!(
+            "{} is not an amount of time, use something like 30m, 12h or 7d",
+            input
+        ))),
+    }
+}
 
-    if !file_exists(&key_file) {
-        bunt::println!("{$red}No password file found{/$}");
+/// Parses a date like `2024-01-31`, or a time like `2024-01-31 12:00:00`, in UTC.
+pub fn parse_time(input: &str) -> Result<NaiveDateTime> {
+    let input = input.trim();
+    if let Ok(time) = NaiveDateTime::parse_from_str(input, TIME_FORMAT) {
+        return Ok(time);
+    }
+    NaiveDate::parse_from_str(input, "%Y-%m-%d")
+        .map(|date| date.and_hms_opt(0, 0, 0).unwrap_or_default())
+        .map_err(|_| {
+            EnvnError::Parse(format!(
+                "{} is not a time, use a date like 2024-01-31",
+                input
+            ))
+        })
+}
 
-        let password_confirm = inquire::Confirm::new("Do you want to create a password file?")
-            .with_default(true)
-            .prompt()
-            .unwrap();
-        if !password_confirm {
-            bunt::println!("{$red}No password file found{/$}");
-            return false;
-        }
+/// The current time, in UTC
+pub fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
 
-        return set_password();
+/// Parses when a secret expires, either a time or an amount of time from now.
+///
+/// # Returns
+///
+/// The time in the format of `TIME_FORMAT`, an empty string if `input` is empty,
+/// or a `Parse` error.
+pub fn parse_expiry(input: &str) -> Result<String> {
+    if input.is_empty() {
+        return Ok(String::new());
     }
+    let time = match parse_duration(input) {
+        Ok(duration) => now() + duration,
+        Err(_) => parse_time(input).map_err(|_| {
+            EnvnError::Parse(format!(
+                "{} is not a time, use a date like 2024-01-31 or a time from now like 90d",
+                input
+            ))
+        })?,
+    };
+    Ok(time.format(TIME_FORMAT).to_string())
+}
 
-    let hashed = std::fs::read_to_string(key_file).expect("Failed to read key file");
-
-    bcrypt::verify(password, &hashed).expect("Failed to verify password")
+/// The associated data a value is encrypted with.
+/// It binds the ciphertext to the project, environment, `name` and `key` of its row, so that moving
+/// a value to another row, project or environment, or changing the key column, makes the decryption fail.
+/// A version of a secret is bound to its `version` number as well, so that versions can not be
+/// swapped with each other, nor with the current value.
+pub fn associated_data(
+    project_id: i64,
+    environment_id: i64,
+    name: &str,
+    key: &str,
+    version: Option<u32>,
+) -> Result<Vec<u8>> {
+    let mut data = bincode::serialize(&(project_id, environment_id, name, key))?;
+    if let Some(version) = version {
+        data.extend(bincode::serialize(&version)?);
+    }
+    Ok(data)
 }
 
-/// Constructs a new `Env` struct with the given `name`, `key`, and `value`.
-/// The `value` is encrypted using the `key` and `nonce` that are stored in the `key` and `nonce` files.
+/// Constructs a new `Env` struct with the given `project_id`, `name`, `key`, and `value`.
+/// The `value` is encrypted using the current key of the keyring and a fresh random nonce.
 /// This is essentially, the abstraction for the `Env` struct.
 ///
 /// # Arguments
 ///
+/// * `project_id` - The project the environment variable is in.
+/// * `environment_id` - The environment of the project it is set in.
 /// * `name` - The name of the environment variable.
 /// * `key` - The key of the environment variable.
 /// * `value` - The value of the environment variable.
+/// * `version` - The version it is encrypted for, `None` for the current value.
+/// * `user_key` - The unlocked keyring.
 ///
 /// # Returns
 ///
 /// A new `Env` struct with the specified `name`, `key`, and `value`.
-pub fn construct_struct(name: String, key: String, value: String) -> Env {
-    let (user_key, nonce) = crate::file::get_keys_and_nonce();
+pub fn construct_struct(
+    project_id: i64,
+    environment_id: i64,
+    name: String,
+    key: String,
+    value: String,
+    version: Option<u32>,
+    user_key: &Keyring,
+) -> Result<Env> {
+    let nonce = crate::encryption::get_nonce();
+    let (key_version, data_key) = user_key.current();
 
-    let bytes_of_value = bincode::serialize(&value).expect("Failed to serialize value");
+    let bytes_of_value = bincode::serialize(&value)?;
     let encrypted_value = crate::encryption::encrypt(
-        Key::<Aes256Gcm>::from_slice(&user_key).to_owned(),
-        Nonce::from_slice(&nonce).to_owned(),
+        data_key,
+        nonce,
         &bytes_of_value,
-    );
-    Env {
+        &associated_data(project_id, environment_id, &name, &key, version)?,
+    )?;
+    Ok(Env {
+        project_id,
+        environment_id,
         name,
         key,
         value: encrypted_value,
-    }
+        nonce: nonce.to_vec(),
+        key_version,
+    })
 }
 
 /// Decrypts a given `Entry` and returns a `DisplayEnv` struct.
-/// The `Entry` is decrypted using the `key` and `nonce` that are stored in the `key` and `nonce` files.
+/// The `Entry` is decrypted using the key of its version and the nonce stored with it.
 /// This is essentially, the abstraction for the `DisplayEnv` struct.
 ///
 /// # Arguments
 ///
 /// * `entry` - The `Entry` to be decrypted.
+/// * `user_key` - The unlocked keyring.
 ///
 /// # Returns
 ///
-/// The decrypted `DisplayEnv` struct.
-pub fn decrypt_struct(entry: Entry) -> DisplayEnv {
-    let (user_key, nonce) = crate::file::get_keys_and_nonce();
+/// The decrypted `DisplayEnv` struct, or an error if the value was tampered with,
+/// moved from another row, its name or key were changed, or its key is no longer in the keyring.
+pub fn decrypt_struct(entry: Entry, user_key: &Keyring) -> Result<DisplayEnv> {
+    let integrity_error =
+        || EnvnError::Crypto(format!("Secret {} failed the integrity check", entry.name));
+
+    let data_key = user_key
+        .get(entry.key_version)
+        .ok_or_else(integrity_error)?;
     let decrypted_value = crate::encryption::decrypt(
-        Key::<Aes256Gcm>::from_slice(&user_key).to_owned(),
-        Nonce::from_slice(&nonce).to_owned(),
-        entry.value,
-    );
-    let value = bincode::deserialize(&decrypted_value).expect("Failed to deserialize value");
-    DisplayEnv {
+        data_key,
+        &entry.nonce,
+        &entry.value,
+        &associated_data(
+            entry.project_id,
+            entry.environment_id,
+            &entry.name,
+            &entry.key,
+            entry.version,
+        )?,
+    )
+    .map_err(|_| integrity_error())?;
+    let value = bincode::deserialize(&decrypted_value).map_err(|_| integrity_error())?;
+    Ok(DisplayEnv {
         name: entry.name,
         key: entry.key,
         value,
-    }
+        metadata: Metadata::default(),
+    })
 }
 
 /// Displays the environment.
@@ -129,54 +316,52 @@ pub fn decrypt_struct(entry: Entry) -> DisplayEnv {
 /// # Example
 ///
 /// ```
-/// use envn::utils::display_env;
+/// use envn::utils::{display_env, DisplayEnv};
 ///
-/// let env = DisplayEnv { /* initialize the DisplayEnv struct */ };
-/// display_env(env);
+/// let env = DisplayEnv {
+///     name: "db".to_string(),
+///     key: "DATABASE_URL".to_string(),
+///     value: "postgres://localhost".to_string(),
+///     ..Default::default()
+/// };
+/// display_env(env, false);
 /// ```
-pub fn display_env(env: DisplayEnv) {
+pub fn display_env(env: DisplayEnv, reveal: bool) {
     bunt::println!("{$blue}\n-----Secret--------{/$}");
     bunt::println!("{$yellow}Name{/$}: {$green}{}{/$}", env.name);
     bunt::println!("{$yellow}Key{/$}: {$green}{}{/$}", env.key);
-    bunt::println!("{$yellow}Value{/$}: {$green}{}{/$}", env.value);
-}
-
-/// Displays the help information for a specific command.
-///
-/// # Arguments
-///
-/// * `cmd` - An optional string representing the command for which help information is to be displayed.
-///
-pub fn display_help(cmd: Option<String>) {
-    let cmd = cmd.unwrap_or("all".to_string());
-    bunt::println!("The Premium Secret Manager\n");
-
-    bunt::println!("{$underline}Usage:{/$} {$green}evnv{/$} {$yellow}[command] [name]{/$}");
+    match reveal {
+        true => bunt::println!("{$yellow}Value{/$}: {$green}{}{/$}", env.value),
+        false => bunt::println!("{$yellow}Value{/$}: {$dimmed}{}{/$}", mask(&env.value)),
+    }
 
-    match cmd.as_str() {
-        "all" => {
-            bunt::println!("{$blue}Show All{/$} secrets");
-            bunt::println!("envn {$green}all{/$} [range]");
-        }
-        "show" => {
-            bunt::println!("{$blue}Show{/$} a secret");
-            bunt::println!("envn {$green}show{/$} [name]");
-        }
-        "add" => {
-            bunt::println!("{$blue}Add{/$} a secret");
-            bunt::println!("envn {$green}add{/$} [name]");
-        }
-        "load" => {
-            bunt::println!("{$blue}Load{/$} from a file");
-            bunt::println!("envn {$green}load{/$} [name]");
-        }
-        "save" => {
-            bunt::println!("{$blue}Save{/$} secret to a file");
-            bunt::println!("envn {$green}save{/$} [name]");
-        }
-        _ => {
-            bunt::println!("Available Commands: show, add, load, save, all");
-            bunt::println!("Use envn help {$yellow}[command]{/$} to see more info about a command");
+    let metadata = env.metadata;
+    let expired = metadata.expires_before(now());
+    if let Some(description) = metadata.description {
+        bunt::println!("{$yellow}Description{/$}: {}", description);
+    }
+    if !metadata.tags.is_empty() {
+        bunt::println!("{$yellow}Tags{/$}: {}", metadata.tags.join(", "));
+    }
+    if let Some(owner) = metadata.owner {
+        bunt::println!("{$yellow}Owner{/$}: {}", owner);
+    }
+    if let Some(url) = metadata.url {
+        bunt::println!("{$yellow}URL{/$}: {}", url);
+    }
+    if let Some(created_at) = metadata.created_at {
+        bunt::println!("{$yellow}Created{/$}: {$dimmed}{} UTC{/$}", created_at);
+    }
+    if let Some(updated_at) = metadata.updated_at {
+        bunt::println!("{$yellow}Updated{/$}: {$dimmed}{} UTC{/$}", updated_at);
+    }
+    if let Some(expires_at) = metadata.expires_at {
+        match expired {
+            true => bunt::println!(
+                "{$yellow}Expires{/$}: {$red}{} UTC, expired{/$}",
+                expires_at
+            ),
+            false => bunt::println!("{$yellow}Expires{/$}: {} UTC", expires_at),
         }
     }
 }
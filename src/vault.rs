@@ -0,0 +1,1361 @@
+/// This file holds the `Vault`, the programmatic way into a store of secrets
+/// A store is a directory holding the `auth` file, the `keyring` and the `env.db` database
+/// The CLI is a thin client of the vault, other tools can use it the same way
+/// Secrets live in the environments of projects, a vault works on one environment at a time
+use std::path::{Path, PathBuf};
+
+use rusqlite::Connection;
+
+use crate::{
+    audit,
+    db::{self, Change, Environment, Project, BASE_ENVIRONMENT, DEFAULT_PROJECT},
+    dotenv,
+    encryption::{KdfParams, Keyring, WrappingKey},
+    error::{EnvnError, Result},
+    file,
+    identity::{self, Identity, Recipient},
+    share::{self, SharedSecret},
+    utils::{construct_struct, decrypt_struct, DisplayEnv, MetadataUpdate},
+    vaultfile::Trust,
+};
+
+/// An unlocked store of secrets.
+///
+/// # Example
+///
+/// ```
+/// use envn::Vault;
+///
+/// let dir = std::env::temp_dir().join(format!("envn-doc-{}", std::process::id()));
+/// let vault = Vault::create(&dir, "hunter2").unwrap();
+/// vault.set("db", "DATABASE_URL", "postgres://localhost").unwrap();
+///
+/// let vault = Vault::open(&dir, "hunter2").unwrap();
+/// assert_eq!(vault.get("db").unwrap().value, "postgres://localhost");
+/// assert_eq!(vault.export(&["db"]).unwrap(), "DATABASE_URL=postgres://localhost\n");
+/// # std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+pub struct Vault {
+    dir: PathBuf,
+    conn: Connection,
+    keyring: Keyring,
+    kdf: KdfParams,
+    /// The project the secrets are read from and written to
+    project: Project,
+    /// The environment of the project the secrets are written to,
+    /// they are read from it and the environments it inherits from
+    environment: Environment,
+}
+
+/// An environment, together with the number of secrets set in it
+#[derive(Debug, Clone)]
+pub struct EnvironmentInfo {
+    pub name: String,
+    /// The environment it inherits from, `None` for the base environment
+    pub parent: Option<String>,
+    pub secrets: usize,
+}
+
+/// A version of a secret
+#[derive(Debug, Clone)]
+pub struct Version {
+    pub version: u32,
+    pub change: Change,
+    /// When the change was made, in UTC
+    pub created_at: String,
+    /// The version whose value a rollback restored
+    pub restored_from: Option<u32>,
+    /// The secret as it was after the change, `None` if it was deleted
+    pub secret: Option<DisplayEnv>,
+}
+
+impl std::fmt::Display for Version {
+    /// Shows the version without its value, like `v2 2024-01-31 12:00:00 update`
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "v{} {} {}", self.version, self.created_at, self.change)
+    }
+}
+
+/// How a secret differs between two environments
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    /// Both environments have the secret, with a different key or value
+    Differs,
+    /// Only the named environment has the secret
+    OnlyIn(String),
+    /// Both environments have the same key and value, but at least one sets it itself
+    /// instead of inheriting it from the same place
+    Overridden,
+}
+
+/// A project, together with the number of secrets in it
+#[derive(Debug, Clone)]
+pub struct ProjectInfo {
+    pub name: String,
+    pub dir: Option<String>,
+    pub secrets: usize,
+}
+
+impl Vault {
+    /// Creates a new store in `dir`, protected by `password`.
+    /// The key is wrapped with the default key derivation parameters.
+    pub fn create(dir: &Path, password: &str) -> Result<Self> {
+        Vault::create_with_kdf(dir, password, KdfParams::default())
+    }
+
+    /// Creates a new store in `dir`, protected by `password`.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The directory of the store, created if it does not exist.
+    /// * `password` - The master password.
+    /// * `kdf` - The key derivation parameters, used whenever the keys are wrapped.
+    pub fn create_with_kdf(dir: &Path, password: &str, kdf: KdfParams) -> Result<Self> {
+        let keyring = file::init_store(dir, password, kdf)?;
+        Vault::connect(dir, keyring, kdf)
+    }
+
+    /// Opens the store in `dir` with `password`.
+    /// The default key derivation parameters are used if the keys have to be wrapped again.
+    pub fn open(dir: &Path, password: &str) -> Result<Self> {
+        Vault::open_with_kdf(dir, password, KdfParams::default())
+    }
+
+    /// Opens the store in `dir` with `password`.
+    ///
+    /// An interrupted password change is finished first, and the store
+    /// of an older version of envn is migrated.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The directory of the store.
+    /// * `password` - The master password.
+    /// * `kdf` - The key derivation parameters, used whenever the keys are wrapped.
+    ///
+    /// # Returns
+    ///
+    /// The unlocked vault, an `Auth` error if the password is wrong,
+    /// or a `NotFound` error if there is no store in `dir`.
+    pub fn open_with_kdf(dir: &Path, password: &str, kdf: KdfParams) -> Result<Self> {
+        file::recover_password_change(dir)?;
+        file::verify_password(dir, password)?;
+        let keyring = file::unlock_key(dir, password, kdf)?;
+        Vault::connect(dir, keyring, kdf)
+    }
+
+    /// Opens the store in `dir` with the key held by the agent, instead of the password.
+    ///
+    /// # Returns
+    ///
+    /// The unlocked vault, or an `Auth` error if the password changed since the agent was unlocked.
+    pub fn open_with_key(dir: &Path, wrapping_key: &WrappingKey, kdf: KdfParams) -> Result<Self> {
+        file::recover_password_change(dir)?;
+        let keyring = file::unlock_key_with(dir, wrapping_key)?;
+        Vault::connect(dir, keyring, kdf)
+    }
+
+    /// The key the keyring is wrapped with, for the agent to hold on to.
+    pub fn wrapping_key(&self) -> WrappingKey {
+        self.keyring.wrapping_key()
+    }
+
+    /// Connects to the database of the store and brings it up to date.
+    /// The default project is selected.
+    fn connect(dir: &Path, keyring: Keyring, kdf: KdfParams) -> Result<Self> {
+        let mut conn = db::connect_to_db(&dir.join("env.db"))?;
+        db::prepare_db(&mut conn, dir, &keyring)?;
+        let project = db::get_project_by_name(&conn, DEFAULT_PROJECT)?
+            .ok_or(EnvnError::NotFound(format!("Project {}", DEFAULT_PROJECT)))?;
+        let environment = base_environment(&conn, &project)?;
+        Ok(Vault {
+            dir: dir.to_path_buf(),
+            conn,
+            keyring,
+            kdf,
+            project,
+            environment,
+        })
+    }
+
+    /// The directory of the store.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The name of the selected project.
+    pub fn project(&self) -> &str {
+        &self.project.name
+    }
+
+    /// Selects the project every following operation on secrets works on,
+    /// starting out with its base environment.
+    ///
+    /// # Returns
+    ///
+    /// A `NotFound` error if there is no project with this name.
+    pub fn select_project(&mut self, name: &str) -> Result<()> {
+        let project = self.find_project(name)?;
+        self.environment = base_environment(&self.conn, &project)?;
+        self.project = project;
+        Ok(())
+    }
+
+    /// The name of the selected environment.
+    pub fn environment(&self) -> &str {
+        &self.environment.name
+    }
+
+    /// Selects the environment of the project every following operation on secrets works on.
+    ///
+    /// # Returns
+    ///
+    /// A `NotFound` error if the project has no environment with this name.
+    pub fn select_environment(&mut self, name: &str) -> Result<()> {
+        self.environment = self.find_environment(name)?;
+        Ok(())
+    }
+
+    /// Returns every environment of the project, with the number of secrets set in it.
+    pub fn environments(&self) -> Result<Vec<EnvironmentInfo>> {
+        let environments = db::get_environments(&self.conn, self.project.id)?;
+        environments
+            .iter()
+            .map(|environment| {
+                let parent = environments
+                    .iter()
+                    .find(|parent| Some(parent.id) == environment.parent_id)
+                    .map(|parent| parent.name.clone());
+                Ok(EnvironmentInfo {
+                    name: environment.name.clone(),
+                    parent,
+                    secrets: db::get_environment_entries(&self.conn, environment.id)?.len(),
+                })
+            })
+            .collect()
+    }
+
+    /// Creates an environment in the project.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the environment, made of letters, digits, `_`, `.` and `-`.
+    /// * `parent` - The environment to inherit secrets from, the base environment if left out.
+    pub fn create_environment(&self, name: &str, parent: Option<&str>) -> Result<()> {
+        check_name(name, "an environment")?;
+        if db::get_environment_by_name(&self.conn, self.project.id, name)?.is_some() {
+            return Err(EnvnError::Parse(format!(
+                "Environment {} already exists",
+                name
+            )));
+        }
+
+        let parent = self.find_environment(parent.unwrap_or(BASE_ENVIRONMENT))?;
+        db::create_environment(&self.conn, self.project.id, name, parent.id)
+    }
+
+    /// Deletes an environment of the project together with the secrets set in it.
+    /// If it was selected, the base environment is selected instead.
+    ///
+    /// # Returns
+    ///
+    /// The number of deleted secrets.
+    pub fn delete_environment(&mut self, name: &str) -> Result<usize> {
+        let environment = self.find_environment(name)?;
+        if environment.parent_id.is_none() {
+            return Err(EnvnError::Parse(format!(
+                "The {} environment can not be deleted",
+                environment.name
+            )));
+        }
+        let environments = db::get_environments(&self.conn, self.project.id)?;
+        if let Some(child) = environments
+            .iter()
+            .find(|child| child.parent_id == Some(environment.id))
+        {
+            return Err(EnvnError::Parse(format!(
+                "Environment {} inherits from {}, delete it first",
+                child.name, environment.name
+            )));
+        }
+
+        let count = db::get_environment_entries(&self.conn, environment.id)?.len();
+        db::delete_environment(&mut self.conn, environment.id)?;
+        if environment.id == self.environment.id {
+            self.environment = base_environment(&self.conn, &self.project)?;
+        }
+        Ok(count)
+    }
+
+    /// Compares the secrets of two environments of the project, inherited ones included.
+    ///
+    /// # Returns
+    ///
+    /// The names of the secrets that are not simply inherited from the same place,
+    /// with how they differ, in the order they first appear in.
+    pub fn diff(&self, first: &str, second: &str) -> Result<Vec<(String, Difference)>> {
+        let first = self.find_environment(first)?;
+        let second = self.find_environment(second)?;
+        let first_secrets = self.resolve(&first)?;
+        let second_secrets = self.resolve(&second)?;
+
+        let mut differences = Vec::new();
+        for (env, from) in &first_secrets {
+            let difference = match second_secrets
+                .iter()
+                .find(|(other, _)| other.name == env.name)
+            {
+                None => Difference::OnlyIn(first.name.clone()),
+                Some((_, other_from)) if other_from == from => continue,
+                Some((other, _)) if other.key == env.key && other.value == env.value => {
+                    Difference::Overridden
+                }
+                Some(_) => Difference::Differs,
+            };
+            differences.push((env.name.clone(), difference));
+        }
+        for (env, _) in &second_secrets {
+            if !first_secrets
+                .iter()
+                .any(|(other, _)| other.name == env.name)
+            {
+                differences.push((env.name.clone(), Difference::OnlyIn(second.name.clone())));
+            }
+        }
+        Ok(differences)
+    }
+
+    /// The environment followed by the ones it inherits from, up to the base environment.
+    fn chain(&self, environment: &Environment) -> Result<Vec<Environment>> {
+        let mut chain = vec![environment.clone()];
+        while let Some(parent_id) = chain[chain.len() - 1].parent_id {
+            if chain.iter().any(|environment| environment.id == parent_id) {
+                return Err(EnvnError::Parse(format!(
+                    "Environment {} inherits from itself",
+                    environment.name
+                )));
+            }
+            let parent = db::get_environment(&self.conn, parent_id)?
+                .ok_or(EnvnError::NotFound(format!("Environment {}", parent_id)))?;
+            chain.push(parent);
+        }
+        Ok(chain)
+    }
+
+    /// Returns the decrypted secrets of an environment, inherited ones included,
+    /// each with the id of the environment it is set in.
+    /// Secrets of the base environment come first, overridden ones keep their place.
+    fn resolve(&self, environment: &Environment) -> Result<Vec<(DisplayEnv, i64)>> {
+        let mut secrets: Vec<(DisplayEnv, i64)> = Vec::new();
+        for layer in self.chain(environment)?.iter().rev() {
+            for env in db::get_all_names(&self.conn, layer.id, &self.keyring)? {
+                match secrets.iter_mut().find(|(other, _)| other.name == env.name) {
+                    Some(secret) => *secret = (env, layer.id),
+                    None => secrets.push((env, layer.id)),
+                }
+            }
+        }
+        Ok(secrets)
+    }
+
+    /// Looks up an environment of the project by its name.
+    fn find_environment(&self, name: &str) -> Result<Environment> {
+        db::get_environment_by_name(&self.conn, self.project.id, name)?.ok_or(EnvnError::NotFound(
+            format!("Environment {} in project {}", name, self.project.name),
+        ))
+    }
+
+    /// Finds the project for a directory.
+    ///
+    /// The project linked to the closest parent of `cwd` wins,
+    /// otherwise a project named like the directory itself is used.
+    ///
+    /// # Returns
+    ///
+    /// The name of the project, or `None` if no project matches.
+    pub fn project_for_dir(&self, cwd: &Path) -> Result<Option<String>> {
+        let projects = db::get_projects(&self.conn)?;
+
+        let linked = projects
+            .iter()
+            .filter_map(|project| Some((project, Path::new(project.dir.as_ref()?))))
+            .filter(|(_, dir)| cwd.starts_with(dir))
+            .max_by_key(|(_, dir)| dir.components().count());
+        if let Some((project, _)) = linked {
+            return Ok(Some(project.name.clone()));
+        }
+
+        let base = cwd.file_name().and_then(|name| name.to_str());
+        Ok(projects
+            .into_iter()
+            .find(|project| Some(project.name.as_str()) == base)
+            .map(|project| project.name))
+    }
+
+    /// Returns every project, with the number of secrets in it.
+    pub fn projects(&self) -> Result<Vec<ProjectInfo>> {
+        db::get_projects(&self.conn)?
+            .into_iter()
+            .map(|project| {
+                Ok(ProjectInfo {
+                    secrets: db::count_entries(&self.conn, project.id)?,
+                    name: project.name,
+                    dir: project.dir,
+                })
+            })
+            .collect()
+    }
+
+    /// Creates a project.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the project, made of letters, digits, `_`, `.` and `-`.
+    /// * `dir` - The directory to link the project to, so that it is used inside of it.
+    pub fn create_project(&mut self, name: &str, dir: Option<&Path>) -> Result<()> {
+        check_name(name, "a project")?;
+        if db::get_project_by_name(&self.conn, name)?.is_some() {
+            return Err(EnvnError::Parse(format!("Project {} already exists", name)));
+        }
+
+        let dir = dir
+            .map(|dir| {
+                dir.to_str()
+                    .map(str::to_string)
+                    .ok_or(EnvnError::Parse(format!(
+                        "{} is not valid UTF-8",
+                        dir.display()
+                    )))
+            })
+            .transpose()?;
+        db::create_project(&mut self.conn, name, dir.as_deref())
+    }
+
+    /// Renames a project, keeping its secrets.
+    pub fn rename_project(&mut self, name: &str, new_name: &str) -> Result<()> {
+        check_name(new_name, "a project")?;
+        let project = self.find_project(name)?;
+        if project.name == DEFAULT_PROJECT {
+            return Err(EnvnError::Parse(format!(
+                "The {} project can not be renamed",
+                DEFAULT_PROJECT
+            )));
+        }
+        if db::get_project_by_name(&self.conn, new_name)?.is_some() {
+            return Err(EnvnError::Parse(format!(
+                "Project {} already exists",
+                new_name
+            )));
+        }
+
+        db::rename_project(&self.conn, project.id, new_name)?;
+        if project.id == self.project.id {
+            self.project.name = new_name.to_string();
+        }
+        Ok(())
+    }
+
+    /// Deletes a project together with all of its secrets.
+    /// If it was selected, the default project is selected instead.
+    ///
+    /// # Returns
+    ///
+    /// The number of deleted secrets.
+    pub fn delete_project(&mut self, name: &str) -> Result<usize> {
+        let project = self.find_project(name)?;
+        if project.name == DEFAULT_PROJECT {
+            return Err(EnvnError::Parse(format!(
+                "The {} project can not be deleted",
+                DEFAULT_PROJECT
+            )));
+        }
+
+        let count = db::count_entries(&self.conn, project.id)?;
+        db::delete_project(&mut self.conn, project.id)?;
+        if project.id == self.project.id {
+            self.select_project(DEFAULT_PROJECT)?;
+        }
+        Ok(count)
+    }
+
+    /// Looks up a project by its name.
+    fn find_project(&self, name: &str) -> Result<Project> {
+        db::get_project_by_name(&self.conn, name)?
+            .ok_or(EnvnError::NotFound(format!("Project {}", name)))
+    }
+
+    /// Returns the decrypted secret with the given name,
+    /// from the environment or the closest one it inherits from.
+    ///
+    /// # Returns
+    ///
+    /// The secret, a `NotFound` error if there is none with this name,
+    /// or a `Crypto` error if it failed the integrity check.
+    pub fn get(&self, name: &str) -> Result<DisplayEnv> {
+        for layer in self.chain(&self.environment)? {
+            if let Some(entry) = db::get_by_name(&self.conn, layer.id, name)? {
+                let mut env = decrypt_struct(entry, &self.keyring)?;
+                env.metadata = db::get_metadata(&self.conn, layer.id, name)?.unwrap_or_default();
+                return Ok(env);
+            }
+        }
+        Err(EnvnError::NotFound(format!("Secret {}", name)))
+    }
+
+    /// Encrypts and stores a secret in the environment, replacing any secret with the same name.
+    /// An inherited secret with the same name is overridden, not changed.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The identifier of the secret.
+    /// * `key` - The environment variable it is exported as.
+    /// * `value` - The value of the secret.
+    pub fn set(&self, name: &str, key: &str, value: &str) -> Result<()> {
+        let env = construct_struct(
+            self.project.id,
+            self.environment.id,
+            name.to_string(),
+            key.to_string(),
+            value.to_string(),
+            None,
+            &self.keyring,
+        )?;
+        db::insert_env(&self.conn, env, &self.keyring)
+    }
+
+    /// Changes the description, tags, owner, URL or expiry of a secret in the environment.
+    ///
+    /// # Returns
+    ///
+    /// A `NotFound` error if the secret is not set in the environment itself,
+    /// or a `Parse` error if a tag holds a comma, which separates the stored tags.
+    pub fn update_metadata(&self, name: &str, update: &MetadataUpdate) -> Result<()> {
+        if let Some(tag) = update.tags.iter().flatten().find(|tag| tag.contains(',')) {
+            return Err(EnvnError::Parse(format!(
+                "The tag {} holds a comma, which separates tags",
+                tag
+            )));
+        }
+        let mut metadata =
+            db::get_metadata(&self.conn, self.environment.id, name)?.ok_or(EnvnError::NotFound(
+                format!("Secret {} in environment {}", name, self.environment.name),
+            ))?;
+        update.apply(&mut metadata);
+        db::set_metadata(&self.conn, self.environment.id, name, &metadata)?;
+        Ok(())
+    }
+
+    /// Checks if a secret with the given name is set in the environment itself,
+    /// inherited secrets do not count.
+    pub fn exists(&self, name: &str) -> Result<bool> {
+        db::does_exist(&self.conn, self.environment.id, name)
+    }
+
+    /// Returns all the decrypted secrets of the environment, inherited ones included.
+    /// Secrets that fail the integrity check are skipped with a warning.
+    pub fn list(&self) -> Result<Vec<DisplayEnv>> {
+        Ok(self
+            .resolve(&self.environment)?
+            .into_iter()
+            .map(|(env, _)| env)
+            .collect())
+    }
+
+    /// Deletes the secret with the given name from the environment.
+    /// An inherited secret with the same name shows through again.
+    ///
+    /// # Returns
+    ///
+    /// A `NotFound` error if there is no secret with this name.
+    pub fn delete(&self, name: &str) -> Result<()> {
+        if !self.exists(name)? {
+            return Err(EnvnError::NotFound(format!("Secret {}", name)));
+        }
+        db::delete_entry_by_name(&self.conn, self.environment.id, name)
+    }
+
+    /// Returns every version of a secret set in the environment, oldest first.
+    ///
+    /// # Returns
+    ///
+    /// The versions, a `NotFound` error if the secret never existed in the environment,
+    /// or a `Crypto` error if any version failed the integrity check.
+    pub fn history(&self, name: &str) -> Result<Vec<Version>> {
+        let versions = db::get_versions(&self.conn, self.environment.id, name)?;
+        if versions.is_empty() {
+            return Err(EnvnError::NotFound(format!("History of secret {}", name)));
+        }
+
+        versions
+            .into_iter()
+            .map(|version| {
+                Ok(Version {
+                    version: version.version,
+                    change: version.change,
+                    created_at: version.created_at,
+                    restored_from: version.restored_from,
+                    secret: version
+                        .entry
+                        .map(|entry| decrypt_struct(entry, &self.keyring))
+                        .transpose()?,
+                })
+            })
+            .collect()
+    }
+
+    /// Restores the key and value a secret had in an older version.
+    /// The rollback is recorded as a new version, so it can be undone as well.
+    ///
+    /// # Returns
+    ///
+    /// The number of the new version, or a `NotFound` error if there is no such version.
+    pub fn rollback(&self, name: &str, version: u32) -> Result<u32> {
+        let old = self
+            .history(name)?
+            .into_iter()
+            .find(|old| old.version == version)
+            .ok_or(EnvnError::NotFound(format!(
+                "Version {} of secret {}",
+                version, name
+            )))?;
+        let secret = old.secret.ok_or(EnvnError::Parse(format!(
+            "Version {} of secret {} is its deletion, delete it instead",
+            version, name
+        )))?;
+
+        let env = construct_struct(
+            self.project.id,
+            self.environment.id,
+            secret.name,
+            secret.key,
+            secret.value,
+            None,
+            &self.keyring,
+        )?;
+        db::restore_env(&self.conn, env, version, &self.keyring)
+    }
+
+    /// Records in the audit log that a command read or changed a secret.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command, like `get` or `delete`.
+    /// * `name` - The secret, `None` if the command worked on all of them.
+    pub fn audit(&self, command: &str, name: Option<&str>) -> Result<()> {
+        audit::append(
+            &self.conn,
+            &self.keyring,
+            command,
+            name,
+            &self.project.name,
+            &self.environment.name,
+        )
+    }
+
+    /// Returns the rows of the audit log that match the filter, oldest first.
+    pub fn audit_log(&self, filter: &audit::Filter) -> Result<Vec<audit::Record>> {
+        audit::read(&self.conn, filter)
+    }
+
+    /// Checks that no row of the audit log was changed, added or removed.
+    ///
+    /// # Returns
+    ///
+    /// The number of rows, or a `Crypto` error naming the first row that breaks the chain.
+    pub fn verify_audit(&self) -> Result<usize> {
+        audit::verify(&self.conn, &self.keyring)
+    }
+
+    /// The recipient of the identity of the store, which others share secrets with.
+    ///
+    /// # Returns
+    ///
+    /// The recipient, or `None` if the store has no identity yet.
+    pub fn recipient(&self) -> Result<Option<Recipient>> {
+        db::get_identity(&self.conn)?
+            .map(|entry| entry.recipient.parse())
+            .transpose()
+    }
+
+    /// The identity of the store, which secrets are shared and received with.
+    ///
+    /// # Returns
+    ///
+    /// The identity, or a `NotFound` error if the store has none yet.
+    pub fn identity(&self) -> Result<Identity> {
+        let entry = db::get_identity(&self.conn)?
+            .ok_or(EnvnError::NotFound("Your identity".to_string()))?;
+        identity::decrypt_identity(&entry, &self.keyring)
+    }
+
+    /// Generates a new identity for the store, replacing the one it had.
+    /// Bundles shared with the old one can not be received anymore.
+    ///
+    /// # Returns
+    ///
+    /// The recipient of the new identity.
+    pub fn create_identity(&self) -> Result<Recipient> {
+        let identity = Identity::generate();
+        db::set_identity(
+            &self.conn,
+            &identity::encrypt_identity(&identity, &self.keyring)?,
+        )?;
+        Ok(identity.recipient())
+    }
+
+    /// What the store trusted of the vault file at `path` when it last used it.
+    ///
+    /// # Returns
+    ///
+    /// The trust, or `None` if the store never used the file.
+    pub fn vault_trust(&self, path: &Path) -> Result<Option<Trust>> {
+        db::get_vault_trust(&self.conn, &path.to_string_lossy())?
+            .map(|(recipients, sealed_by)| {
+                Ok(Trust {
+                    recipients: recipients
+                        .split_whitespace()
+                        .map(str::parse)
+                        .collect::<Result<_>>()?,
+                    sealed_by: sealed_by.parse()?,
+                })
+            })
+            .transpose()
+    }
+
+    /// Trusts the vault file at `path` as it is now, the next time it is used
+    /// its recipients and who sealed its data key are compared with `trust`.
+    pub fn trust_vault_file(&self, path: &Path, trust: &Trust) -> Result<()> {
+        let recipients: Vec<String> = trust.recipients.iter().map(|r| r.to_string()).collect();
+        db::set_vault_trust(
+            &self.conn,
+            &path.to_string_lossy(),
+            &recipients.join(" "),
+            &trust.sealed_by.to_string(),
+        )
+    }
+
+    /// Packs secrets into a bundle only `recipient` can open, see `share::pack`.
+    /// Secrets are looked up like `get` does.
+    pub fn share(&self, names: &[&str], recipient: &Recipient) -> Result<String> {
+        let secrets = names
+            .iter()
+            .map(|name| {
+                self.get(name).map(|env| SharedSecret {
+                    name: env.name,
+                    key: env.key,
+                    value: env.value,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        share::pack(&self.identity()?, recipient, &secrets)
+    }
+
+    /// Opens a bundle shared with the identity of the store, see `share::unpack`.
+    /// Nothing is stored yet.
+    pub fn open_bundle(&self, bundle: &str) -> Result<(Recipient, Vec<SharedSecret>)> {
+        share::unpack(&self.identity()?, bundle)
+    }
+
+    /// Returns the secrets with the given names as environment variables.
+    ///
+    /// # Returns
+    ///
+    /// The keys and values, in the order of `names`, or an error if any of them is missing.
+    pub fn env_vars(&self, names: &[&str]) -> Result<Vec<(String, String)>> {
+        names
+            .iter()
+            .map(|name| self.get(name).map(|env| (env.key, env.value)))
+            .collect()
+    }
+
+    /// Formats the secrets with the given names as the lines of a `.env` file.
+    /// Values are quoted and escaped where needed, so that they load back unchanged.
+    ///
+    /// # Returns
+    ///
+    /// The `KEY=value` lines, in the order of `names`, or an error if any of them is missing.
+    pub fn export(&self, names: &[&str]) -> Result<String> {
+        dotenv::write(&self.env_vars(names)?)
+    }
+
+    /// Checks the integrity of every secret, in every project.
+    ///
+    /// # Returns
+    ///
+    /// The total number of secrets, and the names of the ones that failed the check.
+    pub fn verify(&self) -> Result<(usize, Vec<String>)> {
+        let entries = db::get_all_entries(&self.conn)?;
+        let total = entries.len();
+        let mut failed = Vec::new();
+
+        for entry in entries {
+            let name = entry.name.clone();
+            if decrypt_struct(entry, &self.keyring).is_err() {
+                failed.push(name);
+            }
+        }
+        Ok((total, failed))
+    }
+
+    /// Changes the master password, keeping all the secrets.
+    pub fn change_password(&mut self, password: &str) -> Result<()> {
+        self.keyring = file::change_password(&self.dir, password, self.kdf, &self.keyring)?;
+        Ok(())
+    }
+
+    /// Re-encrypts every secret, in every project, under a fresh key.
+    ///
+    /// The old key stays in the keyring until every row was re-encrypted,
+    /// so that a crash at any point leaves every row readable.
+    ///
+    /// # Returns
+    ///
+    /// The version of the new key, and the number of re-encrypted secrets.
+    pub fn rotate_key(&mut self) -> Result<(u32, usize)> {
+        let mut keyring = self.keyring.clone();
+        let version = keyring.add(crate::encryption::get_key());
+        file::write_keyring(&self.dir, &keyring)?;
+
+        let count = db::reencrypt_all(&mut self.conn, &keyring)?;
+
+        keyring.retain_current();
+        file::write_keyring(&self.dir, &keyring)?;
+        self.keyring = keyring;
+        Ok((version, count))
+    }
+
+    /// Backs up the database, keyring and password hash to a tar in the `backups` directory of the store.
+    pub fn backup(&self, name: &str) -> Result<()> {
+        file::compress(&self.dir, name)
+    }
+
+    /// Restores the database, keyring and password hash from a tar in the `backups` directory
+    /// of the store, so the password is the one the store had when the backup was made.
+    /// The vault is closed, open it again to use the restored secrets.
+    pub fn restore(self, name: &str) -> Result<()> {
+        let dir = self.dir.clone();
+        if !dir.join("backups").join(name).exists() {
+            return Err(EnvnError::NotFound(format!("Backup file {}", name)));
+        }
+        drop(self);
+        file::decompress(&dir, name)
+    }
+}
+
+/// Looks up the base environment of a project
+fn base_environment(conn: &Connection, project: &Project) -> Result<Environment> {
+    db::get_environment_by_name(conn, project.id, BASE_ENVIRONMENT)?.ok_or(EnvnError::NotFound(
+        format!(
+            "Environment {} in project {}",
+            BASE_ENVIRONMENT, project.name
+        ),
+    ))
+}
+
+/// Checks that the name of a project or environment only uses letters, digits, `_`, `.` and `-`
+///
+/// # Arguments
+///
+/// * `name` - The name to check
+/// * `what` - What is named, for the error message
+fn check_name(name: &str, what: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+    match valid {
+        true => Ok(()),
+        false => Err(EnvnError::Parse(format!(
+            "{} can not be used as the name of {}, use letters, digits, _, . and -",
+            name, what
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Difference, Vault};
+    use crate::{
+        db::Change,
+        encryption::{encrypt, get_nonce, KdfParams},
+        error::EnvnError,
+        identity::Identity,
+        share::SharedSecret,
+        utils::{now, parse_time, MetadataUpdate},
+        vaultfile::VaultFile,
+    };
+
+    // keep the tests fast, the real defaults are much more expensive
+    const KDF: KdfParams = KdfParams {
+        m_cost: 1024,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    #[test]
+    fn test_vault_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = Vault::create_with_kdf(dir.path(), "hunter2", KDF).unwrap();
+        vault
+            .set("db", "DATABASE_URL", "postgres://localhost")
+            .unwrap();
+        vault.set("token", "API_TOKEN", "abc=123").unwrap();
+        drop(vault);
+
+        assert!(matches!(
+            Vault::open_with_kdf(dir.path(), "hunter3", KDF),
+            Err(EnvnError::Auth(_))
+        ));
+
+        let vault = Vault::open_with_kdf(dir.path(), "hunter2", KDF).unwrap();
+        assert_eq!(vault.get("token").unwrap().value, "abc=123");
+        assert_eq!(vault.list().unwrap().len(), 2);
+        assert_eq!(
+            vault.export(&["token", "db"]).unwrap(),
+            "API_TOKEN=abc=123\nDATABASE_URL=postgres://localhost\n"
+        );
+
+        vault.delete("db").unwrap();
+        assert!(matches!(vault.get("db"), Err(EnvnError::NotFound(_))));
+        assert!(matches!(vault.delete("db"), Err(EnvnError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_keyring_migration_and_loss() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = Vault::create_with_kdf(dir.path(), "hunter2", KDF).unwrap();
+        vault.set("db", "DATABASE_URL", "one").unwrap();
+        let (_, key) = vault.keyring.current();
+        drop(vault);
+
+        // a store of an older version, with the key in a plaintext file
+        std::fs::remove_file(dir.path().join("keyring")).unwrap();
+        std::fs::write(dir.path().join("key"), key).unwrap();
+        let vault = Vault::open_with_kdf(dir.path(), "hunter2", KDF).unwrap();
+        assert_eq!(vault.get("db").unwrap().value, "one");
+        assert!(!dir.path().join("key").exists());
+        assert!(dir.path().join("keyring").exists());
+        drop(vault);
+        let vault = Vault::open_with_kdf(dir.path(), "hunter2", KDF).unwrap();
+        assert_eq!(vault.get("db").unwrap().value, "one");
+        drop(vault);
+
+        // a lost keyring is not replaced while there are secrets it encrypted
+        std::fs::remove_file(dir.path().join("keyring")).unwrap();
+        assert!(matches!(
+            Vault::open_with_kdf(dir.path(), "hunter2", KDF),
+            Err(EnvnError::Crypto(_))
+        ));
+        assert!(!dir.path().join("keyring").exists());
+
+        let empty = tempfile::tempdir().unwrap();
+        drop(Vault::create_with_kdf(empty.path(), "hunter2", KDF).unwrap());
+        std::fs::remove_file(empty.path().join("keyring")).unwrap();
+        assert!(Vault::open_with_kdf(empty.path(), "hunter2", KDF).is_ok());
+    }
+
+    #[test]
+    fn test_moved_values_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = Vault::create_with_kdf(dir.path(), "hunter2", KDF).unwrap();
+        vault
+            .set("db", "DATABASE_URL", "postgres://localhost")
+            .unwrap();
+        vault.set("token", "API_TOKEN", "abc=123").unwrap();
+        assert_eq!(vault.verify().unwrap(), (2, vec![]));
+
+        // copy the encrypted value of one row over another, as someone with the file could
+        vault
+            .conn
+            .execute(
+                "UPDATE envs SET (value, nonce) =
+                    (SELECT value, nonce FROM envs WHERE name = 'token')
+                    WHERE name = 'db'",
+                [],
+            )
+            .unwrap();
+        assert_eq!(vault.verify().unwrap(), (2, vec!["db".to_string()]));
+        assert!(matches!(vault.get("db"), Err(EnvnError::Crypto(_))));
+        assert_eq!(vault.get("token").unwrap().value, "abc=123");
+    }
+
+    #[test]
+    fn test_migrate_global_nonce_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = Vault::create_with_kdf(dir.path(), "hunter2", KDF).unwrap();
+        let (_, key) = vault.keyring.current();
+        drop(vault);
+
+        // a database of the first version, every value encrypted with the global nonce
+        std::fs::remove_file(dir.path().join("env.db")).unwrap();
+        let nonce = get_nonce();
+        std::fs::write(dir.path().join("nonce"), nonce).unwrap();
+        let conn = rusqlite::Connection::open(dir.path().join("env.db")).unwrap();
+        conn.execute(
+            "CREATE TABLE envs (
+                id INTEGER PRIMARY KEY,
+                name TEXT UNIQUE NOT NULL,
+                key TEXT NOT NULL,
+                value BLOB NOT NULL
+            )",
+            [],
+        )
+        .unwrap();
+        for (name, env_key, value) in [("db", "DATABASE_URL", "one"), ("token", "TOKEN", "two")] {
+            let value = bincode::serialize(value).unwrap();
+            let value = encrypt(key, nonce, &value, &[]).unwrap();
+            conn.execute(
+                "INSERT INTO envs (name, key, value) VALUES (?1, ?2, ?3)",
+                rusqlite::params![name, env_key, value],
+            )
+            .unwrap();
+        }
+        drop(conn);
+
+        let vault = Vault::open_with_kdf(dir.path(), "hunter2", KDF).unwrap();
+        assert_eq!(vault.get("db").unwrap().value, "one");
+        assert_eq!(vault.get("token").unwrap().key, "TOKEN");
+        assert_eq!(vault.verify().unwrap(), (2, vec![]));
+        assert!(!dir.path().join("nonce").exists());
+    }
+
+    #[test]
+    fn test_interrupted_password_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vault = Vault::create_with_kdf(dir.path(), "old", KDF).unwrap();
+        vault.set("db", "DATABASE_URL", "one").unwrap();
+        let read = |name: &str| std::fs::read(dir.path().join(name)).unwrap();
+        let (old_keyring, old_auth) = (read("keyring"), read("auth"));
+        vault.change_password("new").unwrap();
+        let (new_keyring, new_auth) = (read("keyring"), read("auth"));
+        drop(vault);
+
+        // the files as they are at every point a crash could stop the change at,
+        // and the password that works afterwards
+        let points = [
+            (vec![("keyring.new", &new_keyring)], "old"),
+            (
+                vec![("keyring.new", &new_keyring), ("auth.new", &new_auth)],
+                "old",
+            ),
+            (
+                vec![("keyring", &new_keyring), ("auth.new", &new_auth)],
+                "new",
+            ),
+            (vec![("keyring", &new_keyring), ("auth", &new_auth)], "new"),
+        ];
+        for (files, password) in points {
+            std::fs::write(dir.path().join("keyring"), &old_keyring).unwrap();
+            std::fs::write(dir.path().join("auth"), &old_auth).unwrap();
+            for (name, content) in files {
+                std::fs::write(dir.path().join(name), content).unwrap();
+            }
+
+            let wrong = match password {
+                "old" => "new",
+                _ => "old",
+            };
+            assert!(matches!(
+                Vault::open_with_kdf(dir.path(), wrong, KDF),
+                Err(EnvnError::Auth(_))
+            ));
+            let vault = Vault::open_with_kdf(dir.path(), password, KDF).unwrap();
+            assert_eq!(vault.get("db").unwrap().value, "one");
+            assert!(!dir.path().join("keyring.new").exists());
+            assert!(!dir.path().join("auth.new").exists());
+        }
+    }
+
+    #[test]
+    fn test_restore_after_password_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vault = Vault::create_with_kdf(dir.path(), "old", KDF).unwrap();
+        vault.set("db", "DATABASE_URL", "one").unwrap();
+        vault.backup("before.tar").unwrap();
+        vault.change_password("new").unwrap();
+        vault.set("db", "DATABASE_URL", "two").unwrap();
+        vault.restore("before.tar").unwrap();
+
+        assert!(Vault::open_with_kdf(dir.path(), "new", KDF).is_err());
+        let vault = Vault::open_with_kdf(dir.path(), "old", KDF).unwrap();
+        assert_eq!(vault.get("db").unwrap().value, "one");
+    }
+
+    #[test]
+    fn test_projects_keep_secrets_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vault = Vault::create_with_kdf(dir.path(), "hunter2", KDF).unwrap();
+        vault.set("db", "DATABASE_URL", "default db").unwrap();
+
+        let app = dir.path().join("code").join("app");
+        vault.create_project("app", Some(&app)).unwrap();
+        assert!(vault.create_project("app", None).is_err());
+        assert!(vault.create_project("not a name", None).is_err());
+        assert_eq!(
+            vault.project_for_dir(&app.join("src")).unwrap().as_deref(),
+            Some("app")
+        );
+        assert_eq!(vault.project_for_dir(dir.path()).unwrap(), None);
+
+        vault.select_project("app").unwrap();
+        assert!(matches!(vault.get("db"), Err(EnvnError::NotFound(_))));
+        vault.set("db", "DATABASE_URL", "app db").unwrap();
+
+        vault.rename_project("app", "web").unwrap();
+        assert_eq!(vault.project(), "web");
+        assert_eq!(vault.get("db").unwrap().value, "app db");
+        assert_eq!(vault.verify().unwrap(), (2, vec![]));
+
+        assert_eq!(vault.delete_project("web").unwrap(), 1);
+        assert_eq!(vault.project(), "default");
+        assert_eq!(vault.get("db").unwrap().value, "default db");
+        assert!(vault.delete_project("default").is_err());
+    }
+
+    #[test]
+    fn test_environments_inherit() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vault = Vault::create_with_kdf(dir.path(), "hunter2", KDF).unwrap();
+        vault
+            .set("db", "DATABASE_URL", "postgres://localhost")
+            .unwrap();
+        vault.set("log", "LOG_LEVEL", "info").unwrap();
+        vault.set("port", "PORT", "8080").unwrap();
+
+        vault.create_environment("staging", None).unwrap();
+        vault.create_environment("prod", Some("staging")).unwrap();
+        vault.create_environment("dev", None).unwrap();
+        assert!(vault.delete_environment("staging").is_err());
+
+        vault.select_environment("staging").unwrap();
+        vault
+            .set("db", "DATABASE_URL", "postgres://staging")
+            .unwrap();
+        vault.set("port", "PORT", "8080").unwrap();
+        vault.set("sentry", "SENTRY_DSN", "https://sentry").unwrap();
+        vault.select_environment("prod").unwrap();
+        vault.set("log", "LOG_LEVEL", "warn").unwrap();
+
+        assert_eq!(vault.get("db").unwrap().value, "postgres://staging");
+        assert_eq!(vault.get("log").unwrap().value, "warn");
+        assert_eq!(
+            vault.export(&["db", "log", "port"]).unwrap(),
+            "DATABASE_URL=postgres://staging\nLOG_LEVEL=warn\nPORT=8080\n"
+        );
+        assert!(!vault.exists("db").unwrap());
+        assert!(vault.delete("db").is_err());
+
+        let diff = vault.diff("dev", "prod").unwrap();
+        assert_eq!(
+            diff,
+            vec![
+                ("db".to_string(), Difference::Differs),
+                ("log".to_string(), Difference::Differs),
+                ("port".to_string(), Difference::Overridden),
+                ("sentry".to_string(), Difference::OnlyIn("prod".to_string())),
+            ]
+        );
+
+        assert_eq!(vault.delete_environment("prod").unwrap(), 1);
+        assert_eq!(vault.environment(), "base");
+        assert!(vault.delete_environment("base").is_err());
+    }
+
+    #[test]
+    fn test_history_and_rollback() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vault = Vault::create_with_kdf(dir.path(), "hunter2", KDF).unwrap();
+        vault.set("db", "DATABASE_URL", "one").unwrap();
+        vault.set("db", "DATABASE_URL", "two").unwrap();
+        vault.delete("db").unwrap();
+        assert!(vault.rollback("db", 3).is_err());
+        assert_eq!(vault.rollback("db", 1).unwrap(), 4);
+        assert_eq!(vault.get("db").unwrap().value, "one");
+
+        vault.rotate_key().unwrap();
+        let history: Vec<_> = vault
+            .history("db")
+            .unwrap()
+            .into_iter()
+            .map(|v| (v.version, v.change, v.secret.map(|env| env.value)))
+            .collect();
+        assert_eq!(
+            history,
+            vec![
+                (1, Change::Create, Some("one".to_string())),
+                (2, Change::Update, Some("two".to_string())),
+                (3, Change::Delete, None),
+                (4, Change::Rollback, Some("one".to_string())),
+            ]
+        );
+        assert!(matches!(vault.history("nope"), Err(EnvnError::NotFound(_))));
+
+        // versions can not be swapped, nor copied back as the current value
+        let copy = |sql: &str| vault.conn.execute(sql, []).unwrap();
+        copy(
+            "UPDATE versions SET (value, nonce) =
+            (SELECT value, nonce FROM versions WHERE version = 2) WHERE version = 1",
+        );
+        assert!(matches!(vault.history("db"), Err(EnvnError::Crypto(_))));
+        copy(
+            "UPDATE envs SET (value, nonce) =
+            (SELECT value, nonce FROM versions WHERE version = 4)",
+        );
+        assert!(matches!(vault.get("db"), Err(EnvnError::Crypto(_))));
+    }
+
+    #[test]
+    fn test_rotate_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vault = Vault::create_with_kdf(dir.path(), "hunter2", KDF).unwrap();
+        vault.set("db", "DATABASE_URL", "one").unwrap();
+        vault.set("db", "DATABASE_URL", "two").unwrap();
+        vault.create_environment("prod", None).unwrap();
+        vault.select_environment("prod").unwrap();
+        vault.set("db", "DATABASE_URL", "prod").unwrap();
+        vault.create_project("api", None).unwrap();
+        vault.select_project("api").unwrap();
+        vault.set("token", "API_TOKEN", "abc").unwrap();
+        let recipient = vault.create_identity().unwrap();
+
+        vault.backup("pre_rotation.tar").unwrap();
+        assert_eq!(vault.rotate_key().unwrap(), (2, 3));
+
+        // every row is encrypted with the new key, and only the new key is left
+        for table in ["envs", "versions", "identity"] {
+            let sql = format!("SELECT DISTINCT key_version FROM {}", table);
+            let versions: Vec<u32> = vault
+                .conn
+                .prepare(&sql)
+                .unwrap()
+                .query_map([], |row| row.get(0))
+                .unwrap()
+                .collect::<rusqlite::Result<_>>()
+                .unwrap();
+            assert_eq!(versions, [2], "{}", table);
+        }
+        drop(vault);
+
+        let mut vault = Vault::open_with_kdf(dir.path(), "hunter2", KDF).unwrap();
+        assert!(vault.keyring.get(1).is_none());
+        assert_eq!(vault.keyring.current().0, 2);
+        assert_eq!(vault.verify().unwrap(), (3, vec![]));
+        assert_eq!(vault.history("db").unwrap().len(), 2);
+        vault.select_environment("prod").unwrap();
+        assert_eq!(vault.get("db").unwrap().value, "prod");
+        assert_eq!(vault.history("db").unwrap().len(), 1);
+        assert_eq!(vault.identity().unwrap().recipient(), recipient);
+
+        // the backup holds the old key together with the old rows
+        vault.restore("pre_rotation.tar").unwrap();
+        let vault = Vault::open_with_kdf(dir.path(), "hunter2", KDF).unwrap();
+        assert_eq!(vault.keyring.current().0, 1);
+        assert_eq!(vault.verify().unwrap(), (3, vec![]));
+        assert_eq!(vault.get("db").unwrap().value, "two");
+        assert_eq!(vault.identity().unwrap().recipient(), recipient);
+    }
+
+    #[test]
+    fn test_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = Vault::create_with_kdf(dir.path(), "hunter2", KDF).unwrap();
+        vault.set("aws", "AWS_SECRET", "one").unwrap();
+        let update = MetadataUpdate {
+            description: Some("The deploy user".to_string()),
+            tags: Some(vec!["aws".to_string(), " deploy ".to_string()]),
+            expires_at: Some("2020-01-01 00:00:00".to_string()),
+            ..Default::default()
+        };
+        vault.update_metadata("aws", &update).unwrap();
+
+        // setting the value again keeps the metadata
+        vault.set("aws", "AWS_SECRET", "two").unwrap();
+        let metadata = vault.get("aws").unwrap().metadata;
+        assert_eq!(metadata.description.as_deref(), Some("The deploy user"));
+        assert_eq!(metadata.tags, vec!["aws", "deploy"]);
+        assert!(metadata.created_at.is_some() && metadata.updated_at.is_some());
+        assert!(metadata.expires_before(now()));
+        assert!(!metadata.expires_before(parse_time("2019-12-31").unwrap()));
+
+        let clear = MetadataUpdate {
+            description: Some(String::new()),
+            ..Default::default()
+        };
+        vault.update_metadata("aws", &clear).unwrap();
+        let listed = vault.list().unwrap().remove(0).metadata;
+        assert_eq!(listed.description, None);
+        assert!(listed.has_tag("deploy"));
+        assert!(matches!(
+            vault.update_metadata("nope", &clear),
+            Err(EnvnError::NotFound(_))
+        ));
+
+        // a comma would split the tag in two once it is read back
+        let comma = MetadataUpdate {
+            tags: Some(vec!["aws,deploy".to_string()]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            vault.update_metadata("aws", &comma),
+            Err(EnvnError::Parse(_))
+        ));
+        assert_eq!(
+            vault.get("aws").unwrap().metadata.tags,
+            vec!["aws", "deploy"]
+        );
+    }
+
+    #[test]
+    fn test_vault_trust() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = Vault::create_with_kdf(dir.path(), "hunter2", KDF).unwrap();
+        let path = dir.path().join(".envn.vault");
+        let secret = |value: &str| SharedSecret {
+            name: "db".to_string(),
+            key: "DATABASE_URL".to_string(),
+            value: value.to_string(),
+        };
+
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let mut file = VaultFile::new(&[alice.recipient(), bob.recipient()]);
+        file.set(secret("postgres://localhost")).unwrap();
+        let text = file.render(&bob).unwrap();
+        let trust = VaultFile::open(&text, &alice).unwrap().trust().unwrap();
+        assert_eq!(vault.vault_trust(&path).unwrap(), None);
+        vault.trust_vault_file(&path, &trust).unwrap();
+        assert_eq!(vault.vault_trust(&path).unwrap(), Some(trust.clone()));
+
+        // a forger who lists themselves as a recipient gets past `open`, but not past the trust
+        let mallory = Identity::generate();
+        let mut forged = VaultFile::new(&[alice.recipient(), bob.recipient(), mallory.recipient()]);
+        forged.set(secret("postgres://mallory")).unwrap();
+        let forged = forged.render(&mallory).unwrap();
+        let opened = VaultFile::open(&forged, &alice).unwrap();
+        let changes = trust.changes(&opened.trust().unwrap());
+        assert_eq!(
+            changes,
+            [
+                format!(
+                    "The data key was sealed to you by {} instead of {}",
+                    mallory.recipient(),
+                    bob.recipient()
+                ),
+                format!("{} was added as a recipient", mallory.recipient()),
+            ]
+        );
+
+        // so does one who seals the key to us without changing the recipients
+        let mut forged = VaultFile::new(&[alice.recipient(), bob.recipient()]);
+        forged.set(secret("postgres://alice")).unwrap();
+        let forged = forged.render(&alice).unwrap();
+        let opened = VaultFile::open(&forged, &alice).unwrap();
+        assert_eq!(trust.changes(&opened.trust().unwrap()).len(), 1);
+
+        // files the store wrote itself keep their trust
+        let mut file = VaultFile::open(&text, &alice).unwrap();
+        file.set(secret("postgres://db")).unwrap();
+        let text = file.render(&alice).unwrap();
+        let opened = VaultFile::open(&text, &alice).unwrap();
+        assert!(trust.changes(&opened.trust().unwrap()).is_empty());
+    }
+}
@@ -0,0 +1,663 @@
+/// This file reads and writes the `.envn.vault` file, which keeps the secrets of a
+/// repository encrypted inside the repository itself, so that they can be committed
+/// Every secret is encrypted with the data key of the file, and the data key is sealed
+/// to the recipient of everyone who may read it
+///
+/// The file is text, with one sorted line per recipient and per secret, so that a git
+/// diff shows which secrets changed, but not their values:
+///
+/// ```text
+/// envn-vault 1
+/// recipient envn:o7w4fvqh... <the data key, sealed to the recipient>
+/// secret db DATABASE_URL <the value, encrypted with the data key>
+/// ```
+///
+/// A value that did not change is encrypted the same way again, so its line stays the same
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use aes_gcm::{aead::Nonce, Aes256Gcm, Key};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::{
+    encryption::{decrypt, encrypt, get_key},
+    error::{EnvnError, Result},
+    identity::{Identity, Recipient, Sealed},
+    share::SharedSecret,
+};
+
+/// The name of the vault file
+pub const VAULT_FILE: &str = ".envn.vault";
+
+/// The first line of every vault file, with the version of the format
+const HEADER: &str = "envn-vault 1";
+
+/// What data keys are sealed for, so that nothing sealed for anything else opens as one
+const CONTEXT: &str = "envn vault v1";
+
+/// The decrypted contents of a vault file
+#[derive(Clone)]
+pub struct VaultFile {
+    key: Key<Aes256Gcm>,
+    /// The recipients by their text, with the data key sealed to them as written in the file,
+    /// or `None` if it still has to be sealed
+    recipients: BTreeMap<String, Option<String>>,
+    secrets: BTreeMap<String, SharedSecret>,
+    /// Who sealed the data key to the identity the file was opened with, `None` for a new file
+    sealed_by: Option<Recipient>,
+}
+
+/// What can be read from a vault file without being one of its recipients
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listing {
+    /// The recipients, each with who sealed the data key to them
+    /// Who sealed it is only proven once the recipient opens the file
+    pub recipients: Vec<(Recipient, Recipient)>,
+    /// The names and keys of the secrets
+    pub secrets: Vec<(String, String)>,
+}
+
+/// A secret both sides of a merge changed, each in another way
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub name: String,
+    /// What our side did to the secret
+    pub ours: Change,
+    /// What their side did to the secret
+    pub theirs: Change,
+}
+
+/// What one side of a merge did to a secret
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Added,
+    Changed,
+    Deleted,
+}
+
+/// Who can read a vault file and who sealed its data key to us, as the store last accepted it
+///
+/// Anyone who can write to the repository can seal a key of their own to us and list
+/// themselves as a recipient, so a file is compared with its trust before it is used
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trust {
+    /// The recipients, sorted
+    pub recipients: Vec<Recipient>,
+    pub sealed_by: Recipient,
+}
+
+/// The lines of a vault file, still encrypted
+struct Lines {
+    recipients: BTreeMap<String, String>,
+    /// The keys and encrypted values of the secrets, by their name
+    secrets: BTreeMap<String, (String, String)>,
+}
+
+impl VaultFile {
+    /// Creates an empty vault file, with a fresh data key.
+    pub fn new(recipients: &[Recipient]) -> Self {
+        VaultFile {
+            key: get_key(),
+            recipients: recipients.iter().map(|r| (r.to_string(), None)).collect(),
+            secrets: BTreeMap::new(),
+            sealed_by: None,
+        }
+    }
+
+    /// Opens a vault file with the identity of one of its recipients.
+    ///
+    /// # Returns
+    ///
+    /// The contents, a `Parse` error if it is no vault file, an `Auth` error if
+    /// `identity` is not one of its recipients or the data key was sealed to it by
+    /// someone who is not one either, or a `Crypto` error if it was tampered with.
+    pub fn open(text: &str, identity: &Identity) -> Result<Self> {
+        let lines = parse(text)?;
+        let sealed = lines
+            .recipients
+            .get(&identity.recipient().to_string())
+            .ok_or(EnvnError::Auth(format!(
+                "{} is not a recipient of the vault file",
+                identity.recipient()
+            )))?;
+        let sealed = decode_sealed(sealed)?;
+        let key: [u8; 32] = identity
+            .open(&sealed, CONTEXT)?
+            .try_into()
+            .map_err(|_| EnvnError::Crypto("The data key is malformed".to_string()))?;
+        let key = Key::<Aes256Gcm>::from(key);
+
+        // anyone who can write to the repository could seal a key of their own to us,
+        // listing themselves as a recipient only gets past this check, not past `Trust`
+        let sender = sealed.sender();
+        if !lines.recipients.contains_key(&sender.to_string()) {
+            return Err(EnvnError::Auth(format!(
+                "The data key was sealed by {}, who is not a recipient of the vault file",
+                sender
+            )));
+        }
+
+        let secrets = lines
+            .secrets
+            .into_iter()
+            .map(|(name, (env_key, encrypted))| {
+                let value = decrypt_value(key, &name, &env_key, &encrypted)?;
+                let secret = SharedSecret {
+                    name: name.clone(),
+                    key: env_key,
+                    value,
+                };
+                Ok((name, secret))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(VaultFile {
+            key,
+            recipients: lines
+                .recipients
+                .into_iter()
+                .map(|(recipient, sealed)| (recipient, Some(sealed)))
+                .collect(),
+            secrets,
+            sealed_by: Some(sender),
+        })
+    }
+
+    /// Writes the vault file, sealing the data key to every recipient it was not sealed to yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `identity` - The identity the data key is sealed with, which recipients get to see.
+    ///
+    /// # Returns
+    ///
+    /// The text of the file.
+    pub fn render(&self, identity: &Identity) -> Result<String> {
+        let mut text = format!("{}\n", HEADER);
+        for (recipient, sealed) in &self.recipients {
+            let sealed = match sealed {
+                Some(sealed) => sealed.clone(),
+                None => {
+                    let sealed = identity.seal(&recipient.parse()?, CONTEXT, &self.key)?;
+                    STANDARD.encode(bincode::serialize(&sealed)?)
+                }
+            };
+            text.push_str(&format!("recipient {} {}\n", recipient, sealed));
+        }
+        for secret in self.secrets.values() {
+            text.push_str(&format!(
+                "secret {} {} {}\n",
+                secret.name,
+                secret.key,
+                encrypt_value(self.key, secret)?
+            ));
+        }
+        Ok(text)
+    }
+
+    /// Who sealed the data key to the identity the file was opened with.
+    pub fn sealed_by(&self) -> Option<Recipient> {
+        self.sealed_by
+    }
+
+    /// The recipients of the file, sorted.
+    pub fn recipients(&self) -> Vec<Recipient> {
+        self.recipients
+            .keys()
+            .filter_map(|recipient| recipient.parse().ok())
+            .collect()
+    }
+
+    /// The recipients and who sealed the data key to us, to compare with what the store trusts.
+    ///
+    /// # Returns
+    ///
+    /// The trust, or `None` for a new file, which nobody sealed a key in yet.
+    pub fn trust(&self) -> Option<Trust> {
+        self.sealed_by.map(|sealed_by| Trust {
+            recipients: self.recipients(),
+            sealed_by,
+        })
+    }
+
+    /// Lets `recipient` read the file, with the data key it already has.
+    ///
+    /// # Returns
+    ///
+    /// `false` if it was a recipient already.
+    pub fn add_recipient(&mut self, recipient: &Recipient) -> bool {
+        let recipient = recipient.to_string();
+        if self.recipients.contains_key(&recipient) {
+            return false;
+        }
+        self.recipients.insert(recipient, None);
+        true
+    }
+
+    /// Stops `recipient` from reading the file. The data key is replaced, so the
+    /// recipient can not read anything written from now on, even with the old key.
+    ///
+    /// # Returns
+    ///
+    /// `false` if it was no recipient.
+    pub fn remove_recipient(&mut self, recipient: &Recipient) -> bool {
+        if self.recipients.remove(&recipient.to_string()).is_none() {
+            return false;
+        }
+        self.rotate_key();
+        true
+    }
+
+    /// Replaces the data key, which is sealed to every recipient again.
+    pub fn rotate_key(&mut self) {
+        self.key = get_key();
+        for sealed in self.recipients.values_mut() {
+            *sealed = None;
+        }
+    }
+
+    /// The secrets of the file, sorted by their name.
+    pub fn secrets(&self) -> impl Iterator<Item = &SharedSecret> {
+        self.secrets.values()
+    }
+
+    /// Looks up the secret with the given name.
+    pub fn get(&self, name: &str) -> Option<&SharedSecret> {
+        self.secrets.get(name)
+    }
+
+    /// Sets a secret, replacing the one with the same name.
+    ///
+    /// # Returns
+    ///
+    /// A `Parse` error if its name or key is empty or holds whitespace,
+    /// which would break the lines of the file.
+    pub fn set(&mut self, secret: SharedSecret) -> Result<()> {
+        for field in [&secret.name, &secret.key] {
+            if field.is_empty() || field.contains(char::is_whitespace) {
+                return Err(EnvnError::Parse(format!(
+                    "'{}' can not be stored in the vault file, names and keys can not be empty or hold whitespace",
+                    field
+                )));
+            }
+        }
+        self.secrets.insert(secret.name.clone(), secret);
+        Ok(())
+    }
+
+    /// Removes the secret with the given name.
+    ///
+    /// # Returns
+    ///
+    /// `false` if there was none.
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.secrets.remove(name).is_some()
+    }
+
+    /// Finds the vault file of a directory, in the directory itself or one of its parents.
+    ///
+    /// # Returns
+    ///
+    /// The path of the vault file, or `None` if there is none.
+    pub fn find(dir: &Path) -> Option<PathBuf> {
+        dir.ancestors()
+            .map(|dir| dir.join(VAULT_FILE))
+            .find(|path| path.is_file())
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let change = match self {
+            Change::Added => "added",
+            Change::Changed => "changed",
+            Change::Deleted => "deleted",
+        };
+        write!(f, "{}", change)
+    }
+}
+
+impl Trust {
+    /// Describes how `now` differs from this trust, one line per difference.
+    ///
+    /// # Returns
+    ///
+    /// The differences, empty if `now` can be trusted like this trust.
+    pub fn changes(&self, now: &Trust) -> Vec<String> {
+        let mut changes = Vec::new();
+        if now.sealed_by != self.sealed_by {
+            changes.push(format!(
+                "The data key was sealed to you by {} instead of {}",
+                now.sealed_by, self.sealed_by
+            ));
+        }
+        for recipient in &now.recipients {
+            if !self.recipients.contains(recipient) {
+                changes.push(format!("{} was added as a recipient", recipient));
+            }
+        }
+        for recipient in &self.recipients {
+            if !now.recipients.contains(recipient) {
+                changes.push(format!("{} is no longer a recipient", recipient));
+            }
+        }
+        changes
+    }
+}
+
+/// Merges the changes of two versions of a vault file, secret by secret.
+/// A recipient that either side removed is removed, and the data key is replaced.
+///
+/// # Arguments
+///
+/// * `base` - The version both sides started from, an empty file if they have none in common.
+/// * `ours` - The version of our side.
+/// * `theirs` - The version of their side.
+///
+/// # Returns
+///
+/// The merged version, and the secrets both sides changed in another way,
+/// which are left as our side has them.
+pub fn merge(base: &VaultFile, ours: &VaultFile, theirs: &VaultFile) -> (VaultFile, Vec<Conflict>) {
+    let mut merged = ours.clone();
+
+    let recipients: BTreeSet<&String> = base
+        .recipients
+        .keys()
+        .chain(ours.recipients.keys())
+        .chain(theirs.recipients.keys())
+        .collect();
+    let mut removed = false;
+    for recipient in recipients {
+        let before = base.recipients.contains_key(recipient);
+        let after = theirs.recipients.contains_key(recipient);
+        if ours.recipients.contains_key(recipient) != before || after == before {
+            continue;
+        }
+        match after {
+            true => {
+                merged.recipients.insert(recipient.clone(), None);
+            }
+            false => {
+                merged.recipients.remove(recipient);
+                removed = true;
+            }
+        }
+    }
+    if removed {
+        merged.rotate_key();
+    }
+
+    let names: BTreeSet<&String> = base
+        .secrets
+        .keys()
+        .chain(ours.secrets.keys())
+        .chain(theirs.secrets.keys())
+        .collect();
+    let mut conflicts = Vec::new();
+    for name in names {
+        let before = base.secrets.get(name);
+        let (mine, other) = (ours.secrets.get(name), theirs.secrets.get(name));
+        if mine == other || other == before {
+            continue;
+        }
+        if mine == before {
+            match other {
+                Some(secret) => merged.secrets.insert(name.clone(), secret.clone()),
+                None => merged.secrets.remove(name),
+            };
+            continue;
+        }
+        conflicts.push(Conflict {
+            name: name.clone(),
+            ours: change(before, mine),
+            theirs: change(before, other),
+        });
+    }
+    (merged, conflicts)
+}
+
+/// What happened to a secret that is not the same as before
+fn change(before: Option<&SharedSecret>, after: Option<&SharedSecret>) -> Change {
+    match (before, after) {
+        (None, _) => Change::Added,
+        (_, None) => Change::Deleted,
+        _ => Change::Changed,
+    }
+}
+
+/// Lists the recipients and secrets of a vault file, without decrypting anything.
+///
+/// # Returns
+///
+/// The listing, or a `Parse` error if it is no vault file.
+pub fn list(text: &str) -> Result<Listing> {
+    let lines = parse(text)?;
+    Ok(Listing {
+        recipients: lines
+            .recipients
+            .iter()
+            .map(|(recipient, sealed)| Ok((recipient.parse()?, decode_sealed(sealed)?.sender())))
+            .collect::<Result<_>>()?,
+        secrets: lines
+            .secrets
+            .into_iter()
+            .map(|(name, (key, _))| (name, key))
+            .collect(),
+    })
+}
+
+/// Splits a vault file into its lines. Empty lines and lines starting with `#` are skipped.
+fn parse(text: &str) -> Result<Lines> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'));
+    match lines.next() {
+        Some((_, line)) if line.trim() == HEADER => {}
+        _ => {
+            return Err(EnvnError::Parse(format!(
+                "This is not a vault file, it has to start with '{}'",
+                HEADER
+            )))
+        }
+    }
+
+    let mut parsed = Lines {
+        recipients: BTreeMap::new(),
+        secrets: BTreeMap::new(),
+    };
+    for (i, line) in lines {
+        let malformed =
+            |what: &str| EnvnError::Parse(format!("Line {} of the vault file {}", i + 1, what));
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let duplicate = match fields.as_slice() {
+            ["recipient", recipient, sealed] => {
+                recipient.parse::<Recipient>()?;
+                parsed
+                    .recipients
+                    .insert(recipient.to_string(), sealed.to_string())
+                    .is_some()
+            }
+            ["secret", name, key, encrypted] => parsed
+                .secrets
+                .insert(name.to_string(), (key.to_string(), encrypted.to_string()))
+                .is_some(),
+            _ => return Err(malformed("is malformed, was a merge conflict left in it?")),
+        };
+        if duplicate {
+            return Err(malformed("repeats an earlier one"));
+        }
+    }
+    Ok(parsed)
+}
+
+fn decode_sealed(sealed: &str) -> Result<Sealed> {
+    let bytes = STANDARD
+        .decode(sealed)
+        .map_err(|_| EnvnError::Parse("A data key in the vault file is malformed".to_string()))?;
+    Ok(bincode::deserialize(&bytes)?)
+}
+
+/// The name and key a value is bound to, so that lines can not be swapped
+fn associated_data(name: &str, key: &str) -> Vec<u8> {
+    [name.as_bytes(), &[0], key.as_bytes()].concat()
+}
+
+/// Encrypts a value with a nonce derived from the data key and the secret,
+/// so that the same secret always ends up as the same line.
+fn encrypt_value(key: Key<Aes256Gcm>, secret: &SharedSecret) -> Result<String> {
+    let aad = associated_data(&secret.name, &secret.key);
+    let mut nonce = Nonce::<Aes256Gcm>::default();
+    Hkdf::<Sha256>::new(Some(CONTEXT.as_bytes()), &key)
+        .expand(
+            &[aad.as_slice(), &[0], secret.value.as_bytes()].concat(),
+            &mut nonce,
+        )
+        .map_err(|e| EnvnError::Crypto(format!("Failed to derive nonce: {}", e)))?;
+
+    let mut bytes = nonce.to_vec();
+    bytes.extend(encrypt(key, nonce, secret.value.as_bytes(), &aad)?);
+    Ok(STANDARD.encode(bytes))
+}
+
+fn decrypt_value(
+    key: Key<Aes256Gcm>,
+    name: &str,
+    env_key: &str,
+    encrypted: &str,
+) -> Result<String> {
+    let crypto_error = || {
+        EnvnError::Crypto(format!(
+            "The value of {} in the vault file was tampered with",
+            name
+        ))
+    };
+    let bytes = STANDARD.decode(encrypted).map_err(|_| crypto_error())?;
+    if bytes.len() < 12 {
+        return Err(crypto_error());
+    }
+    let (nonce, ciphertext) = bytes.split_at(12);
+    let value = decrypt(key, nonce, ciphertext, &associated_data(name, env_key))
+        .map_err(|_| crypto_error())?;
+    String::from_utf8(value).map_err(|_| crypto_error())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{list, merge, Change, Conflict, VaultFile};
+    use crate::{error::EnvnError, identity::Identity, share::SharedSecret};
+
+    fn secret(name: &str, value: &str) -> SharedSecret {
+        SharedSecret {
+            name: name.to_string(),
+            key: name.to_uppercase(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_vault_file() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+
+        let mut file = VaultFile::new(&[alice.recipient()]);
+        file.set(secret("db", "postgres://localhost")).unwrap();
+        file.set(secret("token", "hunter2")).unwrap();
+        assert!(file.set(secret("two words", "x")).is_err());
+        let text = file.render(&alice).unwrap();
+        assert!(!text.contains("hunter2"));
+        assert!(VaultFile::open(&text, &bob).is_err());
+
+        // unchanged secrets keep their lines
+        let mut file = VaultFile::open(&text, &alice).unwrap();
+        assert_eq!(file.get("token").unwrap().value, "hunter2");
+        file.set(secret("token", "hunter3")).unwrap();
+        let changed = file.render(&alice).unwrap();
+        let diff: Vec<_> = text
+            .lines()
+            .filter(|line| !changed.contains(line))
+            .collect();
+        assert_eq!(diff.len(), 1);
+        assert!(diff[0].starts_with("secret token TOKEN "));
+
+        assert!(file.add_recipient(&bob.recipient()));
+        let shared = file.render(&alice).unwrap();
+        assert_eq!(
+            VaultFile::open(&shared, &bob).unwrap().get("db"),
+            file.get("db")
+        );
+        let listing = list(&shared).unwrap();
+        assert_eq!(listing.recipients.len(), 2);
+        assert!(listing
+            .recipients
+            .iter()
+            .all(|(_, by)| *by == alice.recipient()));
+        let opened = VaultFile::open(&shared, &bob).unwrap();
+        assert_eq!(opened.sealed_by(), Some(alice.recipient()));
+
+        assert!(file.remove_recipient(&bob.recipient()));
+        let removed = file.render(&alice).unwrap();
+        assert!(VaultFile::open(&removed, &bob).is_err());
+        assert!(VaultFile::open(&removed, &alice).is_ok());
+
+        // values can not be moved to another secret
+        let swapped = removed.replace("secret db DB", "secret db TOKEN");
+        assert!(VaultFile::open(&swapped, &alice).is_err());
+        assert!(VaultFile::open("DB=postgres", &alice).is_err());
+
+        // a file written by someone who is not a recipient is not trusted
+        let eve = Identity::generate();
+        let mut forged = VaultFile::new(&[alice.recipient()]);
+        forged.set(secret("db", "postgres://eve")).unwrap();
+        let forged = forged.render(&eve).unwrap();
+        assert!(matches!(
+            VaultFile::open(&forged, &alice),
+            Err(EnvnError::Auth(_))
+        ));
+    }
+
+    #[test]
+    fn test_merge() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let carol = Identity::generate();
+
+        let mut base = VaultFile::new(&[alice.recipient(), bob.recipient()]);
+        base.set(secret("db", "postgres://localhost")).unwrap();
+        base.set(secret("token", "hunter2")).unwrap();
+        base.set(secret("old", "x")).unwrap();
+
+        let mut ours = base.clone();
+        ours.set(secret("api", "ours")).unwrap();
+        ours.set(secret("token", "ours")).unwrap();
+        ours.remove_recipient(&bob.recipient());
+        let mut theirs = base.clone();
+        theirs.set(secret("cache", "redis://")).unwrap();
+        theirs.set(secret("token", "theirs")).unwrap();
+        theirs.remove("old");
+        theirs.add_recipient(&carol.recipient());
+
+        let (merged, conflicts) = merge(&base, &ours, &theirs);
+        let names: Vec<&str> = merged.secrets().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["api", "cache", "db", "token"]);
+        assert_eq!(merged.get("token").unwrap().value, "ours");
+        assert_eq!(
+            conflicts,
+            [Conflict {
+                name: "token".to_string(),
+                ours: Change::Changed,
+                theirs: Change::Changed,
+            }]
+        );
+
+        let text = merged.render(&alice).unwrap();
+        assert!(VaultFile::open(&text, &carol).is_ok());
+        assert!(VaultFile::open(&text, &bob).is_err());
+    }
+}